@@ -0,0 +1,22 @@
+-- Create outbox Table
+-- Emails are written here in the same transaction as the changes
+-- that trigger them and are delivered later on by the outbox relay.
+CREATE TABLE outbox(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    kind TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    -- One of `pending`, `delivered` or `failed`
+    status TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT NULL,
+    next_attempt_at timestamptz NOT NULL,
+    created_at timestamptz NOT NULL,
+    delivered_at timestamptz NULL
+);
+
+-- The relay only ever looks for pending emails that are due
+CREATE INDEX outbox_pending_idx ON outbox (next_attempt_at) WHERE status = 'pending';
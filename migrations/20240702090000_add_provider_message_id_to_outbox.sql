@@ -0,0 +1,4 @@
+-- Add `provider_message_id` to `outbox` table
+-- The id the email provider assigned to a delivered email,
+-- used to correlate bounces and opens with what we sent.
+ALTER TABLE outbox ADD COLUMN provider_message_id TEXT NULL;
@@ -0,0 +1,19 @@
+-- Create newsletter_issues Table
+-- One row per published issue, whether it was published through the API
+-- or `zero2prod issue send`, see `zero2prod issue status`.
+CREATE TABLE newsletter_issues(
+    newsletter_issue_id uuid NOT NULL,
+    PRIMARY KEY (newsletter_issue_id),
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    -- One of `sending`, `sent` or `failed`
+    status TEXT NOT NULL,
+    delivered INT NOT NULL DEFAULT 0,
+    -- Subscribers the email provider refused to deliver to
+    suppressed INT NOT NULL DEFAULT 0,
+    -- Confirmed subscribers whose stored email is not valid anymore
+    skipped INT NOT NULL DEFAULT 0,
+    published_at timestamptz NOT NULL,
+    completed_at timestamptz NULL
+);
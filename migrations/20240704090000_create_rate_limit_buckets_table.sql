@@ -0,0 +1,16 @@
+-- Create rate_limit_buckets Table
+-- Token buckets shared by every instance, see `application.rate_limit.store`.
+-- Keys are hashed, they never hold an IP or email address.
+CREATE TABLE rate_limit_buckets(
+    key TEXT NOT NULL,
+    PRIMARY KEY (key),
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at timestamptz NOT NULL,
+    -- An untouched bucket is full again by then, the row can be deleted
+    full_at timestamptz NOT NULL
+);
+
+CREATE INDEX rate_limit_buckets_full_at_idx ON rate_limit_buckets (full_at);
+
+-- Looking for the last confirmation email sent to an address
+CREATE INDEX outbox_recipient_idx ON outbox (recipient, created_at);
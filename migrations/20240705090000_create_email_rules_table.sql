@@ -0,0 +1,11 @@
+-- Create email_rules Table
+-- Managed through `/admin/email_rules`: `allow` rules let addresses through
+-- that the policy would reject, `deny` rules reject them.
+CREATE TABLE email_rules(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    -- `user@example.com`, `example.com` or `*.example.com`, lower case
+    pattern TEXT NOT NULL UNIQUE,
+    action TEXT NOT NULL CHECK (action IN ('allow', 'deny')),
+    created_at timestamptz NOT NULL
+);
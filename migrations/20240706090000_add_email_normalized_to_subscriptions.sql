@@ -0,0 +1,125 @@
+-- Add `email_normalized` to `subscriptions`
+-- `Ursula@Gmail.com` and `ursula@gmail.com` are the same subscriber:
+-- `email` keeps the address as it was typed, `email_normalized` is unique.
+-- It is computed as `SubscriberEmail::normalized` does with the default
+-- settings: lower case, punycode domain and no provider rules.
+ALTER TABLE subscriptions ADD COLUMN email_normalized TEXT NULL;
+
+-- RFC 3492, the label of an international domain without its `xn--` prefix
+CREATE FUNCTION punycode_encode(label TEXT) RETURNS TEXT AS $$
+DECLARE
+    code_points INT[] := '{}';
+    output TEXT := '';
+    basic INT;
+    handled INT;
+    n INT := 128;
+    delta INT := 0;
+    bias INT := 72;
+    c INT;
+    d INT;
+    m INT;
+    q INT;
+    k INT;
+    t INT;
+BEGIN
+    FOR i IN 1..length(label) LOOP
+        c := ascii(substr(label, i, 1));
+        code_points := code_points || c;
+        IF c < 128 THEN
+            output := output || chr(c);
+        END IF;
+    END LOOP;
+    basic := length(output);
+    handled := basic;
+    IF basic > 0 THEN
+        output := output || '-';
+    END IF;
+
+    WHILE handled < cardinality(code_points) LOOP
+        SELECT min(p) INTO m FROM unnest(code_points) AS p WHERE p >= n;
+        delta := delta + (m - n) * (handled + 1);
+        n := m;
+        FOREACH c IN ARRAY code_points LOOP
+            IF c < n THEN
+                delta := delta + 1;
+            END IF;
+            IF c = n THEN
+                q := delta;
+                k := 36;
+                LOOP
+                    t := CASE WHEN k <= bias THEN 1 WHEN k >= bias + 26 THEN 26 ELSE k - bias END;
+                    EXIT WHEN q < t;
+                    d := t + (q - t) % (36 - t);
+                    output := output || chr(CASE WHEN d < 26 THEN 97 + d ELSE 22 + d END);
+                    q := (q - t) / (36 - t);
+                    k := k + 36;
+                END LOOP;
+                output := output || chr(CASE WHEN q < 26 THEN 97 + q ELSE 22 + q END);
+
+                -- Adapt the bias
+                q := CASE WHEN handled = basic THEN delta / 700 ELSE delta / 2 END;
+                q := q + q / (handled + 1);
+                k := 0;
+                WHILE q > 455 LOOP
+                    q := q / 35;
+                    k := k + 36;
+                END LOOP;
+                bias := k + 36 * q / (q + 38);
+
+                delta := 0;
+                handled := handled + 1;
+            END IF;
+        END LOOP;
+        delta := delta + 1;
+        n := n + 1;
+    END LOOP;
+
+    RETURN output;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
+
+-- Close enough to `idna::domain_to_ascii` for the domains we accepted
+CREATE FUNCTION domain_to_ascii(domain TEXT) RETURNS TEXT AS $$
+    SELECT string_agg(
+        CASE WHEN label ~ '^[ -~]*$' THEN label ELSE 'xn--' || punycode_encode(label) END,
+        '.' ORDER BY position
+    )
+    FROM regexp_split_to_table(
+        normalize(lower(translate(domain, '。', '.')), NFKC), '\.'
+    ) WITH ORDINALITY AS labels(label, position);
+$$ LANGUAGE SQL IMMUTABLE;
+
+UPDATE subscriptions SET email_normalized =
+    lower(substring(trim(email) FROM '^(.*)@'))
+    || '@'
+    || domain_to_ascii(substring(trim(email) FROM '@([^@]*)$'));
+
+DROP FUNCTION domain_to_ascii(TEXT);
+DROP FUNCTION punycode_encode(TEXT);
+
+-- Merge the duplicates: keep the suppressed subscriber if there is one,
+-- so we never email it again, then the confirmed one, then the oldest.
+CREATE TEMPORARY TABLE duplicate_subscriptions AS
+    SELECT id, email
+    FROM (
+        SELECT id, email, row_number() OVER (
+            PARTITION BY email_normalized
+            ORDER BY
+                CASE status WHEN 'suppressed' THEN 0 WHEN 'confirmed' THEN 1 ELSE 2 END,
+                subscribed_at,
+                id
+        ) AS position
+        FROM subscriptions
+    ) AS ranked
+    WHERE position > 1;
+
+DELETE FROM subscription_tokens
+    WHERE subscriber_id IN (SELECT id FROM duplicate_subscriptions);
+DELETE FROM outbox
+    WHERE status = 'pending' AND recipient IN (SELECT email FROM duplicate_subscriptions);
+DELETE FROM subscriptions
+    WHERE id IN (SELECT id FROM duplicate_subscriptions);
+DROP TABLE duplicate_subscriptions;
+
+ALTER TABLE subscriptions ALTER COLUMN email_normalized SET NOT NULL;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_email_normalized_key UNIQUE (email_normalized);
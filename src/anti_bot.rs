@@ -0,0 +1,454 @@
+use crate::configuration::{AntiBotSettings, CaptchaSettings};
+use crate::routes::FormData;
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Form tokens older than this are rejected, the form has to be loaded again
+const FORM_TOKEN_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A CAPTCHA provider that takes longer than this is not going to answer
+const CAPTCHA_TIMEOUT: Duration = Duration::from_secs(5);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a submission was taken for a bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BotCheckFailure {
+    /// The hidden field, that people do not see, was filled in
+    Honeypot,
+    MissingFormToken,
+    /// Not signed by us, or too old
+    InvalidFormToken,
+    /// Submitted sooner after the form was loaded than a person could
+    TooFast,
+    MissingCaptcha,
+    FailedCaptcha,
+}
+
+impl BotCheckFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BotCheckFailure::Honeypot => "honeypot",
+            BotCheckFailure::MissingFormToken => "missing_form_token",
+            BotCheckFailure::InvalidFormToken => "invalid_form_token",
+            BotCheckFailure::TooFast => "too_fast",
+            BotCheckFailure::MissingCaptcha => "missing_captcha",
+            BotCheckFailure::FailedCaptcha => "failed_captcha",
+        }
+    }
+}
+
+/// Signed timestamps, they tell how long the form was open before it was submitted.
+///
+/// A token is `{issued at, in milliseconds since the epoch}.{signature}`.
+pub struct FormTokens {
+    key: Secret<String>,
+}
+
+impl FormTokens {
+    pub fn new(key: Secret<String>) -> Self {
+        Self { key }
+    }
+
+    pub fn issue(&self) -> String {
+        self.sign(Utc::now().timestamp_millis())
+    }
+
+    /// How long ago the token was issued, `None` if we did not sign it
+    pub fn age(&self, token: &str) -> Option<Duration> {
+        let (issued_at, signature) = token.split_once('.')?;
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(signature)
+            .ok()?;
+        let issued_at: i64 = issued_at.parse().ok()?;
+        // In constant time, the signature cannot be guessed one byte at a time
+        self.mac(issued_at).verify_slice(&signature).ok()?;
+
+        let age = Utc::now().timestamp_millis().checked_sub(issued_at)?;
+        u64::try_from(age).ok().map(Duration::from_millis)
+    }
+
+    fn sign(&self, issued_at: i64) -> String {
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(self.mac(issued_at).finalize().into_bytes());
+
+        format!("{}.{}", issued_at, signature)
+    }
+
+    fn mac(&self, issued_at: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.key.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(issued_at.to_string().as_bytes());
+        mac
+    }
+}
+
+/// Asks a CAPTCHA provider whether the response sent with a form was given by a person.
+///
+/// Implement it to support another provider, see `AntiBot::new`.
+#[async_trait::async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    /// `Ok(false)` when the provider rejects the response, `Err` when it
+    /// could not be asked
+    async fn verify(&self, response: &str, client_ip: Option<&str>) -> Result<bool, anyhow::Error>;
+}
+
+/// reCAPTCHA, hCaptcha and Turnstile all expose the same `siteverify` API
+pub struct SiteVerifyCaptcha {
+    http_client: Client,
+    verify_url: String,
+    secret: Secret<String>,
+}
+
+impl SiteVerifyCaptcha {
+    pub fn new(verify_url: String, secret: Secret<String>) -> Self {
+        Self {
+            http_client: Client::builder().timeout(CAPTCHA_TIMEOUT).build().unwrap(),
+            verify_url,
+            secret,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for SiteVerifyCaptcha {
+    #[tracing::instrument(name = "Verify a CAPTCHA response", skip_all)]
+    async fn verify(&self, response: &str, client_ip: Option<&str>) -> Result<bool, anyhow::Error> {
+        let mut form = vec![
+            ("secret", self.secret.expose_secret().as_str()),
+            ("response", response),
+        ];
+        form.extend(client_ip.map(|ip| ("remoteip", ip)));
+
+        let verification: SiteVerifyResponse = self
+            .http_client
+            .post(&self.verify_url)
+            .form(&form)
+            .send()
+            .await
+            .context("Failed to reach the CAPTCHA provider")?
+            .error_for_status()
+            .context("The CAPTCHA provider returned an error")?
+            .json()
+            .await
+            .context("Failed to read the answer of the CAPTCHA provider")?;
+
+        Ok(verification.success)
+    }
+}
+
+/// Accepts a single response, for local development and tests
+pub struct MockCaptcha {
+    accepted_response: String,
+}
+
+impl MockCaptcha {
+    pub fn new(accepted_response: String) -> Self {
+        Self { accepted_response }
+    }
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for MockCaptcha {
+    async fn verify(
+        &self,
+        response: &str,
+        _client_ip: Option<&str>,
+    ) -> Result<bool, anyhow::Error> {
+        Ok(response == self.accepted_response)
+    }
+}
+
+/// The checks run on `POST /subscriptions` to turn bots away
+pub struct AntiBot {
+    /// Can be changed at runtime with `reconfigure`
+    checks: ArcSwap<Checks>,
+}
+
+struct Checks {
+    honeypot: bool,
+    min_time_to_submit: Option<Duration>,
+    form_tokens: Option<Arc<FormTokens>>,
+    captcha: Option<Box<dyn CaptchaVerifier>>,
+}
+
+impl Checks {
+    fn new(settings: &AntiBotSettings) -> Self {
+        let captcha: Option<Box<dyn CaptchaVerifier>> = match &settings.captcha {
+            Some(CaptchaSettings::SiteVerify {
+                verify_url,
+                secret: Some(secret),
+            }) => Some(Box::new(SiteVerifyCaptcha::new(
+                verify_url.clone(),
+                secret.clone(),
+            ))),
+            // Rejected by `Settings::validate`
+            Some(CaptchaSettings::SiteVerify { secret: None, .. }) => None,
+            Some(CaptchaSettings::Mock { accepted_response }) => {
+                Some(Box::new(MockCaptcha::new(accepted_response.clone())))
+            }
+            None => None,
+        };
+
+        Self {
+            honeypot: settings.honeypot,
+            min_time_to_submit: (settings.min_time_to_submit_seconds > 0)
+                .then(|| Duration::from_secs(settings.min_time_to_submit_seconds)),
+            form_tokens: settings
+                .form_token_secret
+                .clone()
+                .map(|key| Arc::new(FormTokens::new(key))),
+            captcha,
+        }
+    }
+}
+
+impl AntiBot {
+    pub fn new(settings: &AntiBotSettings) -> Self {
+        Self {
+            checks: ArcSwap::from_pointee(Checks::new(settings)),
+        }
+    }
+
+    /// Apply new settings to the submissions checked from now on.
+    ///
+    /// Form tokens issued before a change of secret are not accepted anymore.
+    pub fn reconfigure(&self, settings: &AntiBotSettings) {
+        self.checks.store(Arc::new(Checks::new(settings)));
+    }
+
+    /// Set when a form token secret is configured
+    pub fn form_tokens(&self) -> Option<Arc<FormTokens>> {
+        self.checks.load().form_tokens.clone()
+    }
+
+    /// `Ok(None)` when the submission passed every check. Only asking the
+    /// CAPTCHA provider can fail.
+    #[tracing::instrument(name = "Check the submission for bots", skip_all)]
+    pub async fn check(
+        &self,
+        form: &FormData,
+        client_ip: Option<&str>,
+    ) -> Result<Option<BotCheckFailure>, anyhow::Error> {
+        // The settings in effect when the submission came in, for every check
+        let checks = self.checks.load_full();
+
+        if checks.honeypot && form.website.as_deref().is_some_and(|w| !w.is_empty()) {
+            return Ok(Some(BotCheckFailure::Honeypot));
+        }
+
+        if let Some(form_tokens) = &checks.form_tokens {
+            let Some(form_token) = &form.form_token else {
+                return Ok(Some(BotCheckFailure::MissingFormToken));
+            };
+            match form_tokens.age(form_token) {
+                Some(age) if age > FORM_TOKEN_MAX_AGE => {
+                    return Ok(Some(BotCheckFailure::InvalidFormToken))
+                }
+                Some(age) if checks.min_time_to_submit.is_some_and(|min| age < min) => {
+                    return Ok(Some(BotCheckFailure::TooFast))
+                }
+                Some(_) => {}
+                None => return Ok(Some(BotCheckFailure::InvalidFormToken)),
+            }
+        }
+
+        if let Some(captcha) = &checks.captcha {
+            let Some(response) = &form.captcha_response else {
+                return Ok(Some(BotCheckFailure::MissingCaptcha));
+            };
+            if !captcha.verify(response, client_ip).await? {
+                return Ok(Some(BotCheckFailure::FailedCaptcha));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AntiBot, BotCheckFailure, CaptchaVerifier, FormTokens, SiteVerifyCaptcha};
+    use crate::configuration::{AntiBotSettings, CaptchaSettings};
+    use crate::routes::FormData;
+    use chrono::Utc;
+    use claims::{assert_none, assert_ok_eq, assert_some};
+    use secrecy::Secret;
+    use wiremock::matchers::{body_string_contains, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn key() -> Secret<String> {
+        Secret::new("a-form-token-secret-that-is-long-enough".into())
+    }
+
+    fn form() -> FormData {
+        FormData {
+            name: "le guin".into(),
+            email: "ursula_le_guin@gmail.com".into(),
+            website: None,
+            form_token: None,
+            captcha_response: None,
+        }
+    }
+
+    fn anti_bot(configure: impl FnOnce(&mut AntiBotSettings)) -> AntiBot {
+        let mut settings = AntiBotSettings::default();
+        configure(&mut settings);
+        AntiBot::new(&settings)
+    }
+
+    #[test]
+    fn a_form_token_tells_how_long_ago_it_was_issued() {
+        let form_tokens = FormTokens::new(key());
+        let issued_a_minute_ago = form_tokens.sign(Utc::now().timestamp_millis() - 60_000);
+
+        let age = form_tokens.age(&issued_a_minute_ago).unwrap();
+
+        assert!((60..=61).contains(&age.as_secs()), "{:?}", age);
+    }
+
+    #[test]
+    fn form_tokens_signed_with_another_key_are_rejected() {
+        let token = FormTokens::new(Secret::new("another key".into())).issue();
+
+        assert_none!(FormTokens::new(key()).age(&token));
+    }
+
+    #[test]
+    fn tampered_form_tokens_are_rejected() {
+        let form_tokens = FormTokens::new(key());
+        let token = form_tokens.issue();
+        let (_, signature) = token.split_once('.').unwrap();
+        let backdated = format!(
+            "{}.{}",
+            Utc::now().timestamp_millis() - 3_600_000,
+            signature
+        );
+
+        assert_some!(form_tokens.age(&token));
+        assert_none!(form_tokens.age(&backdated));
+        assert_none!(form_tokens.age("not a token"));
+    }
+
+    #[tokio::test]
+    async fn a_filled_in_honeypot_is_a_bot() {
+        let anti_bot = anti_bot(|_| {});
+        let mut form = form();
+        form.website = Some("https://cheap-pills.example.com".into());
+
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::Honeypot)
+        );
+    }
+
+    #[tokio::test]
+    async fn forms_submitted_too_quickly_are_bots() {
+        let anti_bot = anti_bot(|s| {
+            s.min_time_to_submit_seconds = 3;
+            s.form_token_secret = Some(key());
+        });
+        let form_tokens = anti_bot.form_tokens().unwrap();
+        let mut form = form();
+
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::MissingFormToken)
+        );
+
+        form.form_token = Some(form_tokens.issue());
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::TooFast)
+        );
+
+        form.form_token = Some(form_tokens.sign(Utc::now().timestamp_millis() - 10_000));
+        assert_ok_eq!(anti_bot.check(&form, None).await, None);
+
+        form.form_token =
+            Some(form_tokens.sign(Utc::now().timestamp_millis() - 2 * 24 * 60 * 60 * 1000));
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::InvalidFormToken)
+        );
+    }
+
+    #[tokio::test]
+    async fn form_tokens_are_required_as_soon_as_a_secret_is_set() {
+        let anti_bot = anti_bot(|s| s.form_token_secret = Some(key()));
+        let form_tokens = anti_bot.form_tokens().unwrap();
+        let mut form = form();
+
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::MissingFormToken)
+        );
+
+        form.form_token = Some("not a token".into());
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::InvalidFormToken)
+        );
+
+        // Without a minimum time to submit, a fresh token is fine
+        form.form_token = Some(form_tokens.issue());
+        assert_ok_eq!(anti_bot.check(&form, None).await, None);
+    }
+
+    #[tokio::test]
+    async fn the_captcha_response_is_checked_when_a_provider_is_configured() {
+        let anti_bot = anti_bot(|s| {
+            s.captcha = Some(CaptchaSettings::Mock {
+                accepted_response: "human".into(),
+            })
+        });
+        let mut form = form();
+
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::MissingCaptcha)
+        );
+
+        form.captcha_response = Some("robot".into());
+        assert_ok_eq!(
+            anti_bot.check(&form, None).await,
+            Some(BotCheckFailure::FailedCaptcha)
+        );
+
+        form.captcha_response = Some("human".into());
+        assert_ok_eq!(anti_bot.check(&form, None).await, None);
+    }
+
+    #[tokio::test]
+    async fn site_verify_sends_the_secret_response_and_client_ip() {
+        let provider = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_string_contains("secret=captcha-secret"))
+            .and(body_string_contains("response=the-response"))
+            .and(body_string_contains("remoteip=10.0.0.1"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"success": true})),
+            )
+            .expect(1)
+            .mount(&provider)
+            .await;
+        let captcha = SiteVerifyCaptcha::new(provider.uri(), Secret::new("captcha-secret".into()));
+
+        let outcome = captcha.verify("the-response", Some("10.0.0.1")).await;
+
+        assert_ok_eq!(outcome, true);
+    }
+}
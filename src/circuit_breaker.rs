@@ -0,0 +1,281 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The externally visible state of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow through as usual.
+    Closed,
+    /// Too many consecutive failures: requests are rejected straight away.
+    Open,
+    /// The cooldown has elapsed: a single probe request is let through
+    /// to find out if the downstream service has recovered.
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+impl std::fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+enum State {
+    Closed { consecutive_failures: u32 },
+    Open { opened_at: Instant },
+    HalfOpen { probe_started_at: Instant },
+}
+
+/// Protects the application from waiting on a downstream service that is down.
+///
+/// After `failure_threshold` consecutive failures the breaker opens and
+/// `try_acquire` refuses every request until `cooldown` has elapsed.
+/// It then lets one probe request through (half-open): a success closes the
+/// breaker, a failure opens it again for another `cooldown`.
+///
+/// Callers must report the outcome of every acquired request using either
+/// `record_success` or `record_failure`.
+pub struct CircuitBreaker {
+    name: &'static str,
+    // Both can be changed at runtime with `reconfigure`
+    failure_threshold: AtomicU32,
+    cooldown_milliseconds: AtomicU64,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            name,
+            failure_threshold: AtomicU32::new(failure_threshold.max(1)),
+            cooldown_milliseconds: AtomicU64::new(cooldown.as_millis() as u64),
+            state: Mutex::new(State::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Change the thresholds without resetting the current state.
+    pub fn reconfigure(&self, failure_threshold: u32, cooldown: Duration) {
+        self.failure_threshold
+            .store(failure_threshold.max(1), Ordering::Relaxed);
+        self.cooldown_milliseconds
+            .store(cooldown.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn failure_threshold(&self) -> u32 {
+        self.failure_threshold.load(Ordering::Relaxed)
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_millis(self.cooldown_milliseconds.load(Ordering::Relaxed))
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { opened_at } if opened_at.elapsed() < self.cooldown() => {
+                CircuitState::Open
+            }
+            // The cooldown is over, the next request will be used as a probe
+            State::Open { .. } | State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns `true` if a request is allowed to go through.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { opened_at } => {
+                if opened_at.elapsed() < self.cooldown() {
+                    return false;
+                }
+
+                tracing::info!(
+                    circuit_breaker.name = self.name,
+                    circuit_breaker.state = %CircuitState::HalfOpen,
+                    "Circuit breaker is half-open, sending a probe request"
+                );
+                *state = State::HalfOpen {
+                    probe_started_at: Instant::now(),
+                };
+
+                true
+            }
+            State::HalfOpen { probe_started_at } => {
+                // Only one probe at a time.
+                // If the probe never reported back (e.g. the future driving it
+                // was dropped) we allow a new one after another cooldown.
+                if probe_started_at.elapsed() < self.cooldown() {
+                    return false;
+                }
+
+                *state = State::HalfOpen {
+                    probe_started_at: Instant::now(),
+                };
+
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if !matches!(*state, State::Closed { .. }) {
+            tracing::info!(
+                circuit_breaker.name = self.name,
+                circuit_breaker.state = %CircuitState::Closed,
+                "Circuit breaker closed, the downstream service has recovered"
+            );
+        }
+
+        *state = State::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        match *state {
+            State::Closed {
+                consecutive_failures,
+            } => {
+                let consecutive_failures = consecutive_failures + 1;
+
+                if consecutive_failures < self.failure_threshold() {
+                    *state = State::Closed {
+                        consecutive_failures,
+                    };
+                    return;
+                }
+
+                tracing::warn!(
+                    circuit_breaker.name = self.name,
+                    circuit_breaker.state = %CircuitState::Open,
+                    consecutive_failures,
+                    "Circuit breaker opened after too many consecutive failures"
+                );
+            }
+            State::HalfOpen { .. } => {
+                tracing::warn!(
+                    circuit_breaker.name = self.name,
+                    circuit_breaker.state = %CircuitState::Open,
+                    "Circuit breaker probe failed, opening the circuit again"
+                );
+            }
+            // Late failures of requests acquired before the circuit opened
+            // do not extend the cooldown
+            State::Open { .. } => return,
+        }
+
+        *state = State::Open {
+            opened_at: Instant::now(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use std::time::Duration;
+
+    fn circuit_breaker(failure_threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new("test", failure_threshold, cooldown)
+    }
+
+    #[test]
+    fn a_new_circuit_breaker_is_closed() {
+        let breaker = circuit_breaker(3, Duration::from_secs(60));
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn the_circuit_opens_after_consecutive_failures() {
+        let breaker = circuit_breaker(3, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn a_success_resets_the_consecutive_failures() {
+        let breaker = circuit_breaker(2, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn only_one_probe_is_allowed_once_the_cooldown_has_elapsed() {
+        let breaker = circuit_breaker(1, Duration::from_millis(10));
+
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_circuit() {
+        let breaker = circuit_breaker(1, Duration::from_millis(10));
+
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn a_failed_probe_opens_the_circuit_again() {
+        let breaker = circuit_breaker(1, Duration::from_millis(50));
+
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn reconfiguring_the_breaker_keeps_its_state() {
+        let breaker = circuit_breaker(3, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.reconfigure(2, Duration::from_secs(60));
+        breaker.record_failure();
+
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::configuration::Settings;
+use crate::domain::{NewSubscriber, SubscriberEmail};
+use crate::routes::{
+    confirm_subscriber, enqueue_confirmation_email, generate_subscription_token, get_issue_status,
+    insert_subscriber, publish_issue, store_subscription_token, FormData, NewsletterIssue,
+};
+use crate::startup::get_connection_pool;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Subcommand)]
+pub enum SubscribersCommand {
+    /// Import subscribers from a CSV file with `email` and `name` columns.
+    /// Nothing is imported if a line is not valid.
+    Import {
+        file: PathBuf,
+        /// They already confirmed their subscription elsewhere,
+        /// do not send them a confirmation email
+        #[arg(long)]
+        confirmed: bool,
+    },
+    /// Write every subscriber to stdout as CSV
+    Export,
+    /// Delete a subscriber, their subscription tokens and the emails
+    /// still waiting to be sent to them
+    Delete { email: String },
+}
+
+#[derive(Subcommand)]
+pub enum IssueCommand {
+    /// Publish a newsletter issue to every confirmed subscriber
+    Send {
+        #[arg(long)]
+        title: String,
+        /// File holding the HTML content
+        #[arg(long)]
+        html: PathBuf,
+        /// File holding the plain text content
+        #[arg(long)]
+        text: PathBuf,
+    },
+    /// Show how many subscribers a newsletter issue reached
+    Status { newsletter_issue_id: Uuid },
+}
+
+#[derive(Subcommand)]
+pub enum TokensCommand {
+    /// Delete the subscription tokens of subscribers that are not
+    /// pending confirmation anymore
+    Purge,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedSubscriber {
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+}
+
+pub async fn subscribers(
+    command: SubscribersCommand,
+    configuration: &Settings,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+
+    match command {
+        SubscribersCommand::Import { file, confirmed } => {
+            import_subscribers(
+                &connection_pool,
+                &file,
+                confirmed,
+                &configuration.application.base_url,
+            )
+            .await
+        }
+        SubscribersCommand::Export => export_subscribers(&connection_pool).await,
+        SubscribersCommand::Delete { email } => delete_subscriber(&connection_pool, &email).await,
+    }
+}
+
+pub async fn issue(command: IssueCommand, configuration: &Settings) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+
+    match command {
+        IssueCommand::Send { title, html, text } => {
+            let issue = NewsletterIssue {
+                title,
+                html_content: read_file(&html)?,
+                text_content: read_file(&text)?,
+            };
+            let email_client = configuration.email_client.clone().client()?;
+
+            let newsletter_issue_id = publish_issue(&connection_pool, &email_client, &issue)
+                .await
+                .context("Failed to publish the newsletter issue")?;
+            println!("Published newsletter issue {}", newsletter_issue_id);
+
+            Ok(())
+        }
+        IssueCommand::Status {
+            newsletter_issue_id,
+        } => {
+            let status = get_issue_status(&connection_pool, newsletter_issue_id)
+                .await
+                .context("Failed to get the newsletter issue")?
+                .with_context(|| format!("No newsletter issue with id {}", newsletter_issue_id))?;
+
+            println!("Issue:        {}", status.newsletter_issue_id);
+            println!("Title:        {}", status.title);
+            println!("Status:       {}", status.status);
+            println!("Published at: {}", status.published_at);
+            if let Some(completed_at) = status.completed_at {
+                println!("Completed at: {}", completed_at);
+            }
+            println!("Delivered:    {}", status.delivered);
+            println!("Suppressed:   {}", status.suppressed);
+            println!("Skipped:      {}", status.skipped);
+
+            Ok(())
+        }
+    }
+}
+
+pub async fn tokens(command: TokensCommand, configuration: &Settings) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+
+    match command {
+        TokensCommand::Purge => {
+            let purged = purge_subscription_tokens(&connection_pool)
+                .await
+                .context("Failed to purge the subscription tokens")?;
+            println!("Purged {} subscription token(s)", purged);
+
+            Ok(())
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, anyhow::Error> {
+    std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Existing subscribers are left as they are, whatever their status
+#[tracing::instrument(name = "Import subscribers", skip(connection_pool, base_url))]
+async fn import_subscribers(
+    connection_pool: &PgPool,
+    file: &Path,
+    confirmed: bool,
+    base_url: &str,
+) -> Result<(), anyhow::Error> {
+    let mut reader = csv::Reader::from_path(file)
+        .with_context(|| format!("Failed to open {}", file.display()))?;
+
+    let mut new_subscribers = Vec::new();
+    let mut invalid_lines = Vec::new();
+    for (index, record) in reader.deserialize::<FormData>().enumerate() {
+        // The first line holds the headers
+        let line = index + 2;
+        match record
+            .map_err(|e| e.to_string())
+            .and_then(NewSubscriber::try_from)
+        {
+            Ok(new_subscriber) => new_subscribers.push(new_subscriber),
+            Err(e) => invalid_lines.push(format!("line {}: {}", line, e)),
+        }
+    }
+    if !invalid_lines.is_empty() {
+        anyhow::bail!(
+            "Nothing was imported, {} line(s) are not valid:\n{}",
+            invalid_lines.len(),
+            invalid_lines.join("\n")
+        );
+    }
+
+    let mut transaction = connection_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let (mut imported, mut skipped) = (0, 0);
+    for new_subscriber in &new_subscribers {
+        if subscriber_exists(&mut transaction, &new_subscriber.email)
+            .await
+            .context("Failed to look for an existing subscriber")?
+        {
+            skipped += 1;
+            continue;
+        }
+
+        let subscriber_id = insert_subscriber(new_subscriber, &mut transaction)
+            .await
+            .context("Failed to insert a new subscriber in the database")?;
+        if confirmed {
+            confirm_subscriber(&mut *transaction, &subscriber_id)
+                .await
+                .context("Failed to confirm an imported subscriber")?;
+        } else {
+            let subscription_token = generate_subscription_token();
+            store_subscription_token(&mut transaction, &subscriber_id, &subscription_token)
+                .await
+                .context("Failed to store the confirmation token for a new subscriber")?;
+            enqueue_confirmation_email(
+                &mut transaction,
+                &new_subscriber.email,
+                base_url,
+                &subscription_token,
+            )
+            .await
+            .context("Failed to enqueue a confirmation email")?;
+        }
+        imported += 1;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to import subscribers")?;
+
+    println!(
+        "Imported {} subscriber(s), skipped {} already subscribed",
+        imported, skipped
+    );
+
+    Ok(())
+}
+
+async fn subscriber_exists(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id FROM subscriptions WHERE email_normalized = $1",
+        email.normalized()
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+#[tracing::instrument(name = "Export subscribers", skip(connection_pool))]
+async fn export_subscribers(connection_pool: &PgPool) -> Result<(), anyhow::Error> {
+    let subscribers = sqlx::query_as!(
+        ExportedSubscriber,
+        "SELECT email, name, status, subscribed_at FROM subscriptions ORDER BY subscribed_at"
+    )
+    .fetch_all(connection_pool)
+    .await
+    .context("Failed to fetch the subscribers")?;
+
+    let mut writer = csv::Writer::from_writer(std::io::stdout());
+    for subscriber in subscribers {
+        writer
+            .serialize(subscriber)
+            .context("Failed to write a subscriber")?;
+    }
+    writer.flush().context("Failed to write the subscribers")?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "Delete a subscriber", skip(connection_pool, email))]
+async fn delete_subscriber(connection_pool: &PgPool, email: &str) -> Result<(), anyhow::Error> {
+    let email = SubscriberEmail::parse(email).map_err(anyhow::Error::msg)?;
+    let mut transaction = connection_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    sqlx::query!(
+        "DELETE FROM subscription_tokens \
+        USING subscriptions \
+        WHERE subscription_tokens.subscriber_id = subscriptions.id \
+        AND subscriptions.email_normalized = $1",
+        email.normalized()
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to delete the subscription tokens")?;
+    sqlx::query!(
+        "DELETE FROM outbox \
+        WHERE recipient IN (SELECT email FROM subscriptions WHERE email_normalized = $1) \
+        AND status = 'pending'",
+        email.normalized()
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to delete the pending emails")?;
+    let deleted = sqlx::query!(
+        "DELETE FROM subscriptions WHERE email_normalized = $1",
+        email.normalized()
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to delete the subscriber")?
+    .rows_affected();
+    if deleted == 0 {
+        anyhow::bail!("There is no subscriber with this email");
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to delete a subscriber")?;
+    println!("Deleted the subscriber");
+
+    Ok(())
+}
+
+/// Confirmed and suppressed subscribers have no use for their token anymore
+#[tracing::instrument(name = "Purge subscription tokens", skip(connection_pool))]
+async fn purge_subscription_tokens(connection_pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let purged = sqlx::query!(
+        "DELETE FROM subscription_tokens \
+        USING subscriptions \
+        WHERE subscription_tokens.subscriber_id = subscriptions.id \
+        AND subscriptions.status <> 'pending_confirmation'"
+    )
+    .execute(connection_pool)
+    .await?
+    .rows_affected();
+
+    Ok(purged)
+}
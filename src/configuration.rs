@@ -1,19 +1,60 @@
 use secrecy::{ExposeSecret, Secret};
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use crate::domain::SubscriberEmail;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::domain::{EmailNormalization, SubscriberEmail};
+use crate::email_client::EmailClient;
+use crate::routes::error_chain_fmt;
+use crate::secrets::{FileSecretProvider, SecretProvider, SECRET_KEYS};
+use crate::telemetry::RedactionPolicy;
 
+/// Requests to the email provider that take longer than this are a misconfiguration
+const MAX_EMAIL_TIMEOUT_MILLISECONDS: u64 = 5 * 60 * 1000;
+
+const MIN_ADMIN_TOKEN_LENGTH: usize = 32;
+
+const MIN_FORM_TOKEN_SECRET_LENGTH: usize = 32;
+
+const MIN_REDACTION_HASH_KEY_LENGTH: usize = 32;
+
+const MIN_BUCKET_KEY_SECRET_LENGTH: usize = 32;
+
+/// Supported formats for configuration files. Exactly one of these extensions
+/// may exist for each file: there is no precedence between them.
+const CONFIGURATION_FILE_EXTENSIONS: [&str; 4] = ["yaml", "yml", "toml", "json"];
+
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(try_from = "String")]
 pub enum Environment {
+    #[default]
     Local,
     Production,
+    /// Any other environment, e.g. `staging` or `ci`, configured by
+    /// `configurations/{name}.yaml` (or `.toml`, `.json`)
+    Named(String),
 }
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
+    /// Set from `APP_ENVIRONMENT` by `get_configuration`
+    #[serde(default)]
+    pub environment: Environment,
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub outbox: OutboxSettings,
+    #[serde(default)]
+    pub telemetry: TelemetrySettings,
+    #[serde(default)]
+    pub health: HealthSettings,
+    /// Which spellings of an address are the same subscriber
+    #[serde(default)]
+    pub email_normalization: EmailNormalization,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -22,6 +63,191 @@ pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub base_url: String,
+    /// Bearer token for the `/admin` endpoints, they are disabled when unset
+    #[serde(default)]
+    pub admin_token: Option<Secret<String>>,
+    /// Serve `/metrics` on this port instead of the application port
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub metrics_port: Option<u16>,
+    /// On shutdown, how long in-flight requests and background workers
+    /// get to complete before they are cut off
+    #[serde(
+        default = "default_shutdown_grace_period_seconds",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub shutdown_grace_period_seconds: u64,
+    /// Terminate TLS in the application, for deployments without
+    /// a proxy in front of it
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+    /// Limits on `POST /subscriptions`, every request can send an email
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    /// Checks telling bots apart from people on `POST /subscriptions`
+    #[serde(default)]
+    pub anti_bot: AntiBotSettings,
+    /// Which email addresses are accepted on `POST /subscriptions`
+    #[serde(default)]
+    pub email_policy: EmailPolicySettings,
+}
+
+fn default_shutdown_grace_period_seconds() -> u64 {
+    30
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Where the token buckets are kept
+    pub store: RateLimitStore,
+    /// Subscription requests coming from the same IP address
+    pub per_ip: TokenBucketSettings,
+    /// Subscription requests for the same email address
+    pub per_email: TokenBucketSettings,
+    /// Minimum time before the confirmation email is sent again
+    /// to an address that is still pending confirmation
+    pub confirmation_cooldown_seconds: u64,
+    /// Take the client IP address from the last entry of `X-Forwarded-For`,
+    /// the one added by the proxy. Only enable it behind a proxy that sets it,
+    /// clients can send anything.
+    pub trust_forwarded_headers: bool,
+    /// Key of the HMAC that turns IP and email addresses into bucket keys.
+    /// Required by the `postgres` store, the `memory` store falls back to
+    /// a random key.
+    pub bucket_key_secret: Option<Secret<String>>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            store: RateLimitStore::Memory,
+            per_ip: TokenBucketSettings {
+                capacity: 10,
+                refill_period_seconds: 60,
+            },
+            per_email: TokenBucketSettings {
+                capacity: 3,
+                refill_period_seconds: 3600,
+            },
+            confirmation_cooldown_seconds: 300,
+            trust_forwarded_headers: false,
+            bucket_key_secret: None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStore {
+    /// Each instance keeps its own buckets
+    Memory,
+    /// The buckets are shared by every instance
+    Postgres,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct EmailPolicySettings {
+    /// Reject the domains of `assets/disposable_domains.txt`
+    pub block_disposable_domains: bool,
+    /// Reject `postmaster@`, `abuse@`, `noreply@`, ...
+    pub reject_role_addresses: bool,
+    /// Reject the domains that do not receive emails, not checked when unset
+    pub deliverability: Option<DeliverabilitySettings>,
+}
+
+impl Default for EmailPolicySettings {
+    fn default() -> Self {
+        Self {
+            block_disposable_domains: true,
+            reject_role_addresses: true,
+            deliverability: None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeliverabilitySettings {
+    /// Where the MX, A and AAAA records of the domains are looked up
+    #[serde(default)]
+    pub resolver: DomainResolverSettings,
+    /// How long the answer for a domain is reused
+    #[serde(default = "default_deliverability_cache_ttl_seconds")]
+    pub cache_ttl_seconds: u64,
+}
+
+fn default_deliverability_cache_ttl_seconds() -> u64 {
+    3600
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum DomainResolverSettings {
+    /// The resolvers of the system, from `/etc/resolv.conf`
+    #[default]
+    System,
+    /// Only `domains` receive emails, for local development and tests
+    Static { domains: Vec<String> },
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct AntiBotSettings {
+    /// Reject the submissions that fill in the hidden `website` field
+    pub honeypot: bool,
+    /// Reject the submissions sent sooner than this after the form token
+    /// was issued, see `GET /subscriptions/form_token`. 0 disables the check.
+    /// Needs `form_token_secret`.
+    pub min_time_to_submit_seconds: u64,
+    /// Key signing the form tokens, shared by every instance. When set, every
+    /// submission needs a valid form token.
+    pub form_token_secret: Option<Secret<String>>,
+    /// Require a CAPTCHA response with every submission
+    pub captcha: Option<CaptchaSettings>,
+}
+
+impl Default for AntiBotSettings {
+    fn default() -> Self {
+        Self {
+            honeypot: true,
+            min_time_to_submit_seconds: 0,
+            form_token_secret: None,
+            captcha: None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum CaptchaSettings {
+    /// reCAPTCHA, hCaptcha and Turnstile all verify responses this way
+    SiteVerify {
+        /// e.g. `https://hcaptcha.com/siteverify`
+        verify_url: String,
+        secret: Option<Secret<String>>,
+    },
+    /// Accepts `accepted_response` and nothing else, for local development
+    Mock { accepted_response: String },
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenBucketSettings {
+    /// Requests allowed in a burst
+    pub capacity: u32,
+    /// One more request is allowed every `refill_period_seconds`
+    pub refill_period_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TlsSettings {
+    /// PEM file with the certificate chain, leaf first.
+    /// It is reloaded, together with the key, when it changes on disk.
+    pub certificate_path: PathBuf,
+    /// PEM file with the private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+    /// Redirect plain HTTP requests on this port to HTTPS
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub redirect_port: Option<u16>,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -33,6 +259,10 @@ pub struct DatabaseSettings {
     pub port: u16,
     pub database_name: String,
     pub require_ssl: bool,
+    /// Apply the pending migrations in `Application::build`. Instances starting
+    /// together wait for each other on a Postgres advisory lock.
+    #[serde(default)]
+    pub migrate_on_startup: bool,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -41,13 +271,75 @@ pub struct EmailClientSettings {
     pub sender_email: String,
     pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
+    pub circuit_breaker: CircuitBreakerSettings,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct CircuitBreakerSettings {
+    /// Number of consecutive failures after which the circuit opens
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a probe request is allowed
+    pub cooldown_milliseconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct OutboxSettings {
+    /// Number of delivery attempts before an email is marked as failed
+    pub max_attempts: u32,
+    /// Delay before the first retry, it doubles with every failed attempt
+    pub retry_base_delay_milliseconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct TelemetrySettings {
+    /// How emails, names and tokens are written to logs and traces
+    #[serde(default)]
+    pub redaction: RedactionPolicy,
+    /// Replaces the log filter when set, e.g. `info,zero2prod=debug`.
+    /// `RUST_LOG`, or `info`, is used otherwise.
+    #[serde(default)]
+    pub log_filter: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HealthSettings {
+    /// Also check, in `/health/ready`, that the email provider is reachable
+    pub check_email_provider: bool,
+    /// How long the outcome of the email provider check is reused
+    pub email_provider_cache_seconds: u64,
+    /// A background worker that has not reported for this long is considered stuck
+    pub worker_heartbeat_timeout_seconds: u64,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            check_email_provider: false,
+            email_provider_cache_seconds: 60,
+            worker_heartbeat_timeout_seconds: 60,
+        }
+    }
+}
+
+impl HealthSettings {
+    /// `None` when the email provider is not checked
+    pub fn email_provider_cache(&self) -> Option<std::time::Duration> {
+        self.check_email_provider
+            .then(|| std::time::Duration::from_secs(self.email_provider_cache_seconds))
+    }
+
+    pub fn worker_heartbeat_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.worker_heartbeat_timeout_seconds)
+    }
 }
 
 impl Environment {
-    pub fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &str {
         match self {
             Environment::Local => "local",
             Environment::Production => "production",
+            Environment::Named(name) => name,
         }
     }
 }
@@ -59,15 +351,383 @@ impl TryFrom<String> for Environment {
         match value.to_lowercase().as_str() {
             "local" => Ok(Self::Local),
             "production" => Ok(Self::Production),
+            // The name is used as a file name, keep it boring
+            other
+                if !other.is_empty()
+                    && other
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                Ok(Self::Named(other.to_string()))
+            }
             other => Err(format!(
-                "{} is not a supported environment.\
-                Use either `local` or `production`",
+                "{:?} is not a valid environment name. \
+                Use letters, digits, `-` and `_` only",
                 other
             )),
         }
     }
 }
 
+/// Every problem found by `Settings::validate`, reported together so that
+/// a broken configuration can be fixed in one go.
+pub struct InvalidSettings {
+    pub problems: Vec<String>,
+}
+
+impl std::error::Error for InvalidSettings {}
+
+impl std::fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The configuration is invalid:")?;
+        for problem in &self.problems {
+            write!(f, "\n- {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for InvalidSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Settings {
+    /// Check every setting upfront instead of failing on first use.
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut problems = Vec::new();
+        let production = self.environment == Environment::Production;
+
+        // Application
+        if self.application.host.trim().is_empty() {
+            problems.push("application.host must not be empty".into());
+        }
+        match reqwest::Url::parse(&self.application.base_url) {
+            Ok(url) => match (url.scheme(), production) {
+                ("https", _) | ("http", false) => {}
+                ("http", true) => problems.push(
+                    "application.base_url must use https in the production environment".into(),
+                ),
+                (scheme, _) => problems.push(format!(
+                    "application.base_url must use http or https, not {}",
+                    scheme
+                )),
+            },
+            Err(e) => problems.push(format!(
+                "application.base_url ({:?}) is not a valid URL: {}",
+                self.application.base_url, e
+            )),
+        }
+        if let Some(TlsSettings {
+            redirect_port: Some(redirect_port),
+            ..
+        }) = &self.application.tls
+        {
+            // Port 0 picks a random port for each listener
+            if *redirect_port != 0 && *redirect_port == self.application.port {
+                problems
+                    .push("application.tls.redirect_port must differ from application.port".into());
+            }
+        }
+        for (name, bucket) in [
+            ("per_ip", &self.application.rate_limit.per_ip),
+            ("per_email", &self.application.rate_limit.per_email),
+        ] {
+            if bucket.capacity == 0 {
+                problems.push(format!(
+                    "application.rate_limit.{}.capacity must be at least 1",
+                    name
+                ));
+            }
+            if bucket.refill_period_seconds == 0 {
+                problems.push(format!(
+                    "application.rate_limit.{}.refill_period_seconds must be at least 1",
+                    name
+                ));
+            }
+        }
+        match &self.application.rate_limit.bucket_key_secret {
+            Some(secret) if secret.expose_secret().len() < MIN_BUCKET_KEY_SECRET_LENGTH => problems
+                .push(format!(
+                    "application.rate_limit.bucket_key_secret must be at least {} characters long",
+                    MIN_BUCKET_KEY_SECRET_LENGTH
+                )),
+            None if self.application.rate_limit.store == RateLimitStore::Postgres => problems.push(
+                "application.rate_limit.bucket_key_secret must be set \
+                    to use the `postgres` store"
+                    .into(),
+            ),
+            _ => {}
+        }
+        let anti_bot = &self.application.anti_bot;
+        match &anti_bot.form_token_secret {
+            Some(secret) if secret.expose_secret().len() < MIN_FORM_TOKEN_SECRET_LENGTH => problems
+                .push(format!(
+                    "application.anti_bot.form_token_secret must be at least {} characters long",
+                    MIN_FORM_TOKEN_SECRET_LENGTH
+                )),
+            None if anti_bot.min_time_to_submit_seconds > 0 => problems.push(
+                "application.anti_bot.form_token_secret must be set \
+                when application.anti_bot.min_time_to_submit_seconds is set"
+                    .into(),
+            ),
+            _ => {}
+        }
+        match &anti_bot.captcha {
+            Some(CaptchaSettings::SiteVerify { verify_url, secret }) => {
+                if !verify_url.starts_with("https://") && production {
+                    problems.push(
+                        "application.anti_bot.captcha.verify_url must use https \
+                        in the production environment"
+                            .into(),
+                    );
+                }
+                if secret.is_none() {
+                    problems.push("application.anti_bot.captcha.secret must be set".into());
+                }
+            }
+            Some(CaptchaSettings::Mock { .. }) if self.environment != Environment::Local => {
+                problems.push(
+                    "application.anti_bot.captcha.provider can only be mock \
+                    in the local environment"
+                        .into(),
+                );
+            }
+            _ => {}
+        }
+        if let Some(DeliverabilitySettings {
+            resolver: DomainResolverSettings::Static { .. },
+            ..
+        }) = &self.application.email_policy.deliverability
+        {
+            if self.environment != Environment::Local {
+                problems.push(
+                    "application.email_policy.deliverability.resolver.provider can only be static \
+                    in the local environment"
+                        .into(),
+                );
+            }
+        }
+        if let Some(admin_token) = &self.application.admin_token {
+            if admin_token.expose_secret().len() < MIN_ADMIN_TOKEN_LENGTH {
+                problems.push(format!(
+                    "application.admin_token must be at least {} characters long",
+                    MIN_ADMIN_TOKEN_LENGTH
+                ));
+            }
+        }
+
+        // Database
+        if self.database.host.trim().is_empty() {
+            problems.push("database.host must not be empty".into());
+        }
+        if self.database.port == 0 {
+            problems.push("database.port must be between 1 and 65535".into());
+        }
+        if self.database.username.trim().is_empty() {
+            problems.push("database.username must not be empty".into());
+        }
+        if self.database.database_name.trim().is_empty() {
+            problems.push("database.database_name must not be empty".into());
+        }
+        if production && !self.database.require_ssl {
+            problems.push("database.require_ssl must be true in the production environment".into());
+        }
+
+        // Email client
+        match reqwest::Url::parse(&self.email_client.base_url) {
+            Ok(url) if url.scheme() == "https" => {}
+            Ok(url) if url.scheme() == "http" && !production => {}
+            Ok(url) => problems.push(format!(
+                "email_client.base_url must use https{}, not {}",
+                if production { "" } else { " or http" },
+                url.scheme()
+            )),
+            Err(e) => problems.push(format!(
+                "email_client.base_url ({:?}) is not a valid URL: {}",
+                self.email_client.base_url, e
+            )),
+        }
+        if let Err(e) = self.email_client.sender_email() {
+            problems.push(format!("email_client.sender_email is invalid: {}", e));
+        }
+        if !(1..=MAX_EMAIL_TIMEOUT_MILLISECONDS).contains(&self.email_client.timeout_milliseconds) {
+            problems.push(format!(
+                "email_client.timeout_milliseconds must be between 1 and {}",
+                MAX_EMAIL_TIMEOUT_MILLISECONDS
+            ));
+        }
+        if self.email_client.circuit_breaker.failure_threshold == 0 {
+            problems
+                .push("email_client.circuit_breaker.failure_threshold must be at least 1".into());
+        }
+        if self.email_client.circuit_breaker.cooldown_milliseconds == 0 {
+            problems.push(
+                "email_client.circuit_breaker.cooldown_milliseconds must be at least 1".into(),
+            );
+        }
+
+        // Outbox
+        if self.outbox.max_attempts == 0 {
+            problems.push("outbox.max_attempts must be at least 1".into());
+        }
+        if self.outbox.retry_base_delay_milliseconds == 0 {
+            problems.push("outbox.retry_base_delay_milliseconds must be at least 1".into());
+        }
+
+        // Health
+        if self.health.worker_heartbeat_timeout_seconds == 0 {
+            problems.push("health.worker_heartbeat_timeout_seconds must be at least 1".into());
+        }
+
+        // Telemetry
+        if self.telemetry.redaction.disabled && self.environment != Environment::Local {
+            problems.push(
+                "telemetry.redaction.disabled is only allowed in the local environment".into(),
+            );
+        }
+        match &self.telemetry.redaction.hash_key {
+            Some(key) if key.expose_secret().len() < MIN_REDACTION_HASH_KEY_LENGTH => problems
+                .push(format!(
+                    "telemetry.redaction.hash_key must be at least {} characters long",
+                    MIN_REDACTION_HASH_KEY_LENGTH
+                )),
+            None if self.telemetry.redaction.hashes() => problems
+                .push("telemetry.redaction.hash_key must be set to use the `hash` strategy".into()),
+            _ => {}
+        }
+        if let Some(log_filter) = &self.telemetry.log_filter {
+            if let Err(e) = tracing_subscriber::EnvFilter::try_new(log_filter) {
+                problems.push(format!(
+                    "telemetry.log_filter ({:?}) is not a valid filter: {}",
+                    log_filter, e
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings { problems })
+        }
+    }
+
+    /// The settings that differ between `self` and `other` but can only be
+    /// applied by restarting the process (listener address, database, ...).
+    ///
+    /// Everything else (email client timeout and circuit breaker, outbox retries,
+    /// rate limits, anti-bot checks, email policy, log redaction and filter)
+    /// is reloadable.
+    pub fn structural_changes(&self, other: &Settings) -> Vec<&'static str> {
+        let (a, b) = (self, other);
+        let changes = [
+            ("environment", a.environment != b.environment),
+            ("application.host", a.application.host != b.application.host),
+            ("application.port", a.application.port != b.application.port),
+            (
+                "application.metrics_port",
+                a.application.metrics_port != b.application.metrics_port,
+            ),
+            (
+                "application.base_url",
+                a.application.base_url != b.application.base_url,
+            ),
+            (
+                "application.admin_token",
+                a.application
+                    .admin_token
+                    .as_ref()
+                    .map(|t| t.expose_secret())
+                    != b.application
+                        .admin_token
+                        .as_ref()
+                        .map(|t| t.expose_secret()),
+            ),
+            (
+                "application.shutdown_grace_period_seconds",
+                a.application.shutdown_grace_period_seconds
+                    != b.application.shutdown_grace_period_seconds,
+            ),
+            ("application.tls", a.application.tls != b.application.tls),
+            (
+                "application.rate_limit.store",
+                a.application.rate_limit.store != b.application.rate_limit.store,
+            ),
+            ("database.host", a.database.host != b.database.host),
+            ("database.port", a.database.port != b.database.port),
+            (
+                "database.username",
+                a.database.username != b.database.username,
+            ),
+            (
+                "database.password",
+                a.database.password.expose_secret() != b.database.password.expose_secret(),
+            ),
+            (
+                "database.database_name",
+                a.database.database_name != b.database.database_name,
+            ),
+            (
+                "database.require_ssl",
+                a.database.require_ssl != b.database.require_ssl,
+            ),
+            (
+                "database.migrate_on_startup",
+                a.database.migrate_on_startup != b.database.migrate_on_startup,
+            ),
+            (
+                "email_client.base_url",
+                a.email_client.base_url != b.email_client.base_url,
+            ),
+            (
+                "email_client.sender_email",
+                a.email_client.sender_email != b.email_client.sender_email,
+            ),
+            (
+                "email_client.authorization_token",
+                a.email_client.authorization_token.expose_secret()
+                    != b.email_client.authorization_token.expose_secret(),
+            ),
+            ("health", a.health != b.health),
+            (
+                "email_normalization",
+                a.email_normalization != b.email_normalization,
+            ),
+        ];
+
+        changes
+            .into_iter()
+            .filter_map(|(name, changed)| changed.then_some(name))
+            .collect()
+    }
+}
+
+impl ApplicationSettings {
+    pub fn shutdown_grace_period(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_grace_period_seconds)
+    }
+}
+
+impl RateLimitSettings {
+    pub fn confirmation_cooldown(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.confirmation_cooldown_seconds)
+    }
+}
+
+impl TokenBucketSettings {
+    pub fn refill_period(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.refill_period_seconds)
+    }
+}
+
+impl DeliverabilitySettings {
+    pub fn cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cache_ttl_seconds)
+    }
+}
+
 impl DatabaseSettings {
     pub fn with_db(&self) -> PgConnectOptions {
         self.without_db().database(&self.database_name)
@@ -90,6 +750,26 @@ impl DatabaseSettings {
 }
 
 impl EmailClientSettings {
+    pub fn client(self) -> Result<EmailClient, InvalidSettings> {
+        let sender_email = self.sender_email().map_err(|e| InvalidSettings {
+            problems: vec![format!("email_client.sender_email is invalid: {}", e)],
+        })?;
+        let timeout = self.timeout();
+        let circuit_breaker = CircuitBreaker::new(
+            "email_client",
+            self.circuit_breaker.failure_threshold,
+            self.circuit_breaker.cooldown(),
+        );
+
+        Ok(EmailClient::new(
+            self.base_url,
+            sender_email,
+            self.authorization_token,
+            timeout,
+            circuit_breaker,
+        ))
+    }
+
     pub fn sender_email(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(&self.sender_email)
     }
@@ -99,37 +779,592 @@ impl EmailClientSettings {
     }
 }
 
-pub fn get_configuration() -> Result<Settings, config::ConfigError> {
-    let base_path = std::env::current_dir().expect("Failed to determine current directory");
-    println!("base_path: {:?}", base_path.to_str());
+impl CircuitBreakerSettings {
+    pub fn cooldown(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.cooldown_milliseconds)
+    }
+}
 
-    let configuration_directory = base_path.join("configurations");
+impl OutboxSettings {
+    pub fn retry_base_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.retry_base_delay_milliseconds)
+    }
+}
+
+/// The directory holding the configuration files: `APP_CONFIG_DIR` if set,
+/// `configurations` under the current working directory otherwise.
+pub fn default_configuration_directory() -> PathBuf {
+    match std::env::var_os("APP_CONFIG_DIR") {
+        Some(directory) => PathBuf::from(directory),
+        None => std::env::current_dir()
+            .expect("Failed to determine current directory")
+            .join("configurations"),
+    }
+}
 
+pub fn get_configuration() -> Result<Settings, config::ConfigError> {
+    get_configuration_from(&default_configuration_directory(), &FileSecretProvider)
+}
+
+/// Layer, from lowest to highest precedence:
+/// - `{configuration_directory}/base.{yaml,yml,toml,json}`
+/// - `{configuration_directory}/{APP_ENVIRONMENT}.{yaml,yml,toml,json}`
+/// - `APP_*` environment variables
+/// - secrets resolved through `secret_provider`
+pub fn get_configuration_from(
+    configuration_directory: &Path,
+    secret_provider: &dyn SecretProvider,
+) -> Result<Settings, config::ConfigError> {
     // Detect the running environment
     // Default to `local` if unspecified
     let environment: Environment = std::env::var("APP_ENVIRONMENT")
         .unwrap_or_else(|_| "local".into())
         .try_into()
-        .expect("Failed to parse APP_ENVIRONMENT");
+        .map_err(config::ConfigError::Message)?;
+    let variables = std::env::vars()
+        .filter(|(name, _)| name.starts_with("APP_"))
+        .collect();
+
+    load_configuration(
+        configuration_directory,
+        environment,
+        variables,
+        secret_provider,
+    )
+}
+
+/// `get_configuration_from`, with the environment and the `APP_*` variables
+/// passed in rather than read from the process
+pub(crate) fn load_configuration(
+    configuration_directory: &Path,
+    environment: Environment,
+    variables: HashMap<String, String>,
+    secret_provider: &dyn SecretProvider,
+) -> Result<Settings, config::ConfigError> {
+    let base_file = find_configuration_file(configuration_directory, "base")?;
+    let environment_file = find_configuration_file(configuration_directory, environment.as_str())?;
+    let mut sources = vec![
+        base_file.display().to_string(),
+        environment_file.display().to_string(),
+    ];
 
-    let environment_filename = format!("{}.yaml", environment.as_str());
+    // Only the names: the values may be secrets
+    let mut names: Vec<&str> = variables.keys().map(String::as_str).collect();
+    names.sort();
+    if !names.is_empty() {
+        sources.push(format!("environment variables ({})", names.join(", ")));
+    }
 
     let settings = config::Config::builder()
-        .add_source(config::File::from(
-            configuration_directory.join("base.yaml"),
-        ))
-        .add_source(config::File::from(
-            configuration_directory.join(environment_filename),
-        ))
+        .add_source(config::File::from(base_file))
+        .add_source(config::File::from(environment_file))
         // Add in settings from environment variables to Digital Ocean
         // (with a prefix of APP and '__' as separator)
         // E.g. `APP_APPLICATION__PORT=5001 would set `Settings.application.port`
         .add_source(
             config::Environment::with_prefix("APP")
                 .prefix_separator("_")
-                .separator("__"),
+                .separator("__")
+                .source(Some(variables)),
         )
+        .set_override("environment", environment.as_str())?
         .build()?;
 
-    settings.try_deserialize::<Settings>()
+    // Secrets are resolved on the side and never go through `config::Value`s,
+    // so they are only ever held in a `Secret`
+    let mut secrets = Vec::new();
+    for key in SECRET_KEYS {
+        let secret = secret_provider
+            .resolve(key, &settings)
+            .map_err(|e| config::ConfigError::Foreign(Box::new(e)))?;
+        if let Some(secret) = secret {
+            sources.push(format!("secret provider ({})", key));
+            secrets.push((key, secret));
+        }
+    }
+
+    let mut settings = settings.try_deserialize::<Settings>()?;
+    for (key, secret) in secrets {
+        match key {
+            "database.password" => settings.database.password = secret,
+            "email_client.authorization_token" => {
+                settings.email_client.authorization_token = secret
+            }
+            "application.admin_token" => settings.application.admin_token = Some(secret),
+            "application.anti_bot.form_token_secret" => {
+                settings.application.anti_bot.form_token_secret = Some(secret)
+            }
+            "application.rate_limit.bucket_key_secret" => {
+                settings.application.rate_limit.bucket_key_secret = Some(secret)
+            }
+            "telemetry.redaction.hash_key" => settings.telemetry.redaction.hash_key = Some(secret),
+            "application.anti_bot.captcha.secret" => {
+                if let Some(CaptchaSettings::SiteVerify {
+                    secret: current, ..
+                }) = &mut settings.application.anti_bot.captcha
+                {
+                    *current = Some(secret)
+                }
+            }
+            _ => unreachable!("Unknown secret key {}", key),
+        }
+    }
+
+    tracing::info!(
+        environment = environment.as_str(),
+        sources = ?sources,
+        "Loaded the configuration"
+    );
+
+    Ok(settings)
+}
+
+/// Look for `{name}.yaml`, `{name}.yml`, `{name}.toml` or `{name}.json`.
+/// Having more than one of them is ambiguous, so it is an error.
+fn find_configuration_file(directory: &Path, name: &str) -> Result<PathBuf, config::ConfigError> {
+    let candidates: Vec<PathBuf> = CONFIGURATION_FILE_EXTENSIONS
+        .iter()
+        .map(|extension| directory.join(format!("{}.{}", name, extension)))
+        .filter(|path| path.is_file())
+        .collect();
+
+    match candidates.as_slice() {
+        [path] => Ok(path.clone()),
+        [] => Err(config::ConfigError::Message(format!(
+            "No configuration file for `{}` in {:?}, expected one of {}",
+            name,
+            directory,
+            CONFIGURATION_FILE_EXTENSIONS
+                .map(|extension| format!("{}.{}", name, extension))
+                .join(", ")
+        ))),
+        _ => Err(config::ConfigError::Message(format!(
+            "Found several configuration files for `{}` in {:?}: {:?}",
+            name, directory, candidates
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_configuration_file, load_configuration, AntiBotSettings, ApplicationSettings,
+        CaptchaSettings, CircuitBreakerSettings, DatabaseSettings, DeliverabilitySettings,
+        DomainResolverSettings, EmailClientSettings, EmailPolicySettings, Environment,
+        HealthSettings, OutboxSettings, RateLimitSettings, RateLimitStore, RedactionPolicy,
+        Settings, TelemetrySettings, TlsSettings,
+    };
+    use crate::domain::EmailNormalization;
+    use crate::secrets::FileSecretProvider;
+    use crate::telemetry::RedactionStrategy;
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn settings() -> Settings {
+        Settings {
+            environment: Environment::Local,
+            database: DatabaseSettings {
+                username: "postgres".into(),
+                password: Secret::new("password".into()),
+                host: "127.0.0.1".into(),
+                port: 5432,
+                database_name: "newsletter".into(),
+                require_ssl: false,
+                migrate_on_startup: false,
+            },
+            application: ApplicationSettings {
+                host: "127.0.0.1".into(),
+                port: 8000,
+                base_url: "http://127.0.0.1".into(),
+                admin_token: None,
+                metrics_port: None,
+                shutdown_grace_period_seconds: 30,
+                tls: None,
+                rate_limit: RateLimitSettings::default(),
+                anti_bot: AntiBotSettings::default(),
+                email_policy: EmailPolicySettings::default(),
+            },
+            email_client: EmailClientSettings {
+                base_url: "http://localhost".into(),
+                sender_email: "test@example.com".into(),
+                authorization_token: Secret::new("my-secret-token".into()),
+                timeout_milliseconds: 10000,
+                circuit_breaker: CircuitBreakerSettings {
+                    failure_threshold: 5,
+                    cooldown_milliseconds: 30000,
+                },
+            },
+            outbox: OutboxSettings {
+                max_attempts: 10,
+                retry_base_delay_milliseconds: 5000,
+            },
+            telemetry: TelemetrySettings {
+                redaction: RedactionPolicy {
+                    hash_key: Some(Secret::new("a".repeat(32))),
+                    ..RedactionPolicy::default()
+                },
+                log_filter: None,
+            },
+            health: HealthSettings::default(),
+            email_normalization: EmailNormalization::default(),
+        }
+    }
+
+    #[test]
+    fn the_local_settings_are_valid() {
+        assert_ok!(settings().validate());
+    }
+
+    #[test]
+    fn production_requires_ssl_and_https() {
+        let mut settings = settings();
+        settings.environment = Environment::Production;
+
+        let problems = settings.validate().unwrap_err().problems;
+
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("application.base_url")));
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("database.require_ssl")));
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("email_client.base_url")));
+    }
+
+    #[test]
+    fn secure_production_settings_are_valid() {
+        let mut settings = settings();
+        settings.environment = Environment::Production;
+        settings.application.base_url = "https://zero2prod.example.com".into();
+        settings.database.require_ssl = true;
+        settings.email_client.base_url = "https://api.postmarkapp.com".into();
+
+        assert_ok!(settings.validate());
+    }
+
+    #[test]
+    fn the_https_redirect_needs_its_own_port() {
+        let mut settings = settings();
+        settings.application.tls = Some(TlsSettings {
+            certificate_path: "certificate.pem".into(),
+            key_path: "key.pem".into(),
+            redirect_port: Some(settings.application.port),
+        });
+
+        let problems = settings.validate().unwrap_err().problems;
+
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("application.tls.redirect_port"));
+    }
+
+    #[test]
+    fn the_time_to_submit_check_needs_a_form_token_secret() {
+        let mut settings = settings();
+        settings.application.anti_bot.min_time_to_submit_seconds = 3;
+
+        let problems = settings.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("application.anti_bot.form_token_secret"));
+
+        settings.application.anti_bot.form_token_secret = Some(Secret::new("a".repeat(32)));
+        assert_ok!(settings.validate());
+    }
+
+    #[test]
+    fn form_token_secrets_must_not_be_guessable() {
+        let mut settings = settings();
+        settings.application.anti_bot.form_token_secret = Some(Secret::new("secret".into()));
+
+        let problems = settings.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("application.anti_bot.form_token_secret"));
+    }
+
+    #[test]
+    fn the_postgres_rate_limit_store_needs_a_bucket_key_secret() {
+        let mut settings = settings();
+        settings.application.rate_limit.store = RateLimitStore::Postgres;
+
+        let problems = settings.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("application.rate_limit.bucket_key_secret"));
+
+        settings.application.rate_limit.bucket_key_secret = Some(Secret::new("short".into()));
+        assert_err!(settings.validate());
+
+        settings.application.rate_limit.bucket_key_secret = Some(Secret::new("a".repeat(32)));
+        assert_ok!(settings.validate());
+    }
+
+    #[test]
+    fn the_mock_captcha_is_only_allowed_locally() {
+        let mut settings = settings();
+        settings.application.anti_bot.captcha = Some(CaptchaSettings::Mock {
+            accepted_response: "human".into(),
+        });
+        assert_ok!(settings.validate());
+
+        settings.environment = Environment::Named("staging".into());
+        let problems = settings.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("application.anti_bot.captcha.provider"));
+    }
+
+    #[test]
+    fn the_static_domain_resolver_is_only_allowed_locally() {
+        let mut settings = settings();
+        settings.application.email_policy.deliverability = Some(DeliverabilitySettings {
+            resolver: DomainResolverSettings::Static {
+                domains: vec!["example.com".into()],
+            },
+            cache_ttl_seconds: 60,
+        });
+        assert_ok!(settings.validate());
+
+        settings.environment = Environment::Named("staging".into());
+        let problems = settings.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("application.email_policy.deliverability"));
+    }
+
+    #[test]
+    fn rate_limits_must_allow_some_requests() {
+        let mut settings = settings();
+        settings.application.rate_limit.per_ip.capacity = 0;
+        settings
+            .application
+            .rate_limit
+            .per_email
+            .refill_period_seconds = 0;
+
+        let problems = settings.validate().unwrap_err().problems;
+
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("application.rate_limit.per_ip.capacity"));
+        assert!(problems[1].starts_with("application.rate_limit.per_email.refill_period_seconds"));
+    }
+
+    #[test]
+    fn personal_data_is_only_logged_unredacted_locally() {
+        let mut settings = settings();
+        settings.telemetry.redaction.disabled = true;
+        assert_ok!(settings.validate());
+
+        settings.environment = Environment::Named("staging".into());
+        let problems = settings.validate().unwrap_err().problems;
+
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("telemetry.redaction.disabled"));
+    }
+
+    #[test]
+    fn hashing_personal_data_needs_a_key_that_cannot_be_guessed() {
+        let mut settings = settings();
+        settings.telemetry.redaction.hash_key = None;
+
+        let problems = settings.validate().unwrap_err().problems;
+
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("telemetry.redaction.hash_key"));
+
+        settings.telemetry.redaction.hash_key = Some(Secret::new("key".into()));
+        let problems = settings.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("telemetry.redaction.hash_key"));
+    }
+
+    #[test]
+    fn masking_personal_data_does_not_need_a_key() {
+        let mut settings = settings();
+        settings.telemetry.redaction = RedactionPolicy {
+            email: RedactionStrategy::Mask,
+            hash_key: None,
+            ..RedactionPolicy::default()
+        };
+
+        assert_ok!(settings.validate());
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let mut settings = settings();
+        settings.application.base_url = "not a url".into();
+        settings.database.port = 0;
+        settings.email_client.sender_email = "not-an-email".into();
+        settings.email_client.timeout_milliseconds = 0;
+        settings.outbox.max_attempts = 0;
+
+        let error = settings.validate().unwrap_err();
+
+        assert_eq!(error.problems.len(), 5, "{:?}", error.problems);
+        assert!(error.to_string().contains("email_client.sender_email"));
+    }
+
+    #[test]
+    fn an_invalid_sender_email_is_an_error_instead_of_a_panic() {
+        let mut settings = settings();
+        settings.email_client.sender_email = "not-an-email".into();
+
+        let error = settings.email_client.client().err().unwrap();
+
+        assert!(error.to_string().contains("email_client.sender_email"));
+    }
+
+    #[test]
+    fn reloadable_settings_are_not_structural_changes() {
+        let mut changed = settings();
+        changed.email_client.timeout_milliseconds = 500;
+        changed.email_client.circuit_breaker.failure_threshold = 1;
+        changed.outbox.max_attempts = 3;
+        changed.application.rate_limit.per_ip.capacity = 1;
+        changed.application.anti_bot.honeypot = false;
+        changed.application.email_policy.reject_role_addresses = false;
+        changed.telemetry.log_filter = Some("debug".into());
+
+        assert!(settings().structural_changes(&changed).is_empty());
+    }
+
+    #[test]
+    fn structural_changes_are_listed() {
+        let mut changed = settings();
+        changed.application.port = 9000;
+        changed.database.password = Secret::new("another-password".into());
+
+        assert_eq!(
+            settings().structural_changes(&changed),
+            vec!["application.port", "database.password"]
+        );
+    }
+
+    #[test]
+    fn log_filters_must_parse() {
+        let mut settings = settings();
+        settings.telemetry.log_filter = Some("zero2prod=loud".into());
+
+        let error = settings.validate().unwrap_err();
+
+        assert!(error.problems[0].starts_with("telemetry.log_filter"));
+    }
+
+    #[test]
+    fn admin_tokens_must_not_be_guessable() {
+        let mut settings = settings();
+        settings.application.admin_token = Some(Secret::new("admin".into()));
+
+        assert_err!(settings.validate());
+    }
+
+    #[test]
+    fn base_urls_must_use_http_or_https() {
+        let mut settings = settings();
+        settings.email_client.base_url = "ftp://localhost".into();
+
+        assert_err!(settings.validate());
+    }
+
+    fn configuration_directory(files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("zero2prod-config-{}", Uuid::new_v4()));
+        std::fs::create_dir(&directory).unwrap();
+        for (name, content) in files {
+            std::fs::write(directory.join(name), content).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn named_environments_are_accepted() {
+        assert_eq!(
+            Environment::try_from("Staging".to_string()),
+            Ok(Environment::Named("staging".into()))
+        );
+        assert_eq!(
+            Environment::try_from("production".to_string()),
+            Ok(Environment::Production)
+        );
+        assert_err!(Environment::try_from("../secrets".to_string()));
+        assert_err!(Environment::try_from("".to_string()));
+    }
+
+    #[test]
+    fn configuration_files_can_be_written_in_toml_and_json() {
+        let base = r#"
+            [application]
+            port = 8000
+            host = "127.0.0.1"
+            base_url = "http://127.0.0.1"
+
+            [database]
+            host = "127.0.0.1"
+            port = 5432
+            username = "postgres"
+            password = "password"
+            database_name = "newsletter"
+            require_ssl = false
+
+            [email_client]
+            base_url = "http://localhost"
+            sender_email = "test@example.com"
+            authorization_token = "my-secret-token"
+            timeout_milliseconds = 10000
+            circuit_breaker = { failure_threshold = 5, cooldown_milliseconds = 30000 }
+
+            [outbox]
+            max_attempts = 10
+            retry_base_delay_milliseconds = 5000
+        "#;
+        let directory = configuration_directory(&[
+            ("base.toml", base),
+            ("local.json", r#"{"application": {"port": 9000}}"#),
+        ]);
+
+        let settings = load_configuration(
+            &directory,
+            Environment::Local,
+            HashMap::new(),
+            &FileSecretProvider,
+        )
+        .unwrap();
+
+        assert_eq!(settings.application.port, 9000);
+        assert_eq!(settings.database.database_name, "newsletter");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn app_variables_take_precedence_over_the_files() {
+        let source = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("configurations");
+        let variables = HashMap::from([("APP_APPLICATION__PORT".to_string(), "9100".to_string())]);
+
+        let settings =
+            load_configuration(&source, Environment::Local, variables, &FileSecretProvider)
+                .unwrap();
+
+        assert_eq!(settings.application.port, 9100);
+        assert_eq!(settings.environment, Environment::Local);
+    }
+
+    #[test]
+    fn a_missing_environment_file_is_an_error() {
+        let directory = configuration_directory(&[("base.yaml", "application:\n  port: 8000")]);
+
+        assert_err!(find_configuration_file(&directory, "local"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn several_files_for_the_same_environment_are_an_error() {
+        let directory = configuration_directory(&[
+            ("local.yaml", "application:\n  port: 8000"),
+            ("local.toml", "[application]\nport = 8000"),
+        ]);
+
+        assert_err!(find_configuration_file(&directory, "local"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }
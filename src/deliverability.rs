@@ -0,0 +1,283 @@
+use crate::configuration::{DeliverabilitySettings, DomainResolverSettings};
+use anyhow::Context;
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A domain that takes longer than this to look up is let through
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Past this many domains, the cache forgets the ones that expired
+const MAX_CACHED_DOMAINS: usize = 10_000;
+
+/// Typos of these domains are suggested when a domain does not receive emails
+const POPULAR_DOMAINS: [&str; 16] = [
+    "aol.com",
+    "comcast.net",
+    "fastmail.com",
+    "gmail.com",
+    "gmx.com",
+    "gmx.de",
+    "hotmail.co.uk",
+    "hotmail.com",
+    "icloud.com",
+    "live.com",
+    "me.com",
+    "outlook.com",
+    "proton.me",
+    "protonmail.com",
+    "yahoo.co.uk",
+    "yahoo.com",
+];
+
+/// Tells whether a domain receives emails
+#[async_trait::async_trait]
+pub trait DomainResolver: Send + Sync {
+    /// `true` when the domain has MX records or, failing that, A or AAAA
+    /// records, mail is then delivered to the domain itself
+    async fn receives_email(&self, domain: &str) -> Result<bool, anyhow::Error>;
+}
+
+/// Looks the records up with the resolvers of the system
+pub struct HickoryResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl HickoryResolver {
+    pub fn from_system_conf() -> Self {
+        let (config, mut options) =
+            hickory_resolver::system_conf::read_system_conf().unwrap_or_else(|error| {
+                tracing::warn!(
+                    error.message = %error,
+                    "Failed to read the DNS configuration of the system, using the default resolvers"
+                );
+                (ResolverConfig::default(), ResolverOpts::default())
+            });
+        // `LOOKUP_TIMEOUT` caps the whole lookup anyway
+        options.timeout = Duration::from_secs(1);
+        options.attempts = 2;
+
+        Self {
+            resolver: TokioAsyncResolver::tokio(config, options),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainResolver for HickoryResolver {
+    #[tracing::instrument(name = "Look up the mail records of a domain", skip_all)]
+    async fn receives_email(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        // Fully qualified, the search domains of the system are not tried
+        let domain = format!("{}.", domain.trim_end_matches('.'));
+
+        match self.resolver.mx_lookup(domain.as_str()).await {
+            // A lone `.` exchange is a null MX, the domain takes no email (RFC 7505)
+            Ok(records) => return Ok(records.iter().any(|mx| !mx.exchange().is_root())),
+            Err(error) if is_no_records(&error) => {}
+            Err(error) => return Err(error).context("Failed to look up the MX records"),
+        }
+        match self.resolver.lookup_ip(domain.as_str()).await {
+            Ok(addresses) => Ok(addresses.iter().next().is_some()),
+            Err(error) if is_no_records(&error) => Ok(false),
+            Err(error) => Err(error).context("Failed to look up the A and AAAA records"),
+        }
+    }
+}
+
+/// The domain does not exist, or has no records of this type
+fn is_no_records(error: &ResolveError) -> bool {
+    matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+/// Only a fixed set of domains receives emails, for local development and tests
+pub struct StaticResolver {
+    domains: HashSet<String>,
+}
+
+impl StaticResolver {
+    pub fn new(domains: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            domains: domains.into_iter().map(|d| d.to_lowercase()).collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainResolver for StaticResolver {
+    async fn receives_email(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        Ok(self.domains.contains(domain))
+    }
+}
+
+struct CachedAnswer {
+    receives_email: bool,
+    expires_at: Instant,
+}
+
+/// Asks a `DomainResolver` whether subscribers' domains receive emails,
+/// remembering the answers for a while
+pub struct DeliverabilityCheck {
+    resolver: Box<dyn DomainResolver>,
+    cache: Mutex<HashMap<String, CachedAnswer>>,
+    cache_ttl: Duration,
+}
+
+impl DeliverabilityCheck {
+    pub fn new(settings: &DeliverabilitySettings) -> Self {
+        let resolver: Box<dyn DomainResolver> = match &settings.resolver {
+            DomainResolverSettings::System => Box::new(HickoryResolver::from_system_conf()),
+            DomainResolverSettings::Static { domains } => {
+                Box::new(StaticResolver::new(domains.clone()))
+            }
+        };
+
+        Self::with_resolver(resolver, settings.cache_ttl())
+    }
+
+    pub fn with_resolver(resolver: Box<dyn DomainResolver>, cache_ttl: Duration) -> Self {
+        Self {
+            resolver,
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl,
+        }
+    }
+
+    /// Failed lookups are not cached, the next subscriber tries again
+    #[tracing::instrument(name = "Check whether a domain receives emails", skip_all)]
+    pub async fn receives_email(&self, domain: &str) -> Result<bool, anyhow::Error> {
+        if let Some(receives_email) = self.cached(domain) {
+            return Ok(receives_email);
+        }
+
+        let receives_email =
+            tokio::time::timeout(LOOKUP_TIMEOUT, self.resolver.receives_email(domain))
+                .await
+                .context("Timed out looking up the domain")??;
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED_DOMAINS {
+            cache.retain(|_, answer| answer.expires_at > now);
+        }
+        cache.insert(
+            domain.to_owned(),
+            CachedAnswer {
+                receives_email,
+                expires_at: now + self.cache_ttl,
+            },
+        );
+
+        Ok(receives_email)
+    }
+
+    fn cached(&self, domain: &str) -> Option<bool> {
+        self.cache
+            .lock()
+            .unwrap()
+            .get(domain)
+            .filter(|answer| answer.expires_at > Instant::now())
+            .map(|answer| answer.receives_email)
+    }
+}
+
+/// The popular domain `domain` is most likely a typo of, e.g. `gmail.com` for `gmial.con`
+pub fn suggest_domain(domain: &str) -> Option<&'static str> {
+    if POPULAR_DOMAINS.contains(&domain) {
+        return None;
+    }
+
+    POPULAR_DOMAINS
+        .iter()
+        .map(|popular| (strsim::damerau_levenshtein(domain, popular), *popular))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, popular)| popular)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{suggest_domain, DeliverabilityCheck, DomainResolver, StaticResolver};
+    use claims::{assert_err, assert_none, assert_ok_eq, assert_some_eq};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Counts the lookups, `example.com` is the only domain receiving emails
+    struct CountingResolver {
+        lookups: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl DomainResolver for CountingResolver {
+        async fn receives_email(&self, domain: &str) -> Result<bool, anyhow::Error> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                anyhow::bail!("SERVFAIL");
+            }
+            Ok(domain == "example.com")
+        }
+    }
+
+    fn check(cache_ttl: Duration, fail: bool) -> (DeliverabilityCheck, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = CountingResolver {
+            lookups: lookups.clone(),
+            fail,
+        };
+        (
+            DeliverabilityCheck::with_resolver(Box::new(resolver), cache_ttl),
+            lookups,
+        )
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_until_they_expire() {
+        let (cached, lookups) = check(Duration::from_secs(60), false);
+        assert_ok_eq!(cached.receives_email("example.com").await, true);
+        assert_ok_eq!(cached.receives_email("example.com").await, true);
+        assert_ok_eq!(cached.receives_email("exampel.com").await, false);
+        assert_ok_eq!(cached.receives_email("exampel.com").await, false);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        let (expired, lookups) = check(Duration::ZERO, false);
+        assert_ok_eq!(expired.receives_email("example.com").await, true);
+        assert_ok_eq!(expired.receives_email("example.com").await, true);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_not_cached() {
+        let (check, lookups) = check(Duration::from_secs(60), true);
+
+        assert_err!(check.receives_email("example.com").await);
+        assert_err!(check.receives_email("example.com").await);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn the_static_resolver_only_knows_its_domains() {
+        let resolver = StaticResolver::new(["Example.com".to_string()]);
+
+        assert_ok_eq!(resolver.receives_email("example.com").await, true);
+        assert_ok_eq!(resolver.receives_email("gmial.con").await, false);
+    }
+
+    #[test]
+    fn typos_of_popular_domains_get_a_suggestion() {
+        assert_some_eq!(suggest_domain("gmial.con"), "gmail.com");
+        assert_some_eq!(suggest_domain("gmail.co"), "gmail.com");
+        assert_some_eq!(suggest_domain("hotmial.com"), "hotmail.com");
+        assert_some_eq!(suggest_domain("yaho.com"), "yahoo.com");
+    }
+
+    #[test]
+    fn other_domains_get_no_suggestion() {
+        assert_none!(suggest_domain("gmail.com"));
+        assert_none!(suggest_domain("example.com"));
+        assert_none!(suggest_domain("zero2prod.dev"));
+    }
+}
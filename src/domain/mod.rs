@@ -3,5 +3,7 @@ mod subscriber_email;
 mod subscriber_name;
 
 pub use new_subscriber::NewSubscriber;
-pub use subscriber_email::SubscriberEmail;
+pub use subscriber_email::{
+    set_email_normalization, EmailNormalization, ProviderRules, SubscriberEmail,
+};
 pub use subscriber_name::SubscriberName;
@@ -1,34 +1,120 @@
+use std::sync::RwLock;
 use validator::validate_email;
 
-#[derive(Debug)]
-pub struct SubscriberEmail(String);
+/// How `SubscriberEmail::normalized` is computed, see `set_email_normalization`.
+///
+/// Starts out without provider rules, as the migrations normalize the
+/// addresses already in the database.
+static EMAIL_NORMALIZATION: RwLock<EmailNormalization> = RwLock::new(EmailNormalization {
+    providers: Vec::new(),
+});
+
+/// Replace the normalization rules, once the configuration has been loaded.
+///
+/// Only the addresses parsed from now on follow them: the subscribers already
+/// in the database keep the keys they were given.
+pub fn set_email_normalization(normalization: EmailNormalization) {
+    *EMAIL_NORMALIZATION.write().unwrap() = normalization;
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct EmailNormalization {
+    /// Off by default: none of the providers promise to keep these rules
+    pub providers: Vec<ProviderRules>,
+}
+
+/// How a provider delivers to addresses that differ from the mailbox name
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProviderRules {
+    /// Punycode for international domains
+    pub domains: Vec<String>,
+    /// The domain every alias is normalized to
+    pub canonical_domain: String,
+    /// `u.r.sula` is `ursula`
+    #[serde(default)]
+    pub ignores_dots: bool,
+    /// `ursula+news` is `ursula`
+    #[serde(default)]
+    pub ignores_tags: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscriberEmail {
+    /// As the subscriber typed it, trimmed and with a lower case domain
+    address: String,
+    /// One key per mailbox, see `SubscriberEmail::normalized`
+    normalized: String,
+}
 
 impl SubscriberEmail {
-    pub fn parse(string: &String) -> Result<SubscriberEmail, String> {
-        if validate_email(string) {
-            Ok(Self(string.to_owned()))
-        } else {
-            Err(format!("{} is not a valid subscriber email", string))
+    pub fn parse(string: &str) -> Result<SubscriberEmail, String> {
+        let address = string.trim();
+        let parts = address.rsplit_once('@').and_then(|(local_part, domain)| {
+            let ascii_domain = idna::domain_to_ascii(domain).ok()?;
+            Some((local_part, domain.to_lowercase(), ascii_domain))
+        });
+
+        match parts {
+            Some((local_part, domain, ascii_domain)) if validate_email(address) => Ok(Self {
+                address: format!("{}@{}", local_part, domain),
+                normalized: normalize(
+                    local_part,
+                    &ascii_domain,
+                    &EMAIL_NORMALIZATION.read().unwrap().providers,
+                ),
+            }),
+            // The input is personal data, it must not end up in the logs
+            _ => Err("The subscriber email is not valid.".into()),
+        }
+    }
+
+    /// The same for every spelling of the address that reaches the same mailbox:
+    /// lower case and punycode domain. The provider rules, when configured,
+    /// are applied too (`U.rsula+news@GoogleMail.com` is `ursula@gmail.com`).
+    pub fn normalized(&self) -> &str {
+        &self.normalized
+    }
+}
+
+fn normalize(local_part: &str, ascii_domain: &str, providers: &[ProviderRules]) -> String {
+    let mut local_part = local_part.to_lowercase();
+    let mut domain = ascii_domain;
+
+    if let Some(rules) = providers.iter().find(|rules| {
+        rules
+            .domains
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(ascii_domain))
+    }) {
+        if rules.ignores_tags {
+            local_part.truncate(local_part.find('+').unwrap_or(local_part.len()));
+        }
+        if rules.ignores_dots {
+            local_part.retain(|c| c != '.');
         }
+        domain = &rules.canonical_domain;
     }
+
+    format!("{}@{}", local_part, domain)
 }
 
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.address
     }
 }
 
 impl std::fmt::Display for SubscriberEmail {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        self.address.fmt(f)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SubscriberEmail;
-    use claims::assert_err;
+    use super::{normalize, ProviderRules, SubscriberEmail};
+    use claims::{assert_err, assert_ok};
     use fake::{faker::internet::en::SafeEmail, Fake};
 
     // Both `Debug` and `Clone` are required by `quickcheck`
@@ -72,4 +158,59 @@ mod tests {
 
         SubscriberEmail::parse(&valid_email.0).is_ok()
     }
+
+    #[test]
+    fn the_address_is_trimmed_and_its_domain_lower_cased() {
+        let email = assert_ok!(SubscriberEmail::parse(" Ursula@Gmail.COM "));
+
+        assert_eq!(email.as_ref(), "Ursula@gmail.com");
+        assert_eq!(email.normalized(), "ursula@gmail.com");
+    }
+
+    fn gmail() -> ProviderRules {
+        ProviderRules {
+            domains: vec!["gmail.com".into(), "googlemail.com".into()],
+            canonical_domain: "gmail.com".into(),
+            ignores_dots: true,
+            ignores_tags: true,
+        }
+    }
+
+    #[test]
+    fn dots_and_tags_are_kept_without_provider_rules() {
+        let email = assert_ok!(SubscriberEmail::parse("U.r.sula+news@Gmail.com"));
+
+        assert_eq!(email.normalized(), "u.r.sula+news@gmail.com");
+    }
+
+    #[test]
+    fn provider_rules_ignore_dots_and_tags() {
+        for (local_part, domain) in [
+            ("ursula", "gmail.com"),
+            ("U.r.sula", "gmail.com"),
+            ("ursula+newsletter", "gmail.com"),
+            ("ursula", "googlemail.com"),
+        ] {
+            assert_eq!(
+                normalize(local_part, domain, &[gmail()]),
+                "ursula@gmail.com"
+            );
+        }
+    }
+
+    #[test]
+    fn other_providers_keep_dots_and_tags() {
+        assert_eq!(
+            normalize("Ursula.Le+Guin", "example.com", &[gmail()]),
+            "ursula.le+guin@example.com"
+        );
+    }
+
+    #[test]
+    fn international_domains_are_normalized_to_punycode() {
+        let email = assert_ok!(SubscriberEmail::parse("ursula@BÜCHER.de"));
+
+        assert_eq!(email.as_ref(), "ursula@bücher.de");
+        assert_eq!(email.normalized(), "ursula@xn--bcher-kva.de");
+    }
 }
@@ -17,7 +17,8 @@ impl SubscriberName {
             .any(|character| forbidden_characters.contains(&character));
 
         if is_empty_or_whitespace || is_too_long || contains_forbidden_characters {
-            Err(format!("{} is not a valid subscriber name.", &string))
+            // The input is personal data, it must not end up in the logs
+            Err("The subscriber name is not valid.".into())
         } else {
             Ok(Self(string))
         }
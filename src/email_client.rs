@@ -1,12 +1,140 @@
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::configuration::EmailClientSettings;
 use crate::domain::SubscriberEmail;
-use reqwest::Client;
+use crate::email_message::{Attachment, EmailMessage, TrackLinks};
+use crate::routes::error_chain_fmt;
+use crate::telemetry::trace_context_headers;
+use chrono::{DateTime, Utc};
+use reqwest::{Client, StatusCode};
 use secrecy::{ExposeSecret, Secret};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 pub struct EmailClient {
     http_client: Client,
     base_url: String,
     sender_email: SubscriberEmail,
     authorization_token: Secret<String>,
+    /// Applied to every request, can be changed at runtime with `reconfigure`
+    timeout_milliseconds: AtomicU64,
+    circuit_breaker: CircuitBreaker,
+}
+
+/// Why the email provider did not accept an email.
+///
+/// Callers use the variant to decide what to do next: transient errors
+/// (see `is_transient`) are worth retrying, an `InvalidRecipient` should be
+/// suppressed, `Unauthorized` needs a human to fix the configuration.
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    #[error("The circuit breaker for the email provider is open.")]
+    CircuitOpen,
+    #[error("{0}")]
+    InvalidMessage(String),
+    #[error("Failed to reach the email provider.")]
+    Transport(#[source] reqwest::Error),
+    #[error("The email provider did not answer in time.")]
+    Timeout(#[source] reqwest::Error),
+    #[error("The email provider is rate limiting us.")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("The email provider refused to deliver to the recipient: {message}")]
+    InvalidRecipient { message: String },
+    #[error("The email provider rejected our API token.")]
+    Unauthorized,
+    #[error("The email provider rejected the email with error code {code}: {message}")]
+    Provider { code: i64, message: String },
+}
+
+impl Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl From<reqwest::Error> for EmailError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            EmailError::Timeout(error)
+        } else {
+            EmailError::Transport(error)
+        }
+    }
+}
+
+impl EmailError {
+    /// Whether sending the same email again later has a chance to succeed:
+    /// the provider could not be reached, failed on its side (5xx), timed out
+    /// reading our request (408) or asked us to slow down (429).
+    pub fn is_transient(&self) -> bool {
+        match self {
+            EmailError::Transport(error) => match error.status() {
+                Some(status) => status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT,
+                // An answer we could not make sense of will not get better
+                None => !error.is_decode(),
+            },
+            EmailError::CircuitOpen | EmailError::Timeout(_) | EmailError::RateLimited { .. } => {
+                true
+            }
+            EmailError::InvalidMessage(_)
+            | EmailError::InvalidRecipient { .. }
+            | EmailError::Unauthorized
+            | EmailError::Provider { .. } => false,
+        }
+    }
+
+    /// Whether the error says something about the health of the email provider.
+    /// Client errors (e.g. an invalid recipient) mean that the provider is up
+    /// and answering, so they must not open the circuit.
+    fn is_provider_failure(&self) -> bool {
+        match self {
+            EmailError::Timeout(_) => true,
+            EmailError::Transport(error) => match error.status() {
+                Some(status) => status.is_server_error(),
+                // The provider answered, we just could not make sense of it
+                None => !error.is_decode(),
+            },
+            EmailError::CircuitOpen
+            | EmailError::InvalidMessage(_)
+            | EmailError::RateLimited { .. }
+            | EmailError::InvalidRecipient { .. }
+            | EmailError::Unauthorized
+            | EmailError::Provider { .. } => false,
+        }
+    }
+
+    /// Map Postmark's API error codes to the errors callers care about.
+    /// See https://postmarkapp.com/developer/api/overview#error-codes
+    fn from_error_code(code: i64, message: String) -> Self {
+        match code {
+            10 => EmailError::Unauthorized,
+            // Hard bounces, spam complaints and manual suppressions
+            406 => EmailError::InvalidRecipient { message },
+            code => EmailError::Provider { code, message },
+        }
+    }
+}
+
+/// Proof that the email provider accepted an email
+#[derive(Debug, Clone)]
+pub struct SendReceipt {
+    /// Postmark's id for the email, used to correlate bounces, opens
+    /// and support requests with what we sent
+    pub message_id: String,
+    pub submitted_at: DateTime<Utc>,
+    pub error_code: i64,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+    submitted_at: Option<DateTime<Utc>>,
+    error_code: i64,
+    #[serde(default)]
+    message: String,
 }
 
 #[derive(serde::Serialize)]
@@ -17,6 +145,53 @@ struct SendEmailRequest<'a> {
     subject: &'a str,
     text_body: &'a str,
     html_body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<EmailHeader<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_stream: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track_opens: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track_links: Option<TrackLinks>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<PostmarkAttachment<'a>>,
+}
+
+/// Postmark expects the content of attachments to be base64 encoded
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkAttachment<'a> {
+    name: &'a str,
+    content: String,
+    content_type: &'a str,
+    #[serde(rename = "ContentID", skip_serializing_if = "Option::is_none")]
+    content_id: Option<String>,
+}
+
+impl<'a> From<&'a Attachment> for PostmarkAttachment<'a> {
+    fn from(attachment: &'a Attachment) -> Self {
+        Self {
+            name: attachment.name(),
+            content: attachment.base64_content(),
+            content_type: attachment.content_type(),
+            content_id: attachment
+                .content_id()
+                .map(|content_id| format!("cid:{}", content_id)),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailHeader<'a> {
+    name: &'a str,
+    value: &'a str,
 }
 
 impl EmailClient {
@@ -24,63 +199,228 @@ impl EmailClient {
         base_url: String,
         sender_email: SubscriberEmail,
         authorization_token: Secret<String>,
-        timeout: std::time::Duration,
+        timeout: Duration,
+        circuit_breaker: CircuitBreaker,
     ) -> Self {
-        let http_client = Client::builder().timeout(timeout).build().unwrap();
-
         Self {
-            http_client,
+            http_client: Client::new(),
             base_url,
             sender_email,
             authorization_token,
+            timeout_milliseconds: AtomicU64::new(timeout.as_millis() as u64),
+            circuit_breaker,
+        }
+    }
+
+    /// Apply new timeout and circuit breaker settings to the requests sent from now on.
+    pub fn reconfigure(&self, settings: &EmailClientSettings) {
+        self.timeout_milliseconds
+            .store(settings.timeout_milliseconds, Ordering::Relaxed);
+        self.circuit_breaker.reconfigure(
+            settings.circuit_breaker.failure_threshold,
+            settings.circuit_breaker.cooldown(),
+        );
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_milliseconds.load(Ordering::Relaxed))
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker.state()
+    }
+
+    /// Check that the email provider is reachable and accepts our API token,
+    /// without sending anything. It does not go through the circuit breaker.
+    pub async fn check_connection(&self) -> Result<(), EmailError> {
+        let url = format!("{}{}", self.base_url, server_route());
+
+        let response = self
+            .http_client
+            .get(&url)
+            .timeout(self.timeout())
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED => Err(EmailError::Unauthorized),
+            _ => {
+                response.error_for_status()?;
+                Ok(())
+            }
         }
     }
 
+    /// Shorthand to send an email that only needs a subject and a body,
+    /// use `send` for everything else.
     pub async fn send_email(
         &self,
         recipient: &SubscriberEmail,
         subject: &str,
         html_body: &str,
         text_body: &str,
-    ) -> Result<(), reqwest::Error> {
+    ) -> Result<SendReceipt, EmailError> {
+        let message = EmailMessage::builder(recipient.clone(), subject)
+            .html_body(html_body)
+            .text_body(text_body)
+            .build();
+
+        self.send(&message).await
+    }
+
+    pub async fn send(&self, message: &EmailMessage) -> Result<SendReceipt, EmailError> {
+        message.validate().map_err(EmailError::InvalidMessage)?;
+
+        // Fail fast instead of waiting for a timeout from a provider we know is down
+        if !self.circuit_breaker.try_acquire() {
+            return Err(EmailError::CircuitOpen);
+        }
+
+        let result = self.post_email(message).await;
+
+        match &result {
+            Err(error) if error.is_provider_failure() => self.circuit_breaker.record_failure(),
+            _ => self.circuit_breaker.record_success(),
+        }
+
+        result
+    }
+
+    async fn post_email(&self, message: &EmailMessage) -> Result<SendReceipt, EmailError> {
         let url = format!("{}{}", self.base_url, email_route());
 
         let request_body = SendEmailRequest {
             from: self.sender_email.as_ref(),
-            to: recipient.as_ref(),
-            subject,
-            text_body,
-            html_body,
+            to: message.to().as_ref(),
+            subject: message.subject(),
+            text_body: message.text_body(),
+            html_body: message.html_body(),
+            reply_to: message.reply_to().map(|reply_to| reply_to.as_ref()),
+            headers: message
+                .headers()
+                .iter()
+                .map(|(name, value)| EmailHeader { name, value })
+                .collect(),
+            tag: message.tag(),
+            metadata: message.metadata(),
+            message_stream: message.message_stream(),
+            track_opens: message.track_opens(),
+            track_links: message.track_links(),
+            attachments: message
+                .attachments()
+                .iter()
+                .map(PostmarkAttachment::from)
+                .collect(),
         };
 
-        let _ = self
+        let mut response = self
             .http_client
             .post(&url)
+            .timeout(self.timeout())
+            .headers(trace_context_headers())
             .header(
                 "X-Postmark-Server-Token",
                 self.authorization_token.expose_secret(),
             )
             .json(&request_body)
             .send()
-            .await?
-            .error_for_status()?;
+            .await?;
 
-        Ok(())
+        match response.status() {
+            StatusCode::UNAUTHORIZED => return Err(EmailError::Unauthorized),
+            StatusCode::TOO_MANY_REQUESTS => {
+                return Err(EmailError::RateLimited {
+                    retry_after: retry_after(&response),
+                })
+            }
+            // Postmark reports why it rejected an email in the body of a 422,
+            // but a non-zero `ErrorCode` can also come with a 200.
+            StatusCode::UNPROCESSABLE_ENTITY => {}
+            // Sending the same request again will be rejected the same way
+            status if status.is_client_error() && status != StatusCode::REQUEST_TIMEOUT => {
+                return Err(match response.json::<SendEmailResponse>().await {
+                    Ok(body) if body.error_code != 0 => {
+                        EmailError::from_error_code(body.error_code, body.message)
+                    }
+                    _ => EmailError::Provider {
+                        code: 0,
+                        message: format!("The email provider answered with {}.", status),
+                    },
+                });
+            }
+            _ => response = response.error_for_status()?,
+        }
+
+        let body: SendEmailResponse = response.json().await?;
+
+        match body {
+            SendEmailResponse {
+                error_code: 0,
+                message_id: Some(message_id),
+                submitted_at: Some(submitted_at),
+                ..
+            } => Ok(SendReceipt {
+                message_id,
+                submitted_at,
+                error_code: 0,
+            }),
+            SendEmailResponse {
+                error_code: 0,
+                message,
+                ..
+            } => Err(EmailError::Provider {
+                code: 0,
+                message: format!(
+                    "The response is missing the message id or submission date. {}",
+                    message
+                ),
+            }),
+            SendEmailResponse {
+                error_code,
+                message,
+                ..
+            } => Err(EmailError::from_error_code(error_code, message)),
+        }
     }
 }
 
+/// Read the `Retry-After` header, when expressed in seconds
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
 pub fn email_route() -> String {
     String::from("/email")
 }
 
+/// Postmark's details about our server, a cheap authenticated request
+pub fn server_route() -> String {
+    String::from("/server")
+}
+
 pub fn subscriptions_confirm_route() -> String {
     String::from("/subscriptions/confirm")
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+    use crate::configuration::{CircuitBreakerSettings, EmailClientSettings};
     use crate::domain::SubscriberEmail;
-    use crate::email_client::{email_route, EmailClient};
+    use crate::email_client::{email_route, server_route, EmailClient, EmailError};
+    use crate::email_message::{Attachment, EmailMessage, TrackLinks};
     use claims::{assert_err, assert_ok};
     use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::{faker::internet::en::SafeEmail, Fake, Faker};
@@ -106,16 +446,87 @@ mod tests {
         }
     }
 
+    struct OptionalFieldsMatcher;
+
+    impl wiremock::Match for OptionalFieldsMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+
+            if let Ok(body) = result {
+                body.get("ReplyTo").is_some()
+                    && body["Headers"][0]["Name"] == "List-Unsubscribe"
+                    && body["Headers"][0]["Value"] == "<https://example.com/unsubscribe>"
+                    && body["Tag"] == "welcome"
+                    && body["Metadata"]["campaign"] == "spring"
+                    && body["MessageStream"] == "broadcast"
+                    && body["TrackOpens"] == true
+                    && body["TrackLinks"] == "HtmlOnly"
+            } else {
+                false
+            }
+        }
+    }
+
+    struct AttachmentsMatcher;
+
+    impl wiremock::Match for AttachmentsMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+
+            if let Ok(body) = result {
+                let logo = &body["Attachments"][0];
+                let report = &body["Attachments"][1];
+
+                logo["Name"] == "logo.png"
+                    && logo["ContentType"] == "image/png"
+                    // [1, 2, 3] in base64
+                    && logo["Content"] == "AQID"
+                    && logo["ContentID"] == "cid:logo"
+                    && report["Name"] == "report.pdf"
+                    && report.get("ContentID").is_none()
+            } else {
+                false
+            }
+        }
+    }
+
+    /// What Postmark answers when it accepts an email
+    fn success_response() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "To": "receiver@example.com",
+            "SubmittedAt": "2014-02-17T07:25:01.4178645-05:00",
+            "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+            "ErrorCode": 0,
+            "Message": "OK"
+        }))
+    }
+
+    fn error_response(status: u16, error_code: i64) -> ResponseTemplate {
+        ResponseTemplate::new(status).set_body_json(serde_json::json!({
+            "ErrorCode": error_code,
+            "Message": "Error parsing 'To': Illegal email address."
+        }))
+    }
+
     fn email() -> SubscriberEmail {
-        SubscriberEmail::parse(&SafeEmail().fake()).unwrap()
+        SubscriberEmail::parse(&SafeEmail().fake::<String>()).unwrap()
     }
 
     fn email_client(uri: String) -> EmailClient {
+        email_client_with_circuit_breaker(uri, 5)
+    }
+
+    fn email_client_with_circuit_breaker(uri: String, failure_threshold: u32) -> EmailClient {
         EmailClient::new(
             uri,
             email(),
             Secret::new(Faker.fake()),
             std::time::Duration::from_millis(200),
+            CircuitBreaker::new(
+                "email_client",
+                failure_threshold,
+                std::time::Duration::from_secs(60),
+            ),
         )
     }
 
@@ -138,7 +549,7 @@ mod tests {
             .and(path(email_route()))
             .and(method("POST"))
             .and(SendEmailBodyMatcher)
-            .respond_with(ResponseTemplate::new(200))
+            .respond_with(success_response())
             .expect(1)
             .mount(&mock_server)
             .await;
@@ -170,7 +581,7 @@ mod tests {
         // We add the bare minimum needed to trigger the path we want
         // to test in `send_email`.
         Mock::given(any())
-            .respond_with(ResponseTemplate::new(200))
+            .respond_with(success_response())
             .expect(1)
             .mount(&mock_server)
             .await;
@@ -213,6 +624,54 @@ mod tests {
         assert_err!(result);
     }
 
+    #[tokio::test]
+    async fn send_email_fails_without_a_retry_if_the_server_returns_400() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri(), 1);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client
+            .send_email(&email(), &subject(), &body(), &body())
+            .await;
+
+        // Assert
+        let error = result.unwrap_err();
+        assert!(matches!(error, EmailError::Provider { .. }));
+        assert!(!error.is_transient());
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_request_timeouts_are_transient() {
+        for status in [408, 500, 503] {
+            // Arrange
+            let mock_server = MockServer::start().await;
+            let email_client = email_client(mock_server.uri());
+
+            Mock::given(any())
+                .respond_with(ResponseTemplate::new(status))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            // Act
+            let result = email_client
+                .send_email(&email(), &subject(), &body(), &body())
+                .await;
+
+            // Assert
+            let error = result.unwrap_err();
+            assert!(error.is_transient(), "{} should be retried", status);
+        }
+    }
+
     #[tokio::test]
     async fn send_email_times_out_if_the_server_takes_too_long() {
         // Arrange
@@ -223,7 +682,7 @@ mod tests {
         let subject = subject();
         let body = body();
 
-        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(200));
+        let response = success_response().set_delay(std::time::Duration::from_secs(200));
 
         Mock::given(any())
             .respond_with(response)
@@ -236,7 +695,378 @@ mod tests {
             .send_email(&recipient, &subject, &body, &body)
             .await;
 
+        // Assert
+        assert!(matches!(result, Err(EmailError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_fast_once_the_circuit_breaker_is_open() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri(), 2);
+
+        let recipient = email();
+        let subject = subject();
+        let body = body();
+
+        // Only the requests sent before the circuit opens reach the server
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        for _ in 0..2 {
+            let result = email_client
+                .send_email(&recipient, &subject, &body, &body)
+                .await;
+            assert_err!(result);
+        }
+
+        let result = email_client
+            .send_email(&recipient, &subject, &body, &body)
+            .await;
+
+        // Assert
+        assert!(matches!(result, Err(EmailError::CircuitOpen)));
+        assert_eq!(email_client.circuit_state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn client_errors_do_not_open_the_circuit_breaker() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri(), 1);
+
+        let recipient = email();
+        let subject = subject();
+        let body = body();
+
+        Mock::given(any())
+            .respond_with(error_response(422, 300))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client
+            .send_email(&recipient, &subject, &body, &body)
+            .await;
+
         // Assert
         assert_err!(result);
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn send_includes_the_optional_fields_of_the_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path(email_route()))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .and(OptionalFieldsMatcher)
+            .respond_with(success_response())
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let message = EmailMessage::builder(email(), subject())
+            .html_body(body())
+            .text_body(body())
+            .reply_to(email())
+            .header("List-Unsubscribe", "<https://example.com/unsubscribe>")
+            .tag("welcome")
+            .metadata("campaign", "spring")
+            .message_stream("broadcast")
+            .track_opens(true)
+            .track_links(TrackLinks::HtmlOnly)
+            .build();
+
+        // Act
+        let result = email_client.send(&message).await;
+
+        // Assert
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn send_rejects_invalid_messages_without_calling_the_server() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(success_response())
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let message = EmailMessage::builder(email(), subject())
+            .text_body(body())
+            .header("X-Campaign", "spring\r\nBcc: victim@example.com")
+            .build();
+
+        // Act
+        let result = email_client.send(&message).await;
+
+        // Assert
+        assert!(matches!(result, Err(EmailError::InvalidMessage(_))));
+    }
+
+    #[tokio::test]
+    async fn send_encodes_attachments_in_base64() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path(email_route()))
+            .and(method("POST"))
+            .and(AttachmentsMatcher)
+            .respond_with(success_response())
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let message = EmailMessage::builder(email(), subject())
+            .html_body("<img src=\"cid:logo\">")
+            .attachment(Attachment::new("logo.png", "image/png", vec![1, 2, 3]).inline("logo"))
+            .attachment(Attachment::new(
+                "report.pdf",
+                "application/pdf",
+                vec![4, 5, 6],
+            ))
+            .build();
+
+        // Act
+        let result = email_client.send(&message).await;
+
+        // Assert
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_a_receipt_with_the_message_id() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(success_response())
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let receipt = email_client
+            .send_email(&email(), &subject(), &body(), &body())
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(receipt.message_id, "0a129aee-e1cd-480d-b08d-4f48548ff48d");
+        assert_eq!(receipt.error_code, 0);
+        assert_eq!(
+            receipt.submitted_at.to_rfc3339(),
+            "2014-02-17T12:25:01.417864500+00:00"
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_with_a_provider_error_for_a_non_zero_error_code() {
+        for status in [200, 422] {
+            // Arrange
+            let mock_server = MockServer::start().await;
+            let email_client = email_client(mock_server.uri());
+
+            Mock::given(any())
+                .respond_with(error_response(status, 300))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            // Act
+            let result = email_client
+                .send_email(&email(), &subject(), &body(), &body())
+                .await;
+
+            // Assert
+            assert!(
+                matches!(result, Err(EmailError::Provider { code: 300, .. })),
+                "Expected a provider error for a {} response",
+                status
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_response_cannot_be_parsed() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client
+            .send_email(&email(), &subject(), &body(), &body())
+            .await;
+
+        // Assert
+        assert_err!(result);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_with_unauthorized_if_the_token_is_rejected() {
+        for response in [ResponseTemplate::new(401), error_response(422, 10)] {
+            // Arrange
+            let mock_server = MockServer::start().await;
+            let email_client = email_client(mock_server.uri());
+
+            Mock::given(any())
+                .respond_with(response)
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            // Act
+            let result = email_client
+                .send_email(&email(), &subject(), &body(), &body())
+                .await;
+
+            // Assert
+            assert!(matches!(result, Err(EmailError::Unauthorized)));
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_reports_how_long_to_wait_when_rate_limited() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri(), 1);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client
+            .send_email(&email(), &subject(), &body(), &body())
+            .await;
+
+        // Assert
+        match result {
+            Err(error @ EmailError::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Some(std::time::Duration::from_secs(30)));
+                assert!(error.is_transient());
+            }
+            other => panic!("Expected a rate limiting error, got {:?}", other),
+        }
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_with_invalid_recipient_for_an_inactive_recipient() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(error_response(422, 406))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client
+            .send_email(&email(), &subject(), &body(), &body())
+            .await;
+
+        // Assert
+        let error = result.unwrap_err();
+        assert!(matches!(error, EmailError::InvalidRecipient { .. }));
+        assert!(!error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn reconfiguring_the_timeout_applies_to_the_next_requests() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(success_response().set_delay(std::time::Duration::from_millis(500)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        email_client.reconfigure(&EmailClientSettings {
+            base_url: mock_server.uri(),
+            sender_email: email().as_ref().to_string(),
+            authorization_token: Secret::new(Faker.fake()),
+            timeout_milliseconds: 5000,
+            circuit_breaker: CircuitBreakerSettings {
+                failure_threshold: 5,
+                cooldown_milliseconds: 60000,
+            },
+        });
+        let result = email_client
+            .send_email(&email(), &subject(), &body(), &body())
+            .await;
+
+        // Assert
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn check_connection_sends_an_authenticated_request_to_the_server_route() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path(server_route()))
+            .and(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client.check_connection().await;
+
+        // Assert
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn check_connection_fails_if_the_token_is_rejected() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let error = email_client.check_connection().await.unwrap_err();
+
+        // Assert
+        assert!(matches!(error, EmailError::Unauthorized));
+        // Health checks do not count as failures of the provider
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
     }
 }
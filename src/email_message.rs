@@ -0,0 +1,651 @@
+use crate::domain::SubscriberEmail;
+use base64::Engine;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::collections::BTreeMap;
+
+/// Postmark rejects messages bigger than 10 MB, attachments included.
+/// We apply the same limit to every backend.
+pub const MAX_MESSAGE_SIZE_BYTES: usize = 10 * 1024 * 1024;
+
+/// Line length used for base64 encoded MIME parts (RFC 2045)
+const MIME_LINE_LENGTH: usize = 76;
+
+/// Which links Postmark should rewrite to track clicks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum TrackLinks {
+    None,
+    HtmlAndText,
+    HtmlOnly,
+    TextOnly,
+}
+
+/// A file sent along with an email.
+///
+/// Attachments with a content id are displayed inline, the HTML body
+/// can refer to them as `<img src="cid:{content_id}">`.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    name: String,
+    content_type: String,
+    content: Vec<u8>,
+    content_id: Option<String>,
+}
+
+impl Attachment {
+    pub fn new(name: impl Into<String>, content_type: impl Into<String>, content: Vec<u8>) -> Self {
+        Self {
+            name: name.into(),
+            content_type: content_type.into(),
+            content,
+            content_id: None,
+        }
+    }
+
+    /// Turn the attachment into an inline one, e.g. a logo embedded in the HTML body
+    pub fn inline(mut self, content_id: impl Into<String>) -> Self {
+        self.content_id = Some(content_id.into());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    pub fn content_id(&self) -> Option<&str> {
+        self.content_id.as_deref()
+    }
+
+    pub fn base64_content(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&self.content)
+    }
+
+    /// Size of the attachment once base64 encoded, which is how it travels
+    fn encoded_size(&self) -> usize {
+        self.content.len().div_ceil(3) * 4
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let is_valid_name =
+            !self.name.trim().is_empty() && !self.name.chars().any(|c| c.is_control() || c == '"');
+        if !is_valid_name {
+            return Err(format!("{:?} is not a valid attachment name.", self.name));
+        }
+
+        let is_token = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c))
+        };
+        let is_valid_content_type = match self.content_type.split_once('/') {
+            Some((type_, subtype)) => is_token(type_) && is_token(subtype),
+            None => false,
+        };
+        if !is_valid_content_type {
+            return Err(format!(
+                "{:?} is not a valid attachment content type.",
+                self.content_type
+            ));
+        }
+
+        if let Some(content_id) = &self.content_id {
+            let is_valid_content_id = !content_id.is_empty()
+                && content_id
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && c != '<' && c != '>');
+            if !is_valid_content_id {
+                return Err(format!(
+                    "{:?} is not a valid attachment content id.",
+                    content_id
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An email ready to be handed over to `EmailClient::send`.
+///
+/// Use `EmailMessage::builder` to create one.
+#[derive(Debug, Clone)]
+pub struct EmailMessage {
+    to: SubscriberEmail,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    reply_to: Option<SubscriberEmail>,
+    headers: Vec<(String, String)>,
+    tag: Option<String>,
+    metadata: BTreeMap<String, String>,
+    message_stream: Option<String>,
+    track_opens: Option<bool>,
+    track_links: Option<TrackLinks>,
+    attachments: Vec<Attachment>,
+}
+
+pub struct EmailMessageBuilder {
+    message: EmailMessage,
+}
+
+impl EmailMessage {
+    pub fn builder(to: SubscriberEmail, subject: impl Into<String>) -> EmailMessageBuilder {
+        EmailMessageBuilder {
+            message: EmailMessage {
+                to,
+                subject: subject.into(),
+                html_body: String::new(),
+                text_body: String::new(),
+                reply_to: None,
+                headers: Vec::new(),
+                tag: None,
+                metadata: BTreeMap::new(),
+                message_stream: None,
+                track_opens: None,
+                track_links: None,
+                attachments: Vec::new(),
+            },
+        }
+    }
+
+    pub fn to(&self) -> &SubscriberEmail {
+        &self.to
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn html_body(&self) -> &str {
+        &self.html_body
+    }
+
+    pub fn text_body(&self) -> &str {
+        &self.text_body
+    }
+
+    pub fn reply_to(&self) -> Option<&SubscriberEmail> {
+        self.reply_to.as_ref()
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn message_stream(&self) -> Option<&str> {
+        self.message_stream.as_deref()
+    }
+
+    pub fn track_opens(&self) -> Option<bool> {
+        self.track_opens
+    }
+
+    pub fn track_links(&self) -> Option<TrackLinks> {
+        self.track_links
+    }
+
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+
+    /// Approximate size of the message on the wire: bodies plus
+    /// base64 encoded attachments
+    pub fn size(&self) -> usize {
+        self.subject.len()
+            + self.html_body.len()
+            + self.text_body.len()
+            + self
+                .attachments
+                .iter()
+                .map(Attachment::encoded_size)
+                .sum::<usize>()
+    }
+
+    /// Check the message before it leaves the application.
+    ///
+    /// Custom headers end up verbatim in the email, so line breaks in them
+    /// would allow to inject arbitrary headers.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.html_body.is_empty() && self.text_body.is_empty() {
+            return Err("An email needs either an HTML or a text body.".into());
+        }
+
+        if self.subject.contains(['\r', '\n']) {
+            return Err("The subject of the email contains a line break.".into());
+        }
+
+        for (name, value) in &self.headers {
+            let is_valid_name =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_graphic() && c != ':');
+
+            if !is_valid_name {
+                return Err(format!("{:?} is not a valid email header name.", name));
+            }
+
+            if value.contains(['\r', '\n']) {
+                return Err(format!(
+                    "The value of the {} email header contains a line break.",
+                    name
+                ));
+            }
+        }
+
+        for attachment in &self.attachments {
+            attachment.validate()?;
+        }
+
+        let size = self.size();
+        if size > MAX_MESSAGE_SIZE_BYTES {
+            return Err(format!(
+                "The email is {} bytes long, the limit is {} bytes.",
+                size, MAX_MESSAGE_SIZE_BYTES
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Render the message as a MIME document (RFC 2045), the format
+    /// expected by SMTP servers.
+    pub fn to_mime(&self, from: &SubscriberEmail) -> String {
+        let mut mime = String::new();
+
+        push_header(&mut mime, "From", from.as_ref());
+        push_header(&mut mime, "To", self.to.as_ref());
+        if let Some(reply_to) = &self.reply_to {
+            push_header(&mut mime, "Reply-To", reply_to.as_ref());
+        }
+        push_header(&mut mime, "Subject", &encode_header_value(&self.subject));
+        for (name, value) in &self.headers {
+            push_header(&mut mime, name, &encode_header_value(value));
+        }
+        push_header(&mut mime, "MIME-Version", "1.0");
+
+        self.mime_body().render(&mut mime);
+
+        mime
+    }
+
+    /// The text and HTML bodies are alternatives of each other, inline
+    /// attachments are related to the bodies that reference them and
+    /// regular attachments are mixed in with the rest.
+    fn mime_body(&self) -> MimePart<'_> {
+        let mut alternatives = Vec::new();
+        if !self.text_body.is_empty() {
+            alternatives.push(MimePart::text("text/plain", &self.text_body));
+        }
+        if !self.html_body.is_empty() {
+            alternatives.push(MimePart::text("text/html", &self.html_body));
+        }
+
+        let mut body = if alternatives.len() == 1 {
+            alternatives.remove(0)
+        } else {
+            MimePart::Multipart {
+                subtype: "alternative",
+                parts: alternatives,
+            }
+        };
+
+        let (inline, regular): (Vec<_>, Vec<_>) = self
+            .attachments
+            .iter()
+            .partition(|attachment| attachment.content_id.is_some());
+
+        if !inline.is_empty() {
+            let mut parts = vec![body];
+            parts.extend(inline.into_iter().map(MimePart::attachment));
+            body = MimePart::Multipart {
+                subtype: "related",
+                parts,
+            };
+        }
+
+        if !regular.is_empty() {
+            let mut parts = vec![body];
+            parts.extend(regular.into_iter().map(MimePart::attachment));
+            body = MimePart::Multipart {
+                subtype: "mixed",
+                parts,
+            };
+        }
+
+        body
+    }
+}
+
+enum MimePart<'a> {
+    Single {
+        headers: Vec<(&'static str, String)>,
+        content: &'a [u8],
+    },
+    Multipart {
+        subtype: &'static str,
+        parts: Vec<MimePart<'a>>,
+    },
+}
+
+impl<'a> MimePart<'a> {
+    fn text(content_type: &str, body: &'a str) -> Self {
+        MimePart::Single {
+            headers: vec![("Content-Type", format!("{}; charset=utf-8", content_type))],
+            content: body.as_bytes(),
+        }
+    }
+
+    fn attachment(attachment: &'a Attachment) -> Self {
+        let mut headers = vec![(
+            "Content-Type",
+            format!("{}; name=\"{}\"", attachment.content_type, attachment.name),
+        )];
+
+        match &attachment.content_id {
+            Some(content_id) => {
+                headers.push(("Content-ID", format!("<{}>", content_id)));
+                headers.push((
+                    "Content-Disposition",
+                    format!("inline; filename=\"{}\"", attachment.name),
+                ));
+            }
+            None => headers.push((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", attachment.name),
+            )),
+        }
+
+        MimePart::Single {
+            headers,
+            content: &attachment.content,
+        }
+    }
+
+    fn render(&self, mime: &mut String) {
+        match self {
+            MimePart::Single { headers, content } => {
+                for (name, value) in headers {
+                    push_header(mime, name, value);
+                }
+                push_header(mime, "Content-Transfer-Encoding", "base64");
+                mime.push_str("\r\n");
+
+                let encoded = base64::engine::general_purpose::STANDARD.encode(content);
+                // base64 output is ASCII, we can safely split it at any byte
+                for line in encoded.as_bytes().chunks(MIME_LINE_LENGTH) {
+                    mime.push_str(std::str::from_utf8(line).unwrap());
+                    mime.push_str("\r\n");
+                }
+            }
+            MimePart::Multipart { subtype, parts } => {
+                // `=_` can never show up in base64 encoded content
+                let boundary = format!("=_{}", generate_boundary());
+
+                push_header(
+                    mime,
+                    "Content-Type",
+                    &format!("multipart/{}; boundary=\"{}\"", subtype, boundary),
+                );
+                mime.push_str("\r\n");
+
+                for part in parts {
+                    mime.push_str(&format!("--{}\r\n", boundary));
+                    part.render(mime);
+                }
+                mime.push_str(&format!("--{}--\r\n", boundary));
+            }
+        }
+    }
+}
+
+fn push_header(mime: &mut String, name: &str, value: &str) {
+    mime.push_str(&format!("{}: {}\r\n", name, value));
+}
+
+/// Header values must be ASCII, anything else is sent as
+/// RFC 2047 encoded words.
+fn encode_header_value(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_owned();
+    }
+
+    // Encoded words can be at most 75 characters long, we split the value
+    // in chunks of whole characters that fit in that limit once encoded.
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for character in value.chars() {
+        if chunk.len() + character.len_utf8() > 45 {
+            words.push(encode_word(&chunk));
+            chunk.clear();
+        }
+        chunk.push(character);
+    }
+    words.push(encode_word(&chunk));
+
+    words.join("\r\n ")
+}
+
+fn encode_word(chunk: &str) -> String {
+    format!(
+        "=?UTF-8?B?{}?=",
+        base64::engine::general_purpose::STANDARD.encode(chunk)
+    )
+}
+
+fn generate_boundary() -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .map(char::from)
+        .take(24)
+        .collect()
+}
+
+impl EmailMessageBuilder {
+    pub fn html_body(mut self, html_body: impl Into<String>) -> Self {
+        self.message.html_body = html_body.into();
+        self
+    }
+
+    pub fn text_body(mut self, text_body: impl Into<String>) -> Self {
+        self.message.text_body = text_body.into();
+        self
+    }
+
+    pub fn reply_to(mut self, reply_to: SubscriberEmail) -> Self {
+        self.message.reply_to = Some(reply_to);
+        self
+    }
+
+    /// Add a custom header, e.g. `List-Unsubscribe`
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.message.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Categorise the email for statistics in Postmark
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.message.tag = Some(tag.into());
+        self
+    }
+
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.message.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// The Postmark message stream to send through, e.g. `outbound` for
+    /// transactional emails or `broadcast` for newsletters.
+    /// Postmark falls back to `outbound` when unset.
+    pub fn message_stream(mut self, message_stream: impl Into<String>) -> Self {
+        self.message.message_stream = Some(message_stream.into());
+        self
+    }
+
+    pub fn track_opens(mut self, track_opens: bool) -> Self {
+        self.message.track_opens = Some(track_opens);
+        self
+    }
+
+    pub fn track_links(mut self, track_links: TrackLinks) -> Self {
+        self.message.track_links = Some(track_links);
+        self
+    }
+
+    pub fn attachment(mut self, attachment: Attachment) -> Self {
+        self.message.attachments.push(attachment);
+        self
+    }
+
+    pub fn build(self) -> EmailMessage {
+        self.message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Attachment, EmailMessage, MAX_MESSAGE_SIZE_BYTES};
+    use crate::domain::SubscriberEmail;
+    use base64::Engine;
+    use claims::{assert_err, assert_ok};
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com").unwrap()
+    }
+
+    #[test]
+    fn a_message_with_a_body_is_valid() {
+        let message = EmailMessage::builder(recipient(), "Subject")
+            .text_body("Hi!")
+            .header("List-Unsubscribe", "<https://example.com/unsubscribe>")
+            .build();
+
+        assert_ok!(message.validate());
+    }
+
+    #[test]
+    fn a_message_without_a_body_is_rejected() {
+        let message = EmailMessage::builder(recipient(), "Subject").build();
+
+        assert_err!(message.validate());
+    }
+
+    #[test]
+    fn header_values_with_line_breaks_are_rejected() {
+        let message = EmailMessage::builder(recipient(), "Subject")
+            .text_body("Hi!")
+            .header("X-Campaign", "spring\r\nBcc: victim@example.com")
+            .build();
+
+        assert_err!(message.validate());
+    }
+
+    #[test]
+    fn invalid_header_names_are_rejected() {
+        for name in ["", "X Campaign", "X-Campaign:"] {
+            let message = EmailMessage::builder(recipient(), "Subject")
+                .text_body("Hi!")
+                .header(name, "spring")
+                .build();
+
+            assert_err!(message.validate());
+        }
+    }
+
+    #[test]
+    fn attachments_with_invalid_details_are_rejected() {
+        let attachments = [
+            Attachment::new("", "application/pdf", vec![1]),
+            Attachment::new("report.pdf", "pdf", vec![1]),
+            Attachment::new("report.pdf", "application/pdf\r\nBcc: x", vec![1]),
+            Attachment::new("logo.png", "image/png", vec![1]).inline("<logo>"),
+        ];
+
+        for attachment in attachments {
+            let message = EmailMessage::builder(recipient(), "Subject")
+                .text_body("Hi!")
+                .attachment(attachment)
+                .build();
+
+            assert_err!(message.validate());
+        }
+    }
+
+    #[test]
+    fn messages_over_the_size_limit_are_rejected() {
+        // Once base64 encoded the attachment goes over the limit
+        let content = vec![0; MAX_MESSAGE_SIZE_BYTES * 3 / 4 + 1];
+        let message = EmailMessage::builder(recipient(), "Subject")
+            .text_body("Hi!")
+            .attachment(Attachment::new(
+                "big.bin",
+                "application/octet-stream",
+                content,
+            ))
+            .build();
+
+        assert_err!(message.validate());
+    }
+
+    #[test]
+    fn mime_rendering_nests_bodies_and_attachments() {
+        let message = EmailMessage::builder(recipient(), "Subject")
+            .text_body("Hi!")
+            .html_body("<img src=\"cid:logo\">")
+            .attachment(Attachment::new("logo.png", "image/png", vec![1, 2, 3]).inline("logo"))
+            .attachment(Attachment::new(
+                "report.pdf",
+                "application/pdf",
+                vec![4, 5, 6],
+            ))
+            .build();
+
+        let mime = message.to_mime(&recipient());
+
+        assert!(mime.contains("MIME-Version: 1.0\r\n"));
+        assert!(mime.contains("Content-Type: multipart/mixed;"));
+        assert!(mime.contains("Content-Type: multipart/related;"));
+        assert!(mime.contains("Content-Type: multipart/alternative;"));
+        assert!(mime.contains("Content-ID: <logo>\r\n"));
+        assert!(mime.contains("Content-Disposition: inline; filename=\"logo.png\""));
+        assert!(mime.contains("Content-Disposition: attachment; filename=\"report.pdf\""));
+        // [4, 5, 6] in base64
+        assert!(mime.contains("\r\nBAUG\r\n"));
+    }
+
+    #[test]
+    fn a_message_without_attachments_is_not_multipart_mixed() {
+        let message = EmailMessage::builder(recipient(), "Subject")
+            .text_body("Hi!")
+            .build();
+
+        let mime = message.to_mime(&recipient());
+
+        assert!(!mime.contains("multipart"));
+        assert!(mime.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn non_ascii_subjects_are_encoded() {
+        let message = EmailMessage::builder(recipient(), "Bienvenue à bord")
+            .text_body("Hi!")
+            .build();
+
+        let mime = message.to_mime(&recipient());
+
+        let encoded = base64::engine::general_purpose::STANDARD.encode("Bienvenue à bord");
+        assert!(mime.contains(&format!("Subject: =?UTF-8?B?{}?=\r\n", encoded)));
+    }
+}
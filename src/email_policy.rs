@@ -0,0 +1,408 @@
+use crate::configuration::EmailPolicySettings;
+use crate::deliverability::{suggest_domain, DeliverabilityCheck};
+use crate::domain::SubscriberEmail;
+use crate::routes::error_chain_fmt;
+use crate::telemetry::Redacted;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// One domain per line, `#` starts a comment
+const DISPOSABLE_DOMAINS: &str = include_str!("../assets/disposable_domains.txt");
+
+/// Mailboxes that belong to a role or a system rather than to a person
+const ROLE_LOCAL_PARTS: [&str; 9] = [
+    "abuse",
+    "hostmaster",
+    "mailer-daemon",
+    "no-reply",
+    "noreply",
+    "postmaster",
+    "root",
+    "security",
+    "webmaster",
+];
+
+#[derive(thiserror::Error)]
+pub enum EmailPolicyError {
+    #[error("Disposable email addresses are not accepted.")]
+    DisposableDomain,
+    #[error("Role addresses, such as postmaster@ or abuse@, are not accepted.")]
+    RoleAddress,
+    #[error("This email address is not accepted.")]
+    Denied,
+    #[error("{}", undeliverable_message(.suggestion))]
+    Undeliverable { suggestion: Option<&'static str> },
+    #[error("Failed to fetch the email rules")]
+    Database(#[from] sqlx::Error),
+}
+
+impl EmailPolicyError {
+    /// Machine readable, for JSON clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            EmailPolicyError::DisposableDomain => "disposable_domain",
+            EmailPolicyError::RoleAddress => "role_address",
+            EmailPolicyError::Denied => "denied",
+            EmailPolicyError::Undeliverable { .. } => "undeliverable",
+            EmailPolicyError::Database(_) => "unexpected_error",
+        }
+    }
+}
+
+fn undeliverable_message(suggestion: &Option<&str>) -> String {
+    let message = "The domain of this email address does not receive emails.";
+    match suggestion {
+        Some(domain) => format!("{} Did you mean {}?", message, domain),
+        None => message.into(),
+    }
+}
+
+impl Debug for EmailPolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Accept the address, whatever the rest of the policy says
+    Allow,
+    Deny,
+}
+
+impl RuleAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleAction::Allow => "allow",
+            RuleAction::Deny => "deny",
+        }
+    }
+}
+
+impl TryFrom<String> for RuleAction {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "allow" => Ok(RuleAction::Allow),
+            "deny" => Ok(RuleAction::Deny),
+            other => Err(format!("{} is not a rule action", other)),
+        }
+    }
+}
+
+/// A rule managed by admins through `/admin/email_rules`
+#[derive(serde::Serialize, Debug)]
+pub struct EmailRule {
+    pub id: Uuid,
+    /// `user@example.com`, `example.com` or `*.example.com` (subdomains only)
+    pub pattern: String,
+    pub action: RuleAction,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Which addresses we accept subscriptions for, on top of `SubscriberEmail::parse`.
+///
+/// Rules from the database come first: an `allow` rule lets an address through,
+/// a `deny` rule rejects it. Disposable domains, role addresses and, when
+/// enabled, domains that do not receive emails are rejected otherwise.
+pub struct EmailPolicy {
+    /// Can be changed at runtime with `reconfigure`
+    settings: ArcSwap<EmailPolicySettings>,
+    disposable_domains: HashSet<&'static str>,
+    /// Only replaced when its settings change, it keeps its cache otherwise
+    deliverability: ArcSwapOption<DeliverabilityCheck>,
+}
+
+impl EmailPolicy {
+    pub fn new(settings: &EmailPolicySettings) -> Self {
+        let disposable_domains = DISPOSABLE_DOMAINS
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+
+        Self {
+            settings: ArcSwap::from_pointee(settings.clone()),
+            disposable_domains,
+            deliverability: ArcSwapOption::new(deliverability_check(settings)),
+        }
+    }
+
+    /// Apply new settings to the addresses checked from now on.
+    pub fn reconfigure(&self, settings: &EmailPolicySettings) {
+        if self.settings.load().deliverability != settings.deliverability {
+            self.deliverability.store(deliverability_check(settings));
+        }
+        self.settings.store(Arc::new(settings.clone()));
+    }
+
+    #[tracing::instrument(name = "Check the email policy", skip_all)]
+    pub async fn check(
+        &self,
+        connection_pool: &PgPool,
+        email: &SubscriberEmail,
+    ) -> Result<(), EmailPolicyError> {
+        let email = email.normalized();
+        // `SubscriberEmail` always holds an `@`
+        let (local_part, domain) = email.rsplit_once('@').unwrap_or(("", email));
+
+        match matching_rule(connection_pool, &rule_candidates(local_part, domain)).await? {
+            Some(RuleAction::Allow) => return Ok(()),
+            Some(RuleAction::Deny) => return Err(EmailPolicyError::Denied),
+            None => {}
+        }
+        let settings = self.settings.load_full();
+        if settings.block_disposable_domains && self.is_disposable(domain) {
+            return Err(EmailPolicyError::DisposableDomain);
+        }
+        if settings.reject_role_addresses && is_role_address(local_part) {
+            return Err(EmailPolicyError::RoleAddress);
+        }
+        if let Some(deliverability) = self.deliverability.load_full() {
+            match deliverability.receives_email(domain).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(EmailPolicyError::Undeliverable {
+                        suggestion: suggest_domain(domain),
+                    })
+                }
+                // A DNS outage must not turn every subscriber away
+                Err(error) => tracing::warn!(
+                    error.cause_chain = ?error,
+                    "Failed to check whether the domain receives emails, letting it through"
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_disposable(&self, domain: &str) -> bool {
+        domain_and_parents(domain).any(|d| self.disposable_domains.contains(d))
+    }
+}
+
+fn deliverability_check(settings: &EmailPolicySettings) -> Option<Arc<DeliverabilityCheck>> {
+    settings
+        .deliverability
+        .as_ref()
+        .map(|deliverability| Arc::new(DeliverabilityCheck::new(deliverability)))
+}
+
+/// `mail.example.com`, `example.com`, `com`
+fn domain_and_parents(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |d| {
+        d.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+/// `abuse+newsletter` is `abuse` all the same
+fn is_role_address(local_part: &str) -> bool {
+    let mailbox = local_part.split('+').next().unwrap_or(local_part);
+    ROLE_LOCAL_PARTS.contains(&mailbox)
+}
+
+/// The patterns that match an address
+fn rule_candidates(local_part: &str, domain: &str) -> Vec<String> {
+    let mut candidates = vec![format!("{}@{}", local_part, domain), domain.to_owned()];
+    candidates.extend(
+        domain_and_parents(domain)
+            .skip(1)
+            .map(|d| format!("*.{}", d)),
+    );
+    candidates
+}
+
+/// Lower case the pattern, after checking it is one of the supported shapes.
+/// Addresses are normalized, as they are when checked.
+pub fn parse_rule_pattern(pattern: &str) -> Result<String, String> {
+    let pattern = pattern.trim().to_lowercase();
+    let domain = match pattern.rsplit_once('@') {
+        Some((local_part, _)) if !local_part.is_empty() => {
+            return SubscriberEmail::parse(&pattern)
+                .map(|email| email.normalized().to_owned())
+                .map_err(|_| format!("{} is not a valid email address.", pattern));
+        }
+        Some(_) => return Err("The pattern has nothing before the @.".into()),
+        None => pattern.strip_prefix("*.").unwrap_or(&pattern),
+    };
+
+    let is_domain = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !is_domain {
+        return Err(format!("{} is not a valid domain.", domain));
+    }
+
+    Ok(pattern)
+}
+
+#[tracing::instrument(name = "Look for a matching email rule", skip_all)]
+async fn matching_rule(
+    connection_pool: &PgPool,
+    candidates: &[String],
+) -> Result<Option<RuleAction>, sqlx::Error> {
+    let actions = sqlx::query!(
+        "SELECT action FROM email_rules WHERE pattern = ANY($1)",
+        candidates
+    )
+    .fetch_all(connection_pool)
+    .await?;
+
+    // When several rules match, `allow` wins
+    let actions: Vec<_> = actions
+        .into_iter()
+        .filter_map(|r| RuleAction::try_from(r.action).ok())
+        .collect();
+    Ok(actions
+        .iter()
+        .copied()
+        .find(|action| *action == RuleAction::Allow)
+        .or(actions.first().copied()))
+}
+
+#[tracing::instrument(name = "List the email rules", skip_all)]
+pub async fn list_email_rules(connection_pool: &PgPool) -> Result<Vec<EmailRule>, sqlx::Error> {
+    let rules = sqlx::query!(
+        "SELECT id, pattern, action, created_at FROM email_rules ORDER BY created_at, pattern"
+    )
+    .fetch_all(connection_pool)
+    .await?;
+
+    Ok(rules
+        .into_iter()
+        .filter_map(|r| {
+            Some(EmailRule {
+                id: r.id,
+                pattern: r.pattern,
+                action: RuleAction::try_from(r.action).ok()?,
+                created_at: r.created_at,
+            })
+        })
+        .collect())
+}
+
+/// Adding a pattern that already exists changes its action
+#[tracing::instrument(
+    name = "Save an email rule",
+    skip_all,
+    fields(pattern = %Redacted::email(pattern), action = action.as_str())
+)]
+pub async fn save_email_rule(
+    connection_pool: &PgPool,
+    pattern: &str,
+    action: RuleAction,
+) -> Result<EmailRule, sqlx::Error> {
+    let rule = sqlx::query!(
+        "INSERT INTO email_rules (id, pattern, action, created_at) \
+        VALUES ($1, $2, $3, now()) \
+        ON CONFLICT (pattern) DO UPDATE SET action = EXCLUDED.action \
+        RETURNING id, created_at",
+        Uuid::new_v4(),
+        pattern,
+        action.as_str()
+    )
+    .fetch_one(connection_pool)
+    .await?;
+
+    Ok(EmailRule {
+        id: rule.id,
+        pattern: pattern.to_owned(),
+        action,
+        created_at: rule.created_at,
+    })
+}
+
+/// `false` if there was no such rule
+#[tracing::instrument(name = "Delete an email rule", skip(connection_pool))]
+pub async fn delete_email_rule(connection_pool: &PgPool, id: Uuid) -> Result<bool, sqlx::Error> {
+    let deleted = sqlx::query!("DELETE FROM email_rules WHERE id = $1", id)
+        .execute(connection_pool)
+        .await?
+        .rows_affected();
+
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_role_address, parse_rule_pattern, rule_candidates, EmailPolicy};
+    use crate::configuration::EmailPolicySettings;
+    use claims::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn disposable_domains_and_their_subdomains_are_recognised() {
+        let policy = EmailPolicy::new(&EmailPolicySettings::default());
+
+        assert!(policy.is_disposable("mailinator.com"));
+        assert!(policy.is_disposable("eu.mailinator.com"));
+        assert!(!policy.is_disposable("gmail.com"));
+        assert!(!policy.is_disposable("notmailinator.com"));
+    }
+
+    #[test]
+    fn role_addresses_are_recognised_with_or_without_a_tag() {
+        assert!(is_role_address("postmaster"));
+        assert!(is_role_address("abuse+newsletter"));
+        assert!(!is_role_address("ursula"));
+        assert!(!is_role_address("abused"));
+    }
+
+    #[test]
+    fn rules_can_match_the_address_the_domain_or_a_parent_domain() {
+        assert_eq!(
+            rule_candidates("ursula", "mail.example.com"),
+            vec![
+                "ursula@mail.example.com",
+                "mail.example.com",
+                "*.example.com",
+                "*.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn rule_patterns_are_lower_cased() {
+        assert_ok_eq!(
+            parse_rule_pattern(" Example.COM "),
+            "example.com".to_string()
+        );
+        assert_ok_eq!(
+            parse_rule_pattern("*.example.com"),
+            "*.example.com".to_string()
+        );
+        assert_ok_eq!(
+            parse_rule_pattern("Ursula@Example.com"),
+            "ursula@example.com".to_string()
+        );
+        assert_ok_eq!(
+            parse_rule_pattern("U.rsula+news@GMail.com"),
+            "u.rsula+news@gmail.com".to_string()
+        );
+    }
+
+    #[test]
+    fn malformed_rule_patterns_are_rejected() {
+        for pattern in [
+            "",
+            "com",
+            "@example.com",
+            "*example.com",
+            "exa mple.com",
+            "-a.com",
+        ] {
+            assert_err!(parse_rule_pattern(pattern), "{:?}", pattern);
+        }
+    }
+}
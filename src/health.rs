@@ -0,0 +1,260 @@
+use crate::email_client::EmailClient;
+use crate::startup::MIGRATOR;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A readiness probe should answer quickly, even when Postgres does not
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Background workers report here that they are still making progress.
+///
+/// A worker shows up in the readiness checks after its first heartbeat.
+pub struct Heartbeats {
+    /// A worker that has not reported for this long is considered stuck
+    timeout: Duration,
+    last_beats: Mutex<BTreeMap<&'static str, Instant>>,
+}
+
+impl Heartbeats {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_beats: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn beat(&self, worker: &'static str) {
+        self.last_beats
+            .lock()
+            .unwrap()
+            .insert(worker, Instant::now());
+    }
+
+    fn check(&self) -> Vec<(&'static str, CheckReport)> {
+        self.last_beats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(worker, last_beat)| {
+                let silence = last_beat.elapsed();
+                let report = if silence <= self.timeout {
+                    CheckReport::up(true)
+                } else {
+                    CheckReport::down(
+                        true,
+                        format!("No heartbeat for {} seconds", silence.as_secs()),
+                    )
+                };
+                (*worker, report)
+            })
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Up,
+    Down,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct CheckReport {
+    pub status: CheckStatus,
+    /// The application is not ready when a critical check is down
+    pub critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl CheckReport {
+    fn up(critical: bool) -> Self {
+        Self {
+            status: CheckStatus::Up,
+            critical,
+            message: None,
+        }
+    }
+
+    fn down(critical: bool, message: String) -> Self {
+        Self {
+            status: CheckStatus::Down,
+            critical,
+            message: Some(message),
+        }
+    }
+
+    fn is_failing(&self) -> bool {
+        self.critical && self.status == CheckStatus::Down
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessStatus {
+    Ready,
+    NotReady,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct ReadinessReport {
+    pub status: ReadinessStatus,
+    pub checks: BTreeMap<&'static str, CheckReport>,
+}
+
+/// Everything `/health/ready` looks at, on top of the database
+pub struct Readiness {
+    heartbeats: Arc<Heartbeats>,
+    /// `None` when the email provider is not checked
+    email_provider_cache: Option<Duration>,
+    email_provider_report: Mutex<Option<(Instant, CheckReport)>>,
+}
+
+impl Readiness {
+    pub fn new(heartbeats: Arc<Heartbeats>, email_provider_cache: Option<Duration>) -> Self {
+        Self {
+            heartbeats,
+            email_provider_cache,
+            email_provider_report: Mutex::new(None),
+        }
+    }
+
+    #[tracing::instrument(name = "Check readiness", skip_all)]
+    pub async fn check(&self, pool: &PgPool, email_client: &EmailClient) -> ReadinessReport {
+        let mut checks = BTreeMap::new();
+
+        checks.insert("database", check_database(pool).await);
+        checks.insert("migrations", check_migrations(pool).await);
+        if let Some(cache) = self.email_provider_cache {
+            checks.insert(
+                "email_provider",
+                self.check_email_provider(email_client, cache).await,
+            );
+        }
+        checks.extend(self.heartbeats.check());
+
+        let status = if checks.values().any(CheckReport::is_failing) {
+            tracing::warn!(checks = ?checks, "The application is not ready");
+            ReadinessStatus::NotReady
+        } else {
+            ReadinessStatus::Ready
+        };
+
+        ReadinessReport { status, checks }
+    }
+
+    /// Not critical: emails wait in the outbox until the provider is back.
+    /// The outcome is reused for `cache` to avoid calling the provider
+    /// on every probe.
+    async fn check_email_provider(
+        &self,
+        email_client: &EmailClient,
+        cache: Duration,
+    ) -> CheckReport {
+        if let Some((checked_at, report)) = &*self.email_provider_report.lock().unwrap() {
+            if checked_at.elapsed() < cache {
+                return report.clone();
+            }
+        }
+
+        let report = match email_client.check_connection().await {
+            Ok(()) => CheckReport::up(false),
+            Err(e) => CheckReport::down(false, e.to_string()),
+        };
+        *self.email_provider_report.lock().unwrap() = Some((Instant::now(), report.clone()));
+
+        report
+    }
+}
+
+async fn check_database(pool: &PgPool) -> CheckReport {
+    match tokio::time::timeout(
+        DATABASE_TIMEOUT,
+        sqlx::query!("SELECT 1 as one").fetch_one(pool),
+    )
+    .await
+    {
+        Ok(Ok(_)) => CheckReport::up(true),
+        Ok(Err(e)) => CheckReport::down(true, e.to_string()),
+        Err(_) => CheckReport::down(
+            true,
+            format!("No answer within {} seconds", DATABASE_TIMEOUT.as_secs()),
+        ),
+    }
+}
+
+/// The code expects every migration it ships with to have been applied
+async fn check_migrations(pool: &PgPool) -> CheckReport {
+    let applied = tokio::time::timeout(
+        DATABASE_TIMEOUT,
+        // Not checked at compile time: the table is created by the migrator,
+        // it is not part of the schema
+        sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool),
+    )
+    .await;
+
+    match applied {
+        Ok(Ok(applied)) => {
+            let pending = MIGRATOR
+                .iter()
+                .filter(|migration| !migration.migration_type.is_down_migration())
+                .filter(|migration| !applied.contains(&migration.version))
+                .count();
+            if pending == 0 {
+                CheckReport::up(true)
+            } else {
+                CheckReport::down(true, format!("{} pending migration(s)", pending))
+            }
+        }
+        Ok(Err(e)) => CheckReport::down(true, e.to_string()),
+        Err(_) => CheckReport::down(
+            true,
+            format!("No answer within {} seconds", DATABASE_TIMEOUT.as_secs()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CheckStatus, Heartbeats};
+    use std::time::Duration;
+
+    #[test]
+    fn workers_are_only_checked_after_their_first_heartbeat() {
+        let heartbeats = Heartbeats::new(Duration::from_secs(60));
+        assert!(heartbeats.check().is_empty());
+
+        heartbeats.beat("outbox_relay");
+
+        let checks = heartbeats.check();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].0, "outbox_relay");
+        assert_eq!(checks[0].1.status, CheckStatus::Up);
+    }
+
+    #[test]
+    fn a_silent_worker_is_down() {
+        let heartbeats = Heartbeats::new(Duration::from_millis(10));
+        heartbeats.beat("outbox_relay");
+
+        std::thread::sleep(Duration::from_millis(20));
+
+        let checks = heartbeats.check();
+        assert_eq!(checks[0].1.status, CheckStatus::Down);
+        assert!(checks[0].1.critical);
+    }
+
+    #[test]
+    fn a_new_heartbeat_brings_a_worker_back_up() {
+        let heartbeats = Heartbeats::new(Duration::from_millis(10));
+        heartbeats.beat("outbox_relay");
+        std::thread::sleep(Duration::from_millis(20));
+
+        heartbeats.beat("outbox_relay");
+
+        assert_eq!(heartbeats.check()[0].1.status, CheckStatus::Up);
+    }
+}
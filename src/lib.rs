@@ -1,6 +1,21 @@
+pub mod anti_bot;
+pub mod circuit_breaker;
+pub mod cli;
 pub mod configuration;
+pub mod deliverability;
 pub mod domain;
 pub mod email_client;
+pub mod email_message;
+pub mod email_policy;
+pub mod health;
+pub mod metrics;
+pub mod outbox;
+pub mod rate_limit;
+pub mod reload;
 pub mod routes;
+pub mod secrets;
+pub mod shutdown;
 pub mod startup;
+pub mod suppression;
 pub mod telemetry;
+pub mod tls;
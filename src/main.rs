@@ -1,16 +1,212 @@
-use zero2prod::configuration::get_configuration;
-use zero2prod::startup::Application;
-use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use clap::{Parser, Subcommand};
+use std::fmt::{Debug, Display};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{JoinError, JoinHandle};
+use zero2prod::cli::{self, IssueCommand, SubscribersCommand, TokensCommand};
+use zero2prod::configuration::{default_configuration_directory, get_configuration_from, Settings};
+use zero2prod::domain::set_email_normalization;
+use zero2prod::outbox::run_relay_until_stopped;
+use zero2prod::reload::{apply_log_filter, run_reloader_until_stopped, SharedSettings};
+use zero2prod::secrets::FileSecretProvider;
+use zero2prod::shutdown::Shutdown;
+use zero2prod::startup::{get_connection_pool, migrate, Application};
+use zero2prod::telemetry::{
+    get_subscriber, init_subscriber, otlp_tracer_from_env, set_redaction_policy,
+};
+
+/// Time left to close connection pools once the grace period is over
+const SHUTDOWN_MARGIN: Duration = Duration::from_secs(5);
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Directory holding `base.yaml` and the environment files
+    /// [default: ./configurations]
+    #[arg(long, env = "APP_CONFIG_DIR")]
+    config_dir: Option<PathBuf>,
+    /// `serve` when omitted
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API and run the background workers
+    Serve,
+    /// Apply the pending database migrations and exit
+    Migrate,
+    /// Manage subscribers
+    #[command(subcommand)]
+    Subscribers(SubscribersCommand),
+    /// Publish newsletter issues and follow them up
+    #[command(subcommand)]
+    Issue(IssueCommand),
+    /// Manage subscription tokens
+    #[command(subcommand)]
+    Tokens(TokensCommand),
+}
 
 #[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
-    let subscriber = get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
+async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    let (tracer_provider, tracer) = otlp_tracer_from_env("zero2prod")
+        .context("Failed to set up the OpenTelemetry exporter")?
+        .unzip();
+    // The other commands print their outcome to stdout, keep it readable
+    if let Command::Serve = command {
+        let (subscriber, log_filter) =
+            get_subscriber("zero2prod".into(), "info".into(), std::io::stdout, tracer);
+        init_subscriber(subscriber, log_filter);
+    } else {
+        let (subscriber, log_filter) =
+            get_subscriber("zero2prod".into(), "info".into(), std::io::stderr, tracer);
+        init_subscriber(subscriber, log_filter);
+    }
+
+    let configuration_directory = cli
+        .config_dir
+        .unwrap_or_else(default_configuration_directory);
+    let configuration = get_configuration_from(&configuration_directory, &FileSecretProvider)
+        .context("Failed to read configuration")?;
+    set_redaction_policy(configuration.telemetry.redaction.clone());
+    set_email_normalization(configuration.email_normalization.clone());
+    apply_log_filter(&configuration);
+
+    let outcome = match command {
+        Command::Serve => serve(configuration_directory, configuration).await,
+        Command::Migrate => migrate_database(&configuration).await,
+        Command::Subscribers(command) => cli::subscribers(command, &configuration).await,
+        Command::Issue(command) => cli::issue(command, &configuration).await,
+        Command::Tokens(command) => cli::tokens(command, &configuration).await,
+    };
+
+    // Export the spans that are still buffered
+    if let Some(tracer_provider) = tracer_provider {
+        for result in tracer_provider.force_flush() {
+            if let Err(e) = result {
+                tracing::error!(error.message = %e, "Failed to export the remaining spans");
+            }
+        }
+    }
+
+    outcome
+}
 
-    let configuration = get_configuration().expect("Failed to read configuration");
+/// Run the API and the background workers until the process is asked to stop
+async fn serve(
+    configuration_directory: PathBuf,
+    configuration: Settings,
+) -> Result<(), anyhow::Error> {
+    let grace_period = configuration.application.shutdown_grace_period();
+    let application = Application::build(configuration.clone()).await?;
+    let email_client = application.email_client();
+    let components = application.components();
+    let heartbeats = application.heartbeats();
+    let shutdown = application.shutdown();
+    let settings: SharedSettings = Arc::new(ArcSwap::from_pointee(configuration));
 
-    let application = Application::build(configuration).await?;
-    application.run_until_stopped().await?;
+    tokio::spawn(shutdown.clone().trigger_on_signal());
+
+    let application_task = supervise(
+        "API",
+        tokio::spawn(application.run_until_stopped()),
+        &shutdown,
+    );
+    let outbox_relay_task = supervise(
+        "Outbox relay",
+        tokio::spawn(run_relay_until_stopped(
+            settings.clone(),
+            email_client.clone(),
+            heartbeats,
+            shutdown.clone(),
+        )),
+        &shutdown,
+    );
+    let configuration_reloader_task = supervise(
+        "Configuration reloader",
+        tokio::spawn(run_reloader_until_stopped(
+            configuration_directory,
+            settings,
+            components,
+            shutdown.clone(),
+        )),
+        &shutdown,
+    );
+
+    // Every task finishes what it is doing, unless it takes too long
+    shutdown.triggered().await;
+    let tasks = async {
+        tokio::join!(
+            application_task,
+            outbox_relay_task,
+            configuration_reloader_task
+        )
+    };
+    if tokio::time::timeout(grace_period + SHUTDOWN_MARGIN, tasks)
+        .await
+        .is_err()
+    {
+        tracing::error!("Some tasks did not stop within the grace period");
+    }
+
+    Ok(())
+}
+
+/// Meant to run as a release step, before the new version is rolled out
+async fn migrate_database(configuration: &Settings) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+    migrate(&connection_pool)
+        .await
+        .context("Failed to migrate the database")?;
+    connection_pool.close().await;
 
     Ok(())
 }
+
+/// Report how `task` exited. Whichever task exits first brings the whole
+/// process down: the others are asked to stop.
+fn supervise<E>(
+    task_name: &'static str,
+    task: JoinHandle<Result<(), E>>,
+    shutdown: &Shutdown,
+) -> JoinHandle<()>
+where
+    E: Debug + Display + Send + 'static,
+{
+    let shutdown = shutdown.clone();
+
+    tokio::spawn(async move {
+        report_exit(task_name, task.await);
+        shutdown.trigger();
+    })
+}
+
+fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>, JoinError>) {
+    match outcome {
+        Ok(Ok(())) => {
+            tracing::info!("{} has exited", task_name)
+        }
+        Ok(Err(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} failed",
+                task_name
+            )
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} task failed to complete",
+                task_name
+            )
+        }
+    }
+}
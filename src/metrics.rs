@@ -0,0 +1,255 @@
+use crate::anti_bot::BotCheckFailure;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sqlx::pool::PoolConnection;
+use sqlx::{PgPool, Postgres};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// The metrics of the process, exposed in the Prometheus text format on `/metrics`.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// What happened to an email we tried to send
+#[derive(Clone, Copy)]
+pub enum EmailOutcome {
+    Sent,
+    /// Failed for good, we are not trying again
+    Failed,
+    /// Failed, another attempt is scheduled
+    Retried,
+}
+
+impl EmailOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EmailOutcome::Sent => "sent",
+            EmailOutcome::Failed => "failed",
+            EmailOutcome::Retried => "retried",
+        }
+    }
+}
+
+/// Steps of the subscription funnel, their ratio is the confirmation conversion rate
+#[derive(Clone, Copy)]
+pub enum SubscriptionEvent {
+    Requested,
+    Confirmed,
+    /// Refused by a rate limit or the confirmation cooldown
+    RateLimited,
+}
+
+impl SubscriptionEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionEvent::Requested => "requested",
+            SubscriptionEvent::Confirmed => "confirmed",
+            SubscriptionEvent::RateLimited => "rate_limited",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    emails: IntCounterVec,
+    subscriptions: IntCounterVec,
+    bot_submissions: IntCounterVec,
+    outbox_pending_emails: IntGauge,
+    db_pool_connections: IntGaugeVec,
+    db_pool_acquire_duration: Histogram,
+    db_pool_acquire_failures: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling HTTP requests",
+            ),
+            &["method", "route"],
+        )
+        .unwrap();
+        let emails = IntCounterVec::new(
+            Opts::new("emails_total", "Emails handed to the email provider"),
+            &["kind", "outcome"],
+        )
+        .unwrap();
+        let subscriptions = IntCounterVec::new(
+            Opts::new(
+                "subscriptions_total",
+                "Subscription requests and confirmations",
+            ),
+            &["event"],
+        )
+        .unwrap();
+        let bot_submissions = IntCounterVec::new(
+            Opts::new(
+                "bot_submissions_total",
+                "Subscription requests turned away as coming from bots",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let outbox_pending_emails =
+            IntGauge::new("outbox_pending_emails", "Emails waiting in the outbox").unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Connections of the Postgres pool"),
+            &["state"],
+        )
+        .unwrap();
+        let db_pool_acquire_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "db_pool_acquire_duration_seconds",
+                "Time spent waiting for a connection of the Postgres pool",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+            ]),
+        )
+        .unwrap();
+        let db_pool_acquire_failures = IntCounter::new(
+            "db_pool_acquire_failures_total",
+            "Connections of the Postgres pool that could not be acquired, e.g. timed out",
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(emails.clone())).unwrap();
+        registry.register(Box::new(subscriptions.clone())).unwrap();
+        registry
+            .register(Box::new(bot_submissions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(outbox_pending_emails.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_acquire_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_acquire_failures.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            emails,
+            subscriptions,
+            bot_submissions,
+            outbox_pending_emails,
+            db_pool_connections,
+            db_pool_acquire_duration,
+            db_pool_acquire_failures,
+        }
+    }
+
+    /// `route` must be the pattern of the matched route (e.g. `/subscriptions`),
+    /// never the raw path, to keep the number of series bounded.
+    pub fn record_http_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests
+            .with_label_values(&[method, route, &status.to_string()])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[method, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_email(&self, kind: &str, outcome: EmailOutcome) {
+        self.emails
+            .with_label_values(&[kind, outcome.as_str()])
+            .inc();
+    }
+
+    pub fn record_subscription(&self, event: SubscriptionEvent) {
+        self.subscriptions
+            .with_label_values(&[event.as_str()])
+            .inc();
+    }
+
+    pub fn record_bot_submission(&self, reason: BotCheckFailure) {
+        self.bot_submissions
+            .with_label_values(&[reason.as_str()])
+            .inc();
+    }
+
+    pub fn record_pool_acquire(&self, elapsed: Duration, acquired: bool) {
+        self.db_pool_acquire_duration.observe(elapsed.as_secs_f64());
+        if !acquired {
+            self.db_pool_acquire_failures.inc();
+        }
+    }
+
+    /// Render every metric, refreshing the gauges that are sampled on scrape
+    pub async fn render(&self, pool: &PgPool) -> String {
+        let size = pool.size() as i64;
+        let idle = pool.num_idle() as i64;
+        self.db_pool_connections
+            .with_label_values(&["max"])
+            .set(pool.options().get_max_connections() as i64);
+        self.db_pool_connections
+            .with_label_values(&["open"])
+            .set(size);
+        self.db_pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        self.db_pool_connections
+            .with_label_values(&["in_use"])
+            .set(size - idle);
+
+        match count_pending_emails(pool).await {
+            Ok(pending) => self.outbox_pending_emails.set(pending),
+            Err(e) => tracing::warn!(
+                error.cause_chain = ?e,
+                "Failed to count the pending emails for the metrics"
+            ),
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode the metrics");
+
+        String::from_utf8(buffer).expect("The metrics are not valid UTF-8")
+    }
+}
+
+/// `pool.acquire()`, recording how long we waited for the connection.
+///
+/// A pool that is too small shows up here first: requests queue for a
+/// connection long before Postgres itself gets slow.
+pub async fn acquire(pool: &PgPool) -> Result<PoolConnection<Postgres>, sqlx::Error> {
+    let started_at = Instant::now();
+    let connection = pool.acquire().await;
+    metrics().record_pool_acquire(started_at.elapsed(), connection.is_ok());
+
+    connection
+}
+
+async fn count_pending_emails(pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query!(r#"SELECT count(*) as "count!" FROM outbox WHERE status = 'pending'"#)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.count)
+}
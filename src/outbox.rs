@@ -0,0 +1,379 @@
+use crate::{
+    configuration::OutboxSettings,
+    domain::SubscriberEmail,
+    email_client::{EmailClient, EmailError, SendReceipt},
+    email_message::EmailMessage,
+    health::Heartbeats,
+    metrics::{acquire, metrics, EmailOutcome},
+    reload::SharedSettings,
+    shutdown::Shutdown,
+    startup::get_connection_pool,
+    suppression::suppress_subscriber,
+};
+use chrono::Utc;
+use sqlx::{Connection, Executor, PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Upper bound for the delay between two delivery attempts of the same email
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// How long the relay waits before polling an empty outbox again
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The content of an email waiting in the outbox
+pub struct OutboxEmail<'a> {
+    /// What triggered the email, e.g. `subscription_confirmation`
+    pub kind: &'a str,
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+}
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// Write an email to the outbox as part of `transaction`.
+///
+/// The email is only visible to the relay once the transaction commits,
+/// so it is never sent for changes that were rolled back and never lost
+/// for changes that were committed.
+#[tracing::instrument(
+    name = "Enqueue an email in the outbox",
+    skip(transaction, email),
+    fields(kind = %email.kind)
+)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: OutboxEmail<'_>,
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO outbox (
+            id, kind, recipient, subject, html_body, text_body,
+            status, next_attempt_at, created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, 'pending', now(), now())
+        "#,
+        id,
+        email.kind,
+        email.recipient.as_ref(),
+        email.subject,
+        email.html_body,
+        email.text_body,
+    );
+
+    transaction.execute(query).await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(id)
+}
+
+/// The name the relay reports its heartbeats under
+pub const OUTBOX_RELAY_WORKER: &str = "outbox_relay";
+
+/// Deliver the emails of the outbox until `shutdown` is triggered.
+///
+/// The email being delivered when the shutdown is triggered is completed first.
+pub async fn run_relay_until_stopped(
+    settings: SharedSettings,
+    email_client: Arc<EmailClient>,
+    heartbeats: Arc<Heartbeats>,
+    shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&settings.load().database);
+
+    relay_loop(
+        &connection_pool,
+        email_client,
+        settings,
+        heartbeats,
+        shutdown,
+    )
+    .await;
+    connection_pool.close().await;
+    tracing::info!("The outbox relay has stopped");
+
+    Ok(())
+}
+
+async fn relay_loop(
+    pool: &PgPool,
+    email_client: Arc<EmailClient>,
+    settings: SharedSettings,
+    heartbeats: Arc<Heartbeats>,
+    shutdown: Shutdown,
+) {
+    while !shutdown.is_triggered() {
+        heartbeats.beat(OUTBOX_RELAY_WORKER);
+        // Reloaded settings are picked up by the next task
+        let outbox_settings = settings.load().outbox.clone();
+
+        match try_execute_task(pool, &email_client, &outbox_settings).await {
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Ok(ExecutionOutcome::EmptyQueue) | Err(_) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    _ = shutdown.triggered() => {}
+                }
+            }
+        }
+    }
+}
+
+/// Try to deliver the oldest email that is due.
+///
+/// The row stays locked (`FOR UPDATE SKIP LOCKED`) until we are done with it,
+/// which allows multiple relays to run side by side without sending
+/// the same email twice.
+#[tracing::instrument(
+    skip_all,
+    fields(outbox_id = tracing::field::Empty, kind = tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    settings: &OutboxSettings,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut connection = acquire(pool).await?;
+    let mut transaction = connection.begin().await?;
+
+    let task = sqlx::query!(
+        r#"
+        SELECT id, kind, recipient, subject, html_body, text_body, attempts
+        FROM outbox
+        WHERE status = 'pending' AND next_attempt_at <= now()
+        ORDER BY next_attempt_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+
+    let Some(task) = task else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+
+    tracing::Span::current()
+        .record("outbox_id", tracing::field::display(task.id))
+        .record("kind", tracing::field::display(&task.kind));
+
+    let recipient = match SubscriberEmail::parse(&task.recipient) {
+        Ok(recipient) => recipient,
+        Err(error) => {
+            tracing::error!(
+                error.message = %error,
+                "Giving up on an outbox email, its recipient is invalid"
+            );
+            mark_as_failed(&mut transaction, task.id, task.attempts, &error).await?;
+            transaction.commit().await?;
+            metrics().record_email(&task.kind, EmailOutcome::Failed);
+
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+
+    let message = EmailMessage::builder(recipient, task.subject)
+        .html_body(task.html_body)
+        .text_body(task.text_body)
+        .tag(&task.kind)
+        .build();
+
+    let result = email_client.send(&message).await;
+
+    let outcome = match result {
+        Ok(receipt) => {
+            tracing::info!(
+                message_id = %receipt.message_id,
+                "Delivered an outbox email"
+            );
+            mark_as_delivered(&mut transaction, task.id, task.attempts, &receipt).await?;
+            EmailOutcome::Sent
+        }
+        Err(error @ EmailError::CircuitOpen) | Err(error @ EmailError::RateLimited { .. }) => {
+            // The email provider is known to be down or asked us to slow down:
+            // this was not a real delivery attempt, so we try again later
+            // without counting it
+            let delay = match error {
+                EmailError::RateLimited {
+                    retry_after: Some(retry_after),
+                } => retry_after,
+                _ => settings.retry_base_delay(),
+            };
+            reschedule(
+                &mut transaction,
+                task.id,
+                task.attempts,
+                delay,
+                &error.to_string(),
+            )
+            .await?;
+            EmailOutcome::Retried
+        }
+        Err(error @ EmailError::Unauthorized) => {
+            // Every email would fail the same way until someone fixes the
+            // configuration: keep them all around without burning attempts
+            tracing::error!(
+                error.cause_chain = ?error,
+                "The email provider rejected our API token, \
+                check `email_client.authorization_token`"
+            );
+            reschedule(
+                &mut transaction,
+                task.id,
+                task.attempts,
+                settings.retry_base_delay(),
+                &error.to_string(),
+            )
+            .await?;
+            EmailOutcome::Retried
+        }
+        Err(error @ EmailError::InvalidRecipient { .. }) => {
+            tracing::warn!(
+                error.cause_chain = ?error,
+                "Suppressing a recipient the email provider refuses to deliver to"
+            );
+            mark_as_failed(
+                &mut transaction,
+                task.id,
+                task.attempts + 1,
+                &error.to_string(),
+            )
+            .await?;
+            suppress_subscriber(&mut *transaction, message.to()).await?;
+            EmailOutcome::Failed
+        }
+        Err(error) => {
+            let attempts = task.attempts + 1;
+
+            if !error.is_transient() {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    attempts,
+                    "Giving up on an outbox email, the email provider rejected it"
+                );
+                mark_as_failed(&mut transaction, task.id, attempts, &error.to_string()).await?;
+                EmailOutcome::Failed
+            } else if attempts >= settings.max_attempts as i32 {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    attempts,
+                    "Giving up on an outbox email after too many failed attempts"
+                );
+                mark_as_failed(&mut transaction, task.id, attempts, &error.to_string()).await?;
+                EmailOutcome::Failed
+            } else {
+                tracing::warn!(
+                    error.cause_chain = ?error,
+                    attempts,
+                    "Failed to deliver an outbox email, it will be retried"
+                );
+                let delay = retry_delay(settings, attempts);
+                reschedule(
+                    &mut transaction,
+                    task.id,
+                    attempts,
+                    delay,
+                    &error.to_string(),
+                )
+                .await?;
+                EmailOutcome::Retried
+            }
+        }
+    };
+
+    transaction.commit().await?;
+    metrics().record_email(&task.kind, outcome);
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Exponential backoff: `base`, `2 * base`, `4 * base`, ... up to `MAX_RETRY_DELAY`
+fn retry_delay(settings: &OutboxSettings, attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+
+    settings
+        .retry_base_delay()
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_RETRY_DELAY)
+}
+
+async fn mark_as_delivered(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    attempts: i32,
+    receipt: &SendReceipt,
+) -> Result<(), anyhow::Error> {
+    let query = sqlx::query!(
+        r#"
+        UPDATE outbox
+        SET status = 'delivered', attempts = $2, delivered_at = $3,
+            provider_message_id = $4, last_error = NULL
+        WHERE id = $1
+        "#,
+        id,
+        attempts + 1,
+        receipt.submitted_at,
+        receipt.message_id,
+    );
+    transaction.execute(query).await?;
+
+    Ok(())
+}
+
+async fn mark_as_failed(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    attempts: i32,
+    error: &str,
+) -> Result<(), anyhow::Error> {
+    let query = sqlx::query!(
+        r#"
+        UPDATE outbox
+        SET status = 'failed', attempts = $2, last_error = $3
+        WHERE id = $1
+        "#,
+        id,
+        attempts,
+        error,
+    );
+    transaction.execute(query).await?;
+
+    Ok(())
+}
+
+async fn reschedule(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    attempts: i32,
+    delay: Duration,
+    error: &str,
+) -> Result<(), anyhow::Error> {
+    let next_attempt_at = Utc::now() + delay;
+
+    let query = sqlx::query!(
+        r#"
+        UPDATE outbox
+        SET attempts = $2, last_error = $3, next_attempt_at = $4
+        WHERE id = $1
+        "#,
+        id,
+        attempts,
+        error,
+        next_attempt_at,
+    );
+    transaction.execute(query).await?;
+
+    Ok(())
+}
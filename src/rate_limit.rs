@@ -0,0 +1,454 @@
+use crate::configuration::{RateLimitSettings, RateLimitStore, TokenBucketSettings};
+use crate::domain::SubscriberEmail;
+use crate::metrics::acquire;
+use actix_web::HttpRequest;
+use arc_swap::ArcSwap;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::{Connection, PgPool};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Past this many buckets, the in-memory store forgets the ones that are full,
+/// then the least recently used ones
+const MAX_MEMORY_BUCKETS: usize = 100_000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How often the Postgres store deletes the buckets that are full
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// `capacity` requests in a burst, then one more every `refill_period`
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_period: Duration,
+}
+
+impl TokenBucket {
+    pub fn new(settings: &TokenBucketSettings) -> Self {
+        Self {
+            capacity: settings.capacity as f64,
+            refill_period: settings.refill_period(),
+        }
+    }
+
+    /// Take a token out of a bucket that held `tokens` `elapsed` ago.
+    /// Returns the tokens left in the bucket.
+    fn take(&self, tokens: f64, elapsed: Duration) -> (f64, RateLimitDecision) {
+        let refilled =
+            (tokens + elapsed.as_secs_f64() / self.refill_period.as_secs_f64()).min(self.capacity);
+
+        if refilled >= 1.0 {
+            (refilled - 1.0, RateLimitDecision::Allowed)
+        } else {
+            let retry_after = self.refill_period.mul_f64(1.0 - refilled);
+            (refilled, RateLimitDecision::Limited { retry_after })
+        }
+    }
+
+    /// How long a bucket holding `tokens` takes to be full again
+    fn full_after(&self, tokens: f64) -> Duration {
+        self.refill_period.mul_f64(self.capacity - tokens)
+    }
+}
+
+struct MemoryBucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+enum Store {
+    Memory(Mutex<HashMap<String, MemoryBucket>>),
+    Postgres {
+        connection_pool: PgPool,
+        last_pruned: Mutex<Instant>,
+    },
+}
+
+/// The part of `RateLimitSettings` that can change at runtime
+struct Limits {
+    per_ip: TokenBucket,
+    per_email: TokenBucket,
+    confirmation_cooldown: Duration,
+    trust_forwarded_headers: bool,
+    bucket_key_secret: Secret<String>,
+}
+
+impl Limits {
+    fn new(settings: &RateLimitSettings, random_secret: &Secret<String>) -> Self {
+        Self {
+            per_ip: TokenBucket::new(&settings.per_ip),
+            per_email: TokenBucket::new(&settings.per_email),
+            confirmation_cooldown: settings.confirmation_cooldown(),
+            trust_forwarded_headers: settings.trust_forwarded_headers,
+            bucket_key_secret: settings
+                .bucket_key_secret
+                .clone()
+                .unwrap_or_else(|| random_secret.clone()),
+        }
+    }
+}
+
+/// Limits how often `POST /subscriptions` can be called, by IP address
+/// and by email address, using token buckets.
+pub struct RateLimiter {
+    store: Store,
+    /// Can be changed at runtime with `reconfigure`
+    limits: ArcSwap<Limits>,
+    /// The bucket key secret when none is configured, which is only allowed
+    /// for the memory store: its buckets do not outlive the process
+    random_secret: Secret<String>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings, connection_pool: PgPool) -> Self {
+        let store = match settings.store {
+            RateLimitStore::Memory => Store::Memory(Mutex::new(HashMap::new())),
+            RateLimitStore::Postgres => Store::Postgres {
+                connection_pool,
+                last_pruned: Mutex::new(Instant::now()),
+            },
+        };
+
+        let random_secret = Secret::new(
+            thread_rng()
+                .sample_iter(Alphanumeric)
+                .map(char::from)
+                .take(32)
+                .collect(),
+        );
+
+        Self {
+            store,
+            limits: ArcSwap::from_pointee(Limits::new(settings, &random_secret)),
+            random_secret,
+        }
+    }
+
+    /// Apply new limits to the requests checked from now on. Buckets keep
+    /// their tokens, the store cannot be changed without a restart.
+    pub fn reconfigure(&self, settings: &RateLimitSettings) {
+        self.limits
+            .store(Arc::new(Limits::new(settings, &self.random_secret)));
+    }
+
+    /// Minimum time before the confirmation email is sent again to a pending address
+    pub fn confirmation_cooldown(&self) -> Duration {
+        self.limits.load().confirmation_cooldown
+    }
+
+    /// The address the request comes from, `None` if it is unknown
+    pub fn client_ip(&self, request: &HttpRequest) -> Option<String> {
+        if self.limits.load().trust_forwarded_headers {
+            let forwarded_for = request
+                .headers()
+                .get_all("X-Forwarded-For")
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .map(|ip| ip.trim().to_owned());
+            if forwarded_for.is_some() {
+                return forwarded_for;
+            }
+        }
+
+        request.peer_addr().map(|address| address.ip().to_string())
+    }
+
+    #[tracing::instrument(name = "Check the rate limit of an IP address", skip_all)]
+    pub async fn check_ip(&self, ip: &str) -> Result<RateLimitDecision, sqlx::Error> {
+        let (bucket, key) = {
+            let limits = self.limits.load();
+            let key = bucket_key(&limits.bucket_key_secret, "ip", &ip_network(ip));
+            (limits.per_ip, key)
+        };
+        self.take(&bucket, &key).await
+    }
+
+    #[tracing::instrument(name = "Check the rate limit of an email address", skip_all)]
+    pub async fn check_email(
+        &self,
+        email: &SubscriberEmail,
+    ) -> Result<RateLimitDecision, sqlx::Error> {
+        let (bucket, key) = {
+            let limits = self.limits.load();
+            let key = bucket_key(&limits.bucket_key_secret, "email", email.normalized());
+            (limits.per_email, key)
+        };
+        self.take(&bucket, &key).await
+    }
+
+    async fn take(
+        &self,
+        bucket: &TokenBucket,
+        key: &str,
+    ) -> Result<RateLimitDecision, sqlx::Error> {
+        match &self.store {
+            Store::Memory(buckets) => {
+                Ok(take_from_memory(buckets, MAX_MEMORY_BUCKETS, bucket, key))
+            }
+            Store::Postgres {
+                connection_pool,
+                last_pruned,
+            } => {
+                let prune = {
+                    let mut last_pruned = last_pruned.lock().unwrap();
+                    let prune = last_pruned.elapsed() >= PRUNE_INTERVAL;
+                    if prune {
+                        *last_pruned = Instant::now();
+                    }
+                    prune
+                };
+                if prune {
+                    prune_postgres_buckets(connection_pool).await?;
+                }
+
+                take_from_postgres(connection_pool, bucket, key).await
+            }
+        }
+    }
+}
+
+/// IP and email addresses are not kept as they are, only their HMAC:
+/// without the secret, known addresses cannot be matched to their buckets
+fn bucket_key(secret: &Secret<String>, scope: &str, value: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(value.as_bytes());
+    format!("{}:{:x}", scope, mac.finalize().into_bytes())
+}
+
+/// The network an IP address is limited by: IPv6 clients usually get
+/// a whole /64, they would get a new bucket for every address otherwise
+fn ip_network(ip: &str) -> String {
+    match ip.parse() {
+        Ok(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.to_string(),
+            None => {
+                let segments = ip.segments();
+                format!(
+                    "{:x}:{:x}:{:x}:{:x}::/64",
+                    segments[0], segments[1], segments[2], segments[3]
+                )
+            }
+        },
+        _ => ip.to_owned(),
+    }
+}
+
+fn take_from_memory(
+    buckets: &Mutex<HashMap<String, MemoryBucket>>,
+    max_buckets: usize,
+    bucket: &TokenBucket,
+    key: &str,
+) -> RateLimitDecision {
+    let now = Instant::now();
+    let mut buckets = buckets.lock().unwrap();
+    if buckets.len() >= max_buckets && !buckets.contains_key(key) {
+        buckets.retain(|_, b| b.full_at > now);
+        if buckets.len() >= max_buckets {
+            evict_least_recently_used(&mut buckets);
+        }
+    }
+
+    let (tokens, elapsed) = match buckets.get(key) {
+        Some(b) => (b.tokens, now.duration_since(b.updated_at)),
+        None => (bucket.capacity, Duration::ZERO),
+    };
+    let (tokens, decision) = bucket.take(tokens, elapsed);
+    buckets.insert(
+        key.to_owned(),
+        MemoryBucket {
+            tokens,
+            updated_at: now,
+            full_at: now + bucket.full_after(tokens),
+        },
+    );
+
+    decision
+}
+
+/// Forget the least recently used tenth of the buckets, they are as good as
+/// full again compared to the others
+fn evict_least_recently_used(buckets: &mut HashMap<String, MemoryBucket>) {
+    let mut updated_at: Vec<Instant> = buckets.values().map(|b| b.updated_at).collect();
+    let index = updated_at.len() / 10;
+    let (_, cutoff, _) = updated_at.select_nth_unstable(index);
+    let cutoff = *cutoff;
+    buckets.retain(|_, b| b.updated_at > cutoff);
+}
+
+#[tracing::instrument(name = "Take a token from a shared bucket", skip_all)]
+async fn take_from_postgres(
+    connection_pool: &PgPool,
+    bucket: &TokenBucket,
+    key: &str,
+) -> Result<RateLimitDecision, sqlx::Error> {
+    let mut connection = acquire(connection_pool).await?;
+    let mut transaction = connection.begin().await?;
+
+    sqlx::query!(
+        "INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at) \
+        VALUES ($1, $2, now(), now()) \
+        ON CONFLICT (key) DO NOTHING",
+        key,
+        bucket.capacity
+    )
+    .execute(&mut *transaction)
+    .await?;
+    // Concurrent requests for the same key wait for each other here
+    let row = sqlx::query!(
+        r#"
+        SELECT tokens, EXTRACT(EPOCH FROM now() - updated_at)::float8 AS "elapsed_seconds!"
+        FROM rate_limit_buckets
+        WHERE key = $1
+        FOR UPDATE
+        "#,
+        key
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    let elapsed = Duration::from_secs_f64(row.elapsed_seconds.max(0.0));
+    let (tokens, decision) = bucket.take(row.tokens, elapsed);
+    sqlx::query!(
+        "UPDATE rate_limit_buckets \
+        SET tokens = $2, updated_at = now(), full_at = now() + make_interval(secs => $3) \
+        WHERE key = $1",
+        key,
+        tokens,
+        bucket.full_after(tokens).as_secs_f64()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(decision)
+}
+
+/// A bucket that is full again behaves as if it was never used
+#[tracing::instrument(name = "Prune the full rate limit buckets", skip_all)]
+async fn prune_postgres_buckets(connection_pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM rate_limit_buckets WHERE full_at <= now()")
+        .execute(connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket_key, ip_network, take_from_memory, RateLimitDecision, TokenBucket};
+    use crate::configuration::TokenBucketSettings;
+    use secrecy::Secret;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn bucket() -> TokenBucket {
+        TokenBucket::new(&TokenBucketSettings {
+            capacity: 2,
+            refill_period_seconds: 10,
+        })
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_up_to_its_capacity() {
+        let bucket = bucket();
+
+        let (tokens, first) = bucket.take(2.0, Duration::ZERO);
+        let (tokens, second) = bucket.take(tokens, Duration::ZERO);
+        let (_, third) = bucket.take(tokens, Duration::ZERO);
+
+        assert_eq!(first, RateLimitDecision::Allowed);
+        assert_eq!(second, RateLimitDecision::Allowed);
+        assert_eq!(
+            third,
+            RateLimitDecision::Limited {
+                retry_after: Duration::from_secs(10)
+            }
+        );
+    }
+
+    #[test]
+    fn an_empty_bucket_is_refilled_over_time() {
+        let bucket = bucket();
+
+        let (tokens, half_way) = bucket.take(0.0, Duration::from_secs(5));
+        let (_, refilled) = bucket.take(tokens, Duration::from_secs(5));
+
+        assert_eq!(
+            half_way,
+            RateLimitDecision::Limited {
+                retry_after: Duration::from_secs(5)
+            }
+        );
+        assert_eq!(refilled, RateLimitDecision::Allowed);
+    }
+
+    #[test]
+    fn a_bucket_never_holds_more_than_its_capacity() {
+        let bucket = bucket();
+
+        let (tokens, _) = bucket.take(0.0, Duration::from_secs(3600));
+
+        assert_eq!(tokens, 1.0);
+        assert_eq!(bucket.full_after(tokens), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn bucket_keys_depend_on_the_secret() {
+        let secret = Secret::new("a".repeat(32));
+        let other_secret = Secret::new("b".repeat(32));
+
+        let key = bucket_key(&secret, "ip", "203.0.113.7");
+
+        assert!(key.starts_with("ip:"));
+        assert!(!key.contains("203.0.113.7"));
+        assert_eq!(key, bucket_key(&secret, "ip", "203.0.113.7"));
+        assert_ne!(key, bucket_key(&other_secret, "ip", "203.0.113.7"));
+    }
+
+    #[test]
+    fn ipv6_addresses_are_limited_by_their_64_bit_prefix() {
+        assert_eq!(
+            ip_network("2001:db8:85a3:8d3:1319:8a2e:370:7348"),
+            ip_network("2001:db8:85a3:8d3::1")
+        );
+        assert_ne!(
+            ip_network("2001:db8:85a3:8d3::1"),
+            ip_network("2001:db8:85a3:8d4::1")
+        );
+        assert_eq!(ip_network("::ffff:203.0.113.7"), "203.0.113.7");
+        assert_eq!(ip_network("203.0.113.7"), "203.0.113.7");
+    }
+
+    #[test]
+    fn the_least_recently_used_buckets_are_evicted_when_the_memory_store_is_full() {
+        let bucket = bucket();
+        let buckets = Mutex::new(HashMap::new());
+
+        for i in 0..10 {
+            take_from_memory(&buckets, 10, &bucket, &i.to_string());
+        }
+        // None of the buckets is full yet, the oldest one makes room
+        take_from_memory(&buckets, 10, &bucket, "new");
+
+        let buckets = buckets.lock().unwrap();
+        assert!(buckets.len() <= 10);
+        assert!(buckets.contains_key("new"));
+        assert!(!buckets.contains_key("0"));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{
+    anti_bot::AntiBot,
+    configuration::{get_configuration_from, InvalidSettings, Settings},
+    email_client::EmailClient,
+    email_policy::EmailPolicy,
+    rate_limit::RateLimiter,
+    secrets::{FileSecretProvider, SecretProvider},
+    shutdown::Shutdown,
+    telemetry::{log_filter, set_redaction_policy},
+};
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use notify::{RecursiveMode, Watcher};
+use sqlx::PgPool;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The settings currently in effect.
+///
+/// Readers call `load()` every time they need a value so that they pick up
+/// reloaded settings, which are swapped in atomically.
+pub type SharedSettings = Arc<ArcSwap<Settings>>;
+
+/// Editors often save a file in several steps (truncate, write, rename):
+/// we wait for things to settle before reading it.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// The long-lived parts of the application that apply reloaded settings
+/// to the requests they handle from then on.
+#[derive(Clone)]
+pub struct Components {
+    pub email_client: Arc<EmailClient>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub anti_bot: Arc<AntiBot>,
+    pub email_policy: Arc<EmailPolicy>,
+}
+
+impl Components {
+    pub fn new(settings: &Settings, connection_pool: PgPool) -> Result<Self, InvalidSettings> {
+        Ok(Self {
+            email_client: Arc::new(settings.email_client.clone().client()?),
+            rate_limiter: Arc::new(RateLimiter::new(
+                &settings.application.rate_limit,
+                connection_pool,
+            )),
+            anti_bot: Arc::new(AntiBot::new(&settings.application.anti_bot)),
+            email_policy: Arc::new(EmailPolicy::new(&settings.application.email_policy)),
+        })
+    }
+
+    fn reconfigure(&self, settings: &Settings) {
+        self.email_client.reconfigure(&settings.email_client);
+        self.rate_limiter
+            .reconfigure(&settings.application.rate_limit);
+        self.anti_bot.reconfigure(&settings.application.anti_bot);
+        self.email_policy
+            .reconfigure(&settings.application.email_policy);
+    }
+}
+
+#[derive(Debug)]
+enum Trigger {
+    FileChange,
+    Hangup,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReloadOutcome {
+    Applied,
+    /// The new configuration changes settings that need a restart,
+    /// none of it was applied
+    Rejected {
+        structural_changes: Vec<&'static str>,
+    },
+}
+
+/// Read the configuration again and apply it if only reloadable settings changed.
+///
+/// An invalid configuration is an error and leaves the current settings untouched.
+#[tracing::instrument(name = "Reload the configuration", skip_all, err)]
+pub fn reload_configuration(
+    configuration_directory: &Path,
+    secret_provider: &dyn SecretProvider,
+    settings: &SharedSettings,
+    components: &Components,
+) -> Result<ReloadOutcome, anyhow::Error> {
+    let new_settings = get_configuration_from(configuration_directory, secret_provider)
+        .context("Failed to read the configuration")?;
+
+    apply_configuration(new_settings, settings, components)
+}
+
+/// Apply `new_settings` if they are valid and only change reloadable settings.
+fn apply_configuration(
+    new_settings: Settings,
+    settings: &SharedSettings,
+    components: &Components,
+) -> Result<ReloadOutcome, anyhow::Error> {
+    new_settings.validate()?;
+
+    let structural_changes = settings.load().structural_changes(&new_settings);
+    if !structural_changes.is_empty() {
+        tracing::error!(
+            structural_changes = ?structural_changes,
+            "Rejected the new configuration, these settings can only change with a restart"
+        );
+        return Ok(ReloadOutcome::Rejected { structural_changes });
+    }
+
+    components.reconfigure(&new_settings);
+    set_redaction_policy(new_settings.telemetry.redaction.clone());
+    if new_settings.telemetry.log_filter != settings.load().telemetry.log_filter {
+        apply_log_filter(&new_settings);
+    }
+    settings.store(Arc::new(new_settings));
+    tracing::info!("Applied the new configuration");
+
+    Ok(ReloadOutcome::Applied)
+}
+
+/// Replace the filter of the global subscriber with `telemetry.log_filter`, if set.
+///
+/// A filter set through the admin endpoint is overridden.
+pub fn apply_log_filter(settings: &Settings) {
+    let (Some(directives), Some(log_filter)) = (&settings.telemetry.log_filter, log_filter())
+    else {
+        return;
+    };
+    // The directives are checked by `Settings::validate`
+    if let Err(e) = log_filter.set(directives, None) {
+        tracing::error!(error.cause_chain = ?e, "Failed to apply telemetry.log_filter");
+    }
+}
+
+/// Reload the configuration when a file in `configuration_directory` changes
+/// or when the process receives `SIGHUP`, until `shutdown` is triggered.
+pub async fn run_reloader_until_stopped(
+    configuration_directory: PathBuf,
+    settings: SharedSettings,
+    components: Components,
+    shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let file_sender = sender.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if !event.kind.is_access() {
+                let _ = file_sender.send(Trigger::FileChange);
+            }
+        }
+    })
+    .context("Failed to create a watcher for the configuration files")?;
+    watcher
+        .watch(&configuration_directory, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {:?}", configuration_directory))?;
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if sender.send(Trigger::Hangup).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    loop {
+        let trigger = tokio::select! {
+            trigger = receiver.recv() => match trigger {
+                Some(trigger) => trigger,
+                None => break,
+            },
+            _ = shutdown.triggered() => break,
+        };
+        tokio::time::sleep(DEBOUNCE).await;
+        while receiver.try_recv().is_ok() {}
+
+        tracing::info!(trigger = ?trigger, "Reloading the configuration");
+        // Errors are logged by `reload_configuration`, we keep running
+        // with the current settings
+        let _ = reload_configuration(
+            &configuration_directory,
+            &FileSecretProvider,
+            &settings,
+            &components,
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_configuration, Components, ReloadOutcome, SharedSettings};
+    use crate::configuration::{load_configuration, Environment, Settings};
+    use crate::secrets::FileSecretProvider;
+    use crate::startup::get_connection_pool;
+    use arc_swap::ArcSwap;
+    use claims::assert_err;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// A copy of the repository's configuration that tests can modify
+    fn configuration_directory() -> PathBuf {
+        let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("configurations");
+        let directory = std::env::temp_dir().join(format!("zero2prod-reload-{}", Uuid::new_v4()));
+        std::fs::create_dir(&directory).unwrap();
+        for name in ["base.yaml", "local.yaml"] {
+            std::fs::copy(source.join(name), directory.join(name)).unwrap();
+        }
+        directory
+    }
+
+    /// The configuration of the local environment, whatever the `APP_*`
+    /// variables of the process running the tests
+    fn load(directory: &Path) -> Settings {
+        load_configuration(
+            directory,
+            Environment::Local,
+            HashMap::new(),
+            &FileSecretProvider,
+        )
+        .unwrap()
+    }
+
+    fn start(directory: &Path) -> (SharedSettings, Components) {
+        let settings = load(directory);
+        let components =
+            Components::new(&settings, get_connection_pool(&settings.database)).unwrap();
+
+        (Arc::new(ArcSwap::from_pointee(settings)), components)
+    }
+
+    fn append_to_local_configuration(directory: &Path, content: &str) {
+        let path = directory.join("local.yaml");
+        let current = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(path, format!("{}\n{}", current, content)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloadable_settings_are_applied() {
+        let directory = configuration_directory();
+        let (settings, components) = start(&directory);
+
+        append_to_local_configuration(
+            &directory,
+            "email_client:\n  timeout_milliseconds: 500\noutbox:\n  max_attempts: 3",
+        );
+        let mut new_settings = load(&directory);
+        new_settings
+            .application
+            .rate_limit
+            .confirmation_cooldown_seconds = 7;
+        let outcome = apply_configuration(new_settings, &settings, &components);
+
+        assert_eq!(outcome.unwrap(), ReloadOutcome::Applied);
+        assert_eq!(settings.load().email_client.timeout_milliseconds, 500);
+        assert_eq!(settings.load().outbox.max_attempts, 3);
+        assert_eq!(
+            components.rate_limiter.confirmation_cooldown(),
+            Duration::from_secs(7)
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn structural_changes_are_rejected() {
+        let directory = configuration_directory();
+        let (settings, components) = start(&directory);
+
+        append_to_local_configuration(
+            &directory,
+            "email_client:\n  timeout_milliseconds: 500\n  base_url: \"http://127.0.0.1:1234\"",
+        );
+        let outcome = apply_configuration(load(&directory), &settings, &components);
+
+        assert_eq!(
+            outcome.unwrap(),
+            ReloadOutcome::Rejected {
+                structural_changes: vec!["email_client.base_url"]
+            }
+        );
+        assert_eq!(settings.load().email_client.timeout_milliseconds, 10000);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_configurations_are_not_applied() {
+        let directory = configuration_directory();
+        let (settings, components) = start(&directory);
+
+        append_to_local_configuration(&directory, "outbox:\n  max_attempts: 0");
+        let outcome = apply_configuration(load(&directory), &settings, &components);
+
+        assert_err!(outcome);
+        assert_eq!(settings.load().outbox.max_attempts, 10);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use crate::email_policy::{
+    delete_email_rule, list_email_rules, parse_rule_pattern, save_email_rule, RuleAction,
+};
+use crate::routes::error_chain_fmt;
+use crate::telemetry::{log_filter, LogFilterError, Redacted};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::fmt::Debug;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The bearer token expected by the admin endpoints, `None` disables them
+pub struct AdminToken(pub Option<Secret<String>>);
+
+#[derive(thiserror::Error)]
+pub enum AdminError {
+    #[error("Missing or invalid admin token.")]
+    Unauthorized,
+    #[error(transparent)]
+    InvalidLogFilter(LogFilterError),
+    #[error("The log filter is not available.")]
+    LogFilterUnavailable,
+    #[error("{0}")]
+    InvalidEmailRule(String),
+    #[error("There is no such email rule.")]
+    EmailRuleNotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl Debug for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminError::InvalidLogFilter(_) => StatusCode::BAD_REQUEST,
+            AdminError::LogFilterUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AdminError::InvalidEmailRule(_) => StatusCode::BAD_REQUEST,
+            AdminError::EmailRuleNotFound => StatusCode::NOT_FOUND,
+            AdminError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code()).body(self.to_string());
+
+        if let AdminError::Unauthorized = self {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(r#"Bearer realm="admin""#),
+            );
+        }
+
+        response
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct LogFilterRequest {
+    /// `EnvFilter` directives, e.g. `info,zero2prod=debug`
+    directives: String,
+    /// Go back to the previous directives after this many seconds
+    ttl_seconds: Option<u64>,
+}
+
+#[tracing::instrument(name = "Get the log filter", skip_all)]
+pub async fn get_log_filter(
+    request: HttpRequest,
+    admin_token: web::Data<AdminToken>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate(&request, &admin_token)?;
+    let log_filter = log_filter().ok_or(AdminError::LogFilterUnavailable)?;
+
+    Ok(HttpResponse::Ok().json(log_filter.status()))
+}
+
+#[tracing::instrument(
+    name = "Change the log filter",
+    skip_all,
+    fields(directives = %body.directives, ttl_seconds = ?body.ttl_seconds)
+)]
+pub async fn set_log_filter(
+    request: HttpRequest,
+    admin_token: web::Data<AdminToken>,
+    body: web::Json<LogFilterRequest>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate(&request, &admin_token)?;
+    let log_filter = log_filter().ok_or(AdminError::LogFilterUnavailable)?;
+
+    let status = log_filter
+        .set(&body.directives, body.ttl_seconds.map(Duration::from_secs))
+        .map_err(|e| match e {
+            LogFilterError::InvalidDirectives(_) => AdminError::InvalidLogFilter(e),
+            LogFilterError::Reload(_) => AdminError::UnexpectedError(e.into()),
+        })?;
+
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailRuleRequest {
+    /// `user@example.com`, `example.com` or `*.example.com`
+    pattern: String,
+    action: RuleAction,
+}
+
+#[tracing::instrument(name = "Get the email rules", skip_all)]
+pub async fn get_email_rules(
+    request: HttpRequest,
+    admin_token: web::Data<AdminToken>,
+    connection_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate(&request, &admin_token)?;
+
+    let rules = list_email_rules(&connection_pool)
+        .await
+        .context("Failed to fetch the email rules")?;
+
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+#[tracing::instrument(
+    name = "Add an email rule",
+    skip_all,
+    fields(pattern = %Redacted::email(&body.pattern), action = body.action.as_str())
+)]
+pub async fn add_email_rule(
+    request: HttpRequest,
+    admin_token: web::Data<AdminToken>,
+    connection_pool: web::Data<PgPool>,
+    body: web::Json<EmailRuleRequest>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate(&request, &admin_token)?;
+
+    let pattern = parse_rule_pattern(&body.pattern).map_err(AdminError::InvalidEmailRule)?;
+    let rule = save_email_rule(&connection_pool, &pattern, body.action)
+        .await
+        .context("Failed to save the email rule")?;
+
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+#[tracing::instrument(name = "Remove an email rule", skip_all, fields(id = %id))]
+pub async fn remove_email_rule(
+    request: HttpRequest,
+    admin_token: web::Data<AdminToken>,
+    connection_pool: web::Data<PgPool>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate(&request, &admin_token)?;
+
+    let deleted = delete_email_rule(&connection_pool, *id)
+        .await
+        .context("Failed to delete the email rule")?;
+    if !deleted {
+        return Err(AdminError::EmailRuleNotFound);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Check the `Authorization: Bearer <token>` header against the admin token
+fn authenticate(request: &HttpRequest, admin_token: &AdminToken) -> Result<(), AdminError> {
+    let Some(expected) = &admin_token.0 else {
+        tracing::warn!("Rejected an admin request, no admin token is configured");
+        return Err(AdminError::Unauthorized);
+    };
+
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AdminError::Unauthorized)?;
+
+    if constant_time_eq(provided.as_bytes(), expected.expose_secret().as_bytes()) {
+        Ok(())
+    } else {
+        Err(AdminError::Unauthorized)
+    }
+}
+
+/// Compare without leaking, through timing, how many leading bytes match
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn admin_log_filter_route() -> String {
+    String::from("/admin/log_filter")
+}
+
+pub fn admin_email_rules_route() -> String {
+    String::from("/admin/email_rules")
+}
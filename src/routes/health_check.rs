@@ -1,9 +1,58 @@
-use actix_web::HttpResponse;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
 
-pub async fn health_check() -> HttpResponse {
-    HttpResponse::Ok().finish()
+use crate::{
+    circuit_breaker::CircuitState,
+    email_client::EmailClient,
+    health::{CheckStatus, Readiness, ReadinessStatus},
+};
+
+#[derive(serde::Serialize)]
+struct HealthCheckResponse {
+    email_circuit_breaker: CircuitState,
+}
+
+pub async fn health_check(email_client: web::Data<EmailClient>) -> HttpResponse {
+    HttpResponse::Ok().json(HealthCheckResponse {
+        email_circuit_breaker: email_client.circuit_state(),
+    })
+}
+
+#[derive(serde::Serialize)]
+struct LivenessResponse {
+    status: CheckStatus,
+}
+
+/// The process is up and serving requests, nothing else is checked
+pub async fn health_live() -> HttpResponse {
+    HttpResponse::Ok().json(LivenessResponse {
+        status: CheckStatus::Up,
+    })
+}
+
+/// Whether the application can do its job, with the outcome of every check.
+/// 503 when a critical dependency is down.
+pub async fn health_ready(
+    readiness: web::Data<Readiness>,
+    connection_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+) -> HttpResponse {
+    let report = readiness.check(&connection_pool, &email_client).await;
+
+    match report.status {
+        ReadinessStatus::Ready => HttpResponse::Ok().json(report),
+        ReadinessStatus::NotReady => HttpResponse::ServiceUnavailable().json(report),
+    }
 }
 
 pub fn health_check_route() -> String {
     String::from("/health_check")
 }
+
+pub fn health_live_route() -> String {
+    String::from("/health/live")
+}
+
+pub fn health_ready_route() -> String {
+    String::from("/health/ready")
+}
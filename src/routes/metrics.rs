@@ -0,0 +1,13 @@
+use crate::metrics::metrics;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+pub async fn metrics_endpoint(connection_pool: web::Data<PgPool>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics().render(&connection_pool).await)
+}
+
+pub fn metrics_route() -> String {
+    String::from("/metrics")
+}
@@ -1,9 +1,13 @@
+mod admin;
 mod health_check;
+mod metrics;
 mod newsletter;
 mod subscriptions;
 mod subscriptions_confirm;
 
+pub use admin::*;
 pub use health_check::*;
+pub use metrics::*;
 pub use newsletter::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
@@ -1,9 +1,32 @@
-use crate::{domain::SubscriberEmail, email_client::EmailClient, routes::error_chain_fmt};
+use crate::{
+    domain::SubscriberEmail,
+    email_client::{EmailClient, EmailError, SendReceipt},
+    email_message::EmailMessage,
+    metrics::{metrics, EmailOutcome},
+    routes::error_chain_fmt,
+    suppression::suppress_subscriber,
+    telemetry::Redacted,
+};
 use actix_web::{web, HttpResponse, ResponseError};
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
 use sqlx::PgPool;
 use std::fmt::Debug;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The `kind` label of newsletter emails in the metrics
+const NEWSLETTER_EMAIL_KIND: &str = "newsletter";
+
+/// How many times we try to send an issue to a subscriber when the
+/// email provider has a transient issue
+const MAX_SEND_ATTEMPTS: u32 = 3;
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// We do not keep the request hanging for longer if the provider asks for it
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
 
 #[derive(serde::Deserialize)]
 pub struct BodyData {
@@ -17,6 +40,34 @@ pub struct Content {
     text: String,
 }
 
+/// A newsletter issue, published through the API or `zero2prod issue send`
+pub struct NewsletterIssue {
+    pub title: String,
+    pub html_content: String,
+    pub text_content: String,
+}
+
+/// What became of a published issue, see `zero2prod issue status`
+#[derive(Debug)]
+pub struct IssueStatus {
+    pub newsletter_issue_id: Uuid,
+    pub title: String,
+    /// One of `sending`, `sent` or `failed`
+    pub status: String,
+    pub delivered: i32,
+    pub suppressed: i32,
+    pub skipped: i32,
+    pub published_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct DeliveryCounts {
+    delivered: i32,
+    suppressed: i32,
+    skipped: i32,
+}
+
 struct ConfirmedSubscriber {
     email: SubscriberEmail,
 }
@@ -46,22 +97,111 @@ pub async fn publish_newsletter(
     body: web::Json<BodyData>,
     email_client: web::Data<EmailClient>,
 ) -> Result<HttpResponse, PublishError> {
-    let confirmed_subscribers = get_confirmed_subscribers(&connection_pool).await?;
+    let body = body.into_inner();
+    let issue = NewsletterIssue {
+        title: body.title,
+        html_content: body.content.html,
+        text_content: body.content.text,
+    };
+    publish_issue(&connection_pool, &email_client, &issue).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Send `issue` to every confirmed subscriber.
+///
+/// The issue is recorded in `newsletter_issues`, together with how many
+/// subscribers it reached, even if sending it fails halfway through.
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip_all,
+    fields(newsletter_issue_id = tracing::field::Empty)
+)]
+pub async fn publish_issue(
+    connection_pool: &PgPool,
+    email_client: &EmailClient,
+    issue: &NewsletterIssue,
+) -> Result<Uuid, PublishError> {
+    let newsletter_issue_id = insert_newsletter_issue(connection_pool, issue)
+        .await
+        .context("Failed to store the newsletter issue")?;
+    tracing::Span::current().record(
+        "newsletter_issue_id",
+        tracing::field::display(&newsletter_issue_id),
+    );
+
+    let mut counts = DeliveryCounts::default();
+    let outcome = send_issue(connection_pool, email_client, issue, &mut counts).await;
+    let completed = complete_newsletter_issue(
+        connection_pool,
+        newsletter_issue_id,
+        outcome.is_ok(),
+        &counts,
+    )
+    .await;
+
+    outcome?;
+    completed.context("Failed to record the outcome of the newsletter issue")?;
+
+    Ok(newsletter_issue_id)
+}
+
+async fn send_issue(
+    connection_pool: &PgPool,
+    email_client: &EmailClient,
+    issue: &NewsletterIssue,
+    counts: &mut DeliveryCounts,
+) -> Result<(), PublishError> {
+    let confirmed_subscribers = get_confirmed_subscribers(connection_pool).await?;
 
     for subscriber in confirmed_subscribers {
         match subscriber {
             Ok(subscriber) => {
-                email_client
-                    .send_email(
-                        &subscriber.email,
-                        &body.title,
-                        &body.content.html,
-                        &body.content.text,
-                    )
-                    .await
-                    .with_context(|| {
-                        format!("Failed to send newsletter issue to {}", subscriber.email)
-                    })?;
+                let message = EmailMessage::builder(subscriber.email.clone(), &issue.title)
+                    .html_body(&issue.html_content)
+                    .text_body(&issue.text_content)
+                    // Newsletters go through Postmark's broadcast stream to keep
+                    // them from affecting the reputation of transactional emails
+                    .message_stream("broadcast")
+                    .build();
+
+                match send_with_retries(email_client, &message).await {
+                    Ok(receipt) => {
+                        metrics().record_email(NEWSLETTER_EMAIL_KIND, EmailOutcome::Sent);
+                        tracing::info!(
+                            message_id = %receipt.message_id,
+                            "Sent a newsletter issue"
+                        );
+                        counts.delivered += 1;
+                    }
+                    Err(error @ EmailError::InvalidRecipient { .. }) => {
+                        metrics().record_email(NEWSLETTER_EMAIL_KIND, EmailOutcome::Failed);
+                        tracing::warn!(
+                            error.cause_chain = ?error,
+                            "Suppressing a subscriber the email provider refuses to deliver to"
+                        );
+                        suppress_subscriber(connection_pool, &subscriber.email)
+                            .await
+                            .context("Failed to suppress a subscriber")?;
+                        counts.suppressed += 1;
+                    }
+                    Err(error) => {
+                        metrics().record_email(NEWSLETTER_EMAIL_KIND, EmailOutcome::Failed);
+                        if let EmailError::Unauthorized = error {
+                            tracing::error!(
+                                "The email provider rejected our API token, \
+                                check `email_client.authorization_token`"
+                            );
+                        }
+
+                        return Err(anyhow::Error::new(error)
+                            .context(format!(
+                                "Failed to send newsletter issue to {}",
+                                Redacted::email(subscriber.email.as_ref())
+                            ))
+                            .into());
+                    }
+                }
             }
             Err(error) => {
                 tracing::warn!(
@@ -71,11 +211,49 @@ pub async fn publish_newsletter(
                     "Skipping a confirmed subscriber with email.\
                     Their stored contact details are invalid",
                 );
+                counts.skipped += 1;
             }
         }
     }
 
-    Ok(HttpResponse::Ok().finish())
+    Ok(())
+}
+
+/// Send `message`, retrying a few times if the email provider has a transient issue.
+async fn send_with_retries(
+    email_client: &EmailClient,
+    message: &EmailMessage,
+) -> Result<SendReceipt, EmailError> {
+    let mut attempt = 1;
+
+    loop {
+        match email_client.send(message).await {
+            // Retrying right away is pointless while the circuit is open
+            Err(error)
+                if error.is_transient()
+                    && !matches!(error, EmailError::CircuitOpen)
+                    && attempt < MAX_SEND_ATTEMPTS =>
+            {
+                let delay = match error {
+                    EmailError::RateLimited {
+                        retry_after: Some(retry_after),
+                    } => retry_after.min(MAX_RETRY_AFTER),
+                    _ => RETRY_DELAY * attempt,
+                };
+                tracing::warn!(
+                    error.cause_chain = ?error,
+                    attempt,
+                    "Failed to send a newsletter issue, retrying in {:?}",
+                    delay
+                );
+
+                metrics().record_email(NEWSLETTER_EMAIL_KIND, EmailOutcome::Retried);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
 #[tracing::instrument(name = "Get confirmed subscribers", skip(pool))]
@@ -108,6 +286,76 @@ async fn get_confirmed_subscribers(
     Ok(confirmed_subscribers)
 }
 
+#[tracing::instrument(name = "Store a newsletter issue", skip_all)]
+async fn insert_newsletter_issue(
+    pool: &PgPool,
+    issue: &NewsletterIssue,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id, title, text_content, html_content,
+            status, published_at
+        )
+        VALUES ($1, $2, $3, $4, 'sending', now())
+        "#,
+        newsletter_issue_id,
+        issue.title,
+        issue.text_content,
+        issue.html_content,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(newsletter_issue_id)
+}
+
+#[tracing::instrument(name = "Record the outcome of a newsletter issue", skip(pool, counts))]
+async fn complete_newsletter_issue(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    sent: bool,
+    counts: &DeliveryCounts,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, delivered = $3, suppressed = $4, skipped = $5, completed_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        if sent { "sent" } else { "failed" },
+        counts.delivered,
+        counts.suppressed,
+        counts.skipped,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get the status of a newsletter issue", skip(pool))]
+pub async fn get_issue_status(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<IssueStatus>, sqlx::Error> {
+    sqlx::query_as!(
+        IssueStatus,
+        r#"
+        SELECT newsletter_issue_id, title, status, delivered, suppressed, skipped,
+            published_at, completed_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
 pub fn publish_newsletter_route() -> String {
     String::from("/newsletter")
 }
@@ -1,20 +1,44 @@
-use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use actix_web::dev::Payload;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{
+    http::StatusCode, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
 use anyhow::Context;
 use chrono::Utc;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use sqlx::{Executor, PgPool, Postgres, Transaction};
+use sqlx::{Connection, Executor, PgPool, Postgres, Transaction};
 use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::{
-    domain::{NewSubscriber, SubscriberEmail},
-    email_client::EmailClient,
+    anti_bot::AntiBot,
+    domain::{NewSubscriber, SubscriberEmail, SubscriberName},
+    email_policy::{EmailPolicy, EmailPolicyError},
+    metrics::{acquire, metrics, SubscriptionEvent},
+    outbox::{enqueue_email, OutboxEmail},
+    rate_limit::{RateLimitDecision, RateLimiter},
+    telemetry::Redacted,
 };
 
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
-    #[error("{0}")]
-    ValidationError(String),
+    #[error("{message}")]
+    ValidationError {
+        /// `None` when the body as a whole is malformed
+        field: Option<&'static str>,
+        code: &'static str,
+        message: String,
+    },
+    #[error("Too many subscription requests, please try again later")]
+    TooManyRequests { retry_after: Duration },
+    #[error("The body is too large.")]
+    PayloadTooLarge,
+    #[error("The body must be JSON or a form.")]
+    UnsupportedMediaType,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -28,10 +52,111 @@ impl Debug for SubscribeError {
 impl ResponseError for SubscribeError {
     fn status_code(&self) -> StatusCode {
         match self {
-            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::ValidationError { .. } => StatusCode::BAD_REQUEST,
+            SubscribeError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            SubscribeError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            SubscribeError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
             SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let response = HttpResponse::build(self.status_code()).body(self.to_string());
+        self.with_retry_after(response)
+    }
+}
+
+impl SubscribeError {
+    fn invalid(field: &'static str, message: String) -> Self {
+        SubscribeError::ValidationError {
+            field: Some(field),
+            code: "invalid",
+            message,
+        }
+    }
+
+    /// Machine readable, for JSON clients
+    fn code(&self) -> &'static str {
+        match self {
+            SubscribeError::ValidationError { code, .. } => code,
+            SubscribeError::TooManyRequests { .. } => "too_many_requests",
+            SubscribeError::PayloadTooLarge => "payload_too_large",
+            SubscribeError::UnsupportedMediaType => "unsupported_media_type",
+            SubscribeError::UnexpectedError(_) => "unexpected_error",
+        }
+    }
+
+    fn with_retry_after(&self, mut response: HttpResponse) -> HttpResponse {
+        if let SubscribeError::TooManyRequests { retry_after } = self {
+            // Whole seconds, rounded up so that the retry is not limited again
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+
+        response
+    }
+
+    fn from_decision(decision: RateLimitDecision) -> Result<(), Self> {
+        match decision {
+            RateLimitDecision::Allowed => Ok(()),
+            RateLimitDecision::Limited { retry_after } => {
+                metrics().record_subscription(SubscriptionEvent::RateLimited);
+                Err(SubscribeError::TooManyRequests { retry_after })
+            }
+        }
+    }
+}
+
+/// How `POST /subscriptions` reports errors to JSON clients
+#[derive(serde::Serialize)]
+struct ErrorBody<'a> {
+    field: Option<&'a str>,
+    code: &'a str,
+    message: String,
+}
+
+/// A `SubscribeError`, answered in the format the request was sent in
+pub struct NegotiatedSubscribeError {
+    error: SubscribeError,
+    format: BodyFormat,
+}
+
+impl Display for NegotiatedSubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.error, f)
+    }
+}
+
+impl Debug for NegotiatedSubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.error, f)
+    }
+}
+
+impl ResponseError for NegotiatedSubscribeError {
+    fn status_code(&self) -> StatusCode {
+        self.error.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self.format {
+            BodyFormat::Form => self.error.error_response(),
+            BodyFormat::Json => {
+                let field = match &self.error {
+                    SubscribeError::ValidationError { field, .. } => *field,
+                    _ => None,
+                };
+                let response = HttpResponse::build(self.status_code()).json(ErrorBody {
+                    field,
+                    code: self.error.code(),
+                    message: self.error.to_string(),
+                });
+                self.error.with_retry_after(response)
+            }
+        }
+    }
 }
 
 pub struct StoreTokenError(sqlx::Error);
@@ -78,74 +203,374 @@ pub fn error_chain_fmt(
 pub struct FormData {
     pub name: String,
     pub email: String,
+    /// Honeypot: the field is hidden from people, only bots fill it in
+    #[serde(default)]
+    pub website: Option<String>,
+    /// From `GET /subscriptions/form_token`, when the form was loaded
+    #[serde(default)]
+    pub form_token: Option<String>,
+    /// Filled in by the widget of the CAPTCHA provider
+    #[serde(
+        default,
+        alias = "h-captcha-response",
+        alias = "g-recaptcha-response",
+        alias = "cf-turnstile-response"
+    )]
+    pub captcha_response: Option<String>,
+}
+
+/// Where JSON clients can send the CAPTCHA response, as for `FormData`
+const CAPTCHA_RESPONSE_FIELDS: [&str; 4] = [
+    "captcha_response",
+    "h-captcha-response",
+    "g-recaptcha-response",
+    "cf-turnstile-response",
+];
+
+/// `FormData` as JSON clients send it: a missing `name` or `email` is
+/// reported as such, a field that is not a string as malformed.
+fn form_data_from_json(json: serde_json::Value) -> Result<FormData, SubscribeError> {
+    let serde_json::Value::Object(mut fields) = json else {
+        return Err(malformed_json(None));
+    };
+
+    let name =
+        string_field(&mut fields, &["name"])?.ok_or_else(|| SubscribeError::ValidationError {
+            field: Some("name"),
+            code: "missing",
+            message: "The subscriber name is missing.".into(),
+        })?;
+    let email =
+        string_field(&mut fields, &["email"])?.ok_or_else(|| SubscribeError::ValidationError {
+            field: Some("email"),
+            code: "missing",
+            message: "The subscriber email is missing.".into(),
+        })?;
+
+    Ok(FormData {
+        name,
+        email,
+        website: string_field(&mut fields, &["website"])?,
+        form_token: string_field(&mut fields, &["form_token"])?,
+        captcha_response: string_field(&mut fields, &CAPTCHA_RESPONSE_FIELDS)?,
+    })
+}
+
+/// The first of `names` that is set, `null` is the same as missing
+fn string_field(
+    fields: &mut serde_json::Map<String, serde_json::Value>,
+    names: &[&'static str],
+) -> Result<Option<String>, SubscribeError> {
+    for name in names {
+        match fields.remove(*name) {
+            None | Some(serde_json::Value::Null) => continue,
+            Some(serde_json::Value::String(value)) => return Ok(Some(value)),
+            Some(_) => return Err(malformed_json(Some(name))),
+        }
+    }
+
+    Ok(None)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFormat {
+    /// `application/x-www-form-urlencoded`, from the HTML form
+    Form,
+    /// `application/json`, from the apps
+    Json,
+}
+
+impl BodyFormat {
+    /// JSON for `application/json`, `text/json` or `application/*+json`,
+    /// whatever their case
+    fn of(request: &HttpRequest) -> Self {
+        match request.mime_type() {
+            Ok(Some(mime))
+                if mime.subtype().as_str().eq_ignore_ascii_case("json")
+                    || mime
+                        .suffix()
+                        .is_some_and(|suffix| suffix.as_str().eq_ignore_ascii_case("json")) =>
+            {
+                BodyFormat::Json
+            }
+            _ => BodyFormat::Form,
+        }
+    }
+}
+
+/// The body of `POST /subscriptions`, a form or JSON depending on its `Content-Type`
+pub struct SubscribeBody {
+    pub form: FormData,
+    pub format: BodyFormat,
+}
+
+impl FromRequest for SubscribeBody {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        match BodyFormat::of(request) {
+            BodyFormat::Form => {
+                let form = web::Form::<FormData>::from_request(request, payload);
+                Box::pin(async move {
+                    Ok(SubscribeBody {
+                        form: form.await?.into_inner(),
+                        format: BodyFormat::Form,
+                    })
+                })
+            }
+            BodyFormat::Json => {
+                // Errors reading the body are answered by `subscribe_json_config`
+                let json = web::Json::<serde_json::Value>::from_request(request, payload);
+                Box::pin(async move {
+                    match form_data_from_json(json.await?.into_inner()) {
+                        Ok(form) => Ok(SubscribeBody {
+                            form,
+                            format: BodyFormat::Json,
+                        }),
+                        Err(error) => Err(NegotiatedSubscribeError {
+                            error,
+                            format: BodyFormat::Json,
+                        }
+                        .into()),
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// Not a JSON object, or `field` is of the wrong type.
+/// The message does not depend on the body, which can hold personal data.
+fn malformed_json(field: Option<&'static str>) -> SubscribeError {
+    SubscribeError::ValidationError {
+        field,
+        code: "malformed_body",
+        message: "The body is not a valid JSON subscription.".into(),
+    }
+}
+
+/// The `JsonConfig` of `POST /subscriptions`: bodies that cannot be read
+/// get the same structured errors as the invalid subscriptions
+pub fn subscribe_json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        // The limit of `FormConfig`, a subscription is a few short fields
+        .limit(16 * 1024)
+        .error_handler(|error, _request| {
+            let error = match error {
+                JsonPayloadError::Overflow { .. }
+                | JsonPayloadError::OverflowKnownLength { .. } => SubscribeError::PayloadTooLarge,
+                JsonPayloadError::ContentType => SubscribeError::UnsupportedMediaType,
+                error => {
+                    tracing::warn!(error = %error, "Failed to read a JSON subscription");
+                    malformed_json(None)
+                }
+            };
+
+            NegotiatedSubscribeError {
+                error,
+                format: BodyFormat::Json,
+            }
+            .into()
+        })
+}
+
+#[derive(serde::Serialize)]
+struct FormTokenResponse {
+    form_token: String,
+}
+
+/// A signed timestamp to send back with the form, see `AntiBotSettings`
+pub async fn form_token(anti_bot: web::Data<AntiBot>) -> HttpResponse {
+    match anti_bot.form_tokens() {
+        Some(form_tokens) => HttpResponse::Ok()
+            .insert_header(header::CacheControl(vec![header::CacheDirective::NoStore]))
+            .json(FormTokenResponse {
+                form_token: form_tokens.issue(),
+            }),
+        None => HttpResponse::NotFound().finish(),
+    }
 }
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, connection_pool, email_client, base_url),
+    skip(request, body, connection_pool, base_url, rate_limiter, anti_bot, email_policy),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_email = %Redacted::email(&body.form.email),
+        subscriber_name = %Redacted::name(&body.form.name),
+        format = ?body.format
     )
 )]
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    request: HttpRequest,
+    body: SubscribeBody,
     connection_pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
     base_url: web::Data<String>,
+    rate_limiter: web::Data<RateLimiter>,
+    anti_bot: web::Data<AntiBot>,
+    email_policy: web::Data<EmailPolicy>,
+) -> Result<HttpResponse, NegotiatedSubscribeError> {
+    let SubscribeBody { form, format } = body;
+
+    add_subscriber(
+        &request,
+        form,
+        &connection_pool,
+        &base_url,
+        &rate_limiter,
+        &anti_bot,
+        &email_policy,
+    )
+    .await
+    .map_err(|error| NegotiatedSubscribeError { error, format })
+}
+
+async fn add_subscriber(
+    request: &HttpRequest,
+    form: FormData,
+    connection_pool: &web::Data<PgPool>,
+    base_url: &str,
+    rate_limiter: &RateLimiter,
+    anti_bot: &AntiBot,
+    email_policy: &EmailPolicy,
 ) -> Result<HttpResponse, SubscribeError> {
-    let new_subscriber: NewSubscriber =
-        form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    let client_ip = rate_limiter.client_ip(request);
+    if let Some(client_ip) = &client_ip {
+        let decision = rate_limiter
+            .check_ip(client_ip)
+            .await
+            .context("Failed to check the rate limit of the client IP address")?;
+        SubscribeError::from_decision(decision)?;
+    }
+
+    if let Some(failure) = anti_bot
+        .check(&form, client_ip.as_deref())
+        .await
+        .context("Failed to check the submission for bots")?
+    {
+        tracing::warn!(
+            reason = failure.as_str(),
+            "Rejected a subscription from a bot"
+        );
+        metrics().record_bot_submission(failure);
+        // Bots get the same answer as everyone else, they learn nothing
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    let new_subscriber = NewSubscriber {
+        name: SubscriberName::parse(form.name).map_err(|e| SubscribeError::invalid("name", e))?,
+        email: SubscriberEmail::parse(&form.email)
+            .map_err(|e| SubscribeError::invalid("email", e))?,
+    };
+
+    email_policy
+        .check(connection_pool, &new_subscriber.email)
+        .await
+        .map_err(|e| match e {
+            EmailPolicyError::Database(_) => SubscribeError::UnexpectedError(
+                anyhow::Error::new(e).context("Failed to check the email policy"),
+            ),
+            _ => SubscribeError::ValidationError {
+                field: Some("email"),
+                code: e.code(),
+                message: e.to_string(),
+            },
+        })?;
+
+    let decision = rate_limiter
+        .check_email(&new_subscriber.email)
+        .await
+        .context("Failed to check the rate limit of the email address")?;
+    SubscribeError::from_decision(decision)?;
 
-    let subscription_token =
-        match get_subscription_token_if_subscriber_exists(&connection_pool, &new_subscriber.email)
+    let pending_subscription =
+        match get_subscription_token_if_subscriber_exists(connection_pool, &new_subscriber.email)
             .await
         {
-            Ok(Some(token)) => Some(token),
+            Ok(Some(pending_subscription)) => Some(pending_subscription),
             Ok(None) => None,
             Err(_) => None,
         };
 
-    if subscription_token.is_none() {
-        let mut transaction = connection_pool
-            .begin()
-            .await
-            .context("Failed to acquire a Postgres connection from the pool")?;
-
-        let subsriber_id = insert_subscriber(&new_subscriber, &mut transaction)
+    // Asking again for a pending subscription sends the confirmation email
+    // again, to the address we have on file, but not more often than the
+    // cooldown allows
+    let recipient = match &pending_subscription {
+        Some(pending_subscription) => SubscriberEmail::parse(&pending_subscription.email)
+            .unwrap_or_else(|_| new_subscriber.email.clone()),
+        None => new_subscriber.email.clone(),
+    };
+    if pending_subscription.is_some() {
+        let since_last_email = time_since_last_confirmation_email(connection_pool, &recipient)
             .await
-            .context("Failed to insert a new subscriber in the database")?;
-
-        let subscription_token = generate_subscription_token();
-        store_subscription_token(&mut transaction, &subsriber_id, &subscription_token)
-            .await
-            .context("Failed to store the confirmation token for a new subscriber")?;
+            .context("Failed to look for the last confirmation email")?;
+        if let Some(since_last_email) = since_last_email {
+            let cooldown = rate_limiter.confirmation_cooldown();
+            if since_last_email < cooldown {
+                SubscribeError::from_decision(RateLimitDecision::Limited {
+                    retry_after: cooldown - since_last_email,
+                })?;
+            }
+        }
+    }
 
-        transaction
-            .commit()
-            .await
-            .context("Failed to commit SQL transaction to store a new subscriber")?;
+    let mut connection = acquire(connection_pool)
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let mut transaction = connection
+        .begin()
+        .await
+        .context("Failed to start a transaction")?;
+
+    let subscription_token = match pending_subscription {
+        Some(pending_subscription) => pending_subscription.subscription_token,
+        None => {
+            let subsriber_id = match insert_subscriber(&new_subscriber, &mut transaction).await {
+                Ok(subscriber_id) => subscriber_id,
+                // Confirmed, suppressed, or subscribed by a concurrent request:
+                // they get the same answer, nothing tells them apart
+                Err(sqlx::Error::Database(error)) if error.is_unique_violation() => {
+                    tracing::info!("The subscriber already exists, nothing to do");
+                    return Ok(HttpResponse::Ok().finish());
+                }
+                Err(error) => {
+                    return Err(anyhow::Error::new(error)
+                        .context("Failed to insert a new subscriber in the database")
+                        .into())
+                }
+            };
+
+            let subscription_token = generate_subscription_token();
+            store_subscription_token(&mut transaction, &subsriber_id, &subscription_token)
+                .await
+                .context("Failed to store the confirmation token for a new subscriber")?;
+
+            subscription_token
+        }
+    };
 
-        send_confirmation_email(
-            &email_client,
-            &new_subscriber,
-            &base_url,
-            &subscription_token,
-        )
+    // The confirmation email is written to the outbox in the same transaction
+    // as the subscriber: it is delivered by the outbox relay, so the outcome
+    // of this request does not depend on the email provider being available.
+    enqueue_confirmation_email(&mut transaction, &recipient, base_url, &subscription_token)
         .await
-        .context("Failed to send a confirmation email")?;
-    } else {
-        send_confirmation_email(
-            &email_client,
-            &new_subscriber,
-            &base_url,
-            &subscription_token.unwrap(),
-        )
+        .context("Failed to enqueue a confirmation email")?;
+
+    transaction
+        .commit()
         .await
-        .context("Failed to send a confirmation email")?;
-    }
+        .context("Failed to commit SQL transaction to store a new subscriber")?;
 
-    return Ok(HttpResponse::Ok().finish());
+    metrics().record_subscription(SubscriptionEvent::Requested);
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// A subscriber who has yet to confirm, whatever the spelling of their address
+struct PendingSubscription {
+    subscription_token: String,
+    /// The address they first subscribed with
+    email: String,
 }
 
 #[tracing::instrument(
@@ -155,15 +580,16 @@ pub async fn subscribe(
 async fn get_subscription_token_if_subscriber_exists(
     connection_pool: &web::Data<PgPool>,
     email: &SubscriberEmail,
-) -> Result<Option<String>, sqlx::Error> {
-    let result = sqlx::query!(
-        "SELECT subscription_token \
+) -> Result<Option<PendingSubscription>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        PendingSubscription,
+        "SELECT subscription_token, email \
         FROM subscription_tokens \
         INNER JOIN subscriptions \
         ON subscription_tokens.subscriber_id = subscriptions.id \
-        WHERE email=$1 \
+        WHERE email_normalized=$1 \
         AND status='pending_confirmation'",
-        email.as_ref()
+        email.normalized()
     )
     .fetch_optional(connection_pool.get_ref())
     .await
@@ -172,19 +598,42 @@ async fn get_subscription_token_if_subscriber_exists(
         e
     })?;
 
-    Ok(result.map(|r| r.subscription_token))
+    Ok(result)
 }
 
 #[tracing::instrument(
-    name = "Sending a confirmation email",
-    skip(email_client, new_subscriber, base_url, subscription_token)
+    name = "Getting the time since the last confirmation email",
+    skip(connection_pool, email)
 )]
-async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: &NewSubscriber,
+async fn time_since_last_confirmation_email(
+    connection_pool: &PgPool,
+    email: &SubscriberEmail,
+) -> Result<Option<Duration>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT EXTRACT(EPOCH FROM now() - MAX(created_at))::float8 AS elapsed_seconds \
+        FROM outbox \
+        WHERE kind = 'subscription_confirmation' \
+        AND recipient = $1",
+        email.as_ref()
+    )
+    .fetch_one(connection_pool)
+    .await?;
+
+    Ok(row
+        .elapsed_seconds
+        .map(|seconds| Duration::from_secs_f64(seconds.max(0.0))))
+}
+
+#[tracing::instrument(
+    name = "Enqueueing a confirmation email",
+    skip(transaction, recipient, base_url, subscription_token)
+)]
+pub(crate) async fn enqueue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), sqlx::Error> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token
@@ -199,9 +648,19 @@ async fn send_confirmation_email(
         confirmation_link
     );
 
-    email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, &text_body)
-        .await
+    enqueue_email(
+        transaction,
+        OutboxEmail {
+            kind: "subscription_confirmation",
+            recipient,
+            subject: "Welcome!",
+            html_body: &html_body,
+            text_body: &text_body,
+        },
+    )
+    .await?;
+
+    Ok(())
 }
 
 #[tracing::instrument(
@@ -215,9 +674,10 @@ pub async fn insert_subscriber(
     let subscriber_id = Uuid::new_v4();
 
     let query = sqlx::query!(
-        "INSERT INTO subscriptions (id, email, name, subscribed_at, status) VALUES ($1, $2, $3, $4, 'pending_confirmation')",
+        "INSERT INTO subscriptions (id, email, email_normalized, name, subscribed_at, status) VALUES ($1, $2, $3, $4, $5, 'pending_confirmation')",
         subscriber_id,
         new_subscriber.email.as_ref(),
+        new_subscriber.email.normalized(),
         new_subscriber.name.as_ref(),
         Utc::now(),
     );
@@ -232,7 +692,7 @@ pub async fn insert_subscriber(
     Ok(subscriber_id)
 }
 
-fn generate_subscription_token() -> String {
+pub(crate) fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
 
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
@@ -245,7 +705,7 @@ fn generate_subscription_token() -> String {
     name = "Store subscription token in the database",
     skip(transaction, subscription_token)
 )]
-async fn store_subscription_token(
+pub(crate) async fn store_subscription_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: &Uuid,
     subscription_token: &str,
@@ -267,3 +727,7 @@ async fn store_subscription_token(
 pub fn subscriptions_route() -> String {
     String::from("/subscriptions")
 }
+
+pub fn subscriptions_form_token_route() -> String {
+    String::from("/subscriptions/form_token")
+}
@@ -1,5 +1,6 @@
+use crate::metrics::{metrics, SubscriptionEvent};
 use actix_web::{web, HttpResponse};
-use sqlx::PgPool;
+use sqlx::{PgExecutor, PgPool};
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
@@ -25,13 +26,14 @@ pub async fn confirm(
     match id {
         None => HttpResponse::Unauthorized().finish(),
         Some(subscriber_id) => {
-            if confirm_subscriber(&connection_pool, &subscriber_id)
+            if confirm_subscriber(connection_pool.get_ref(), &subscriber_id)
                 .await
                 .is_err()
             {
                 return HttpResponse::InternalServerError().finish();
             }
 
+            metrics().record_subscription(SubscriptionEvent::Confirmed);
             HttpResponse::Ok().finish()
         }
     }
@@ -60,19 +62,16 @@ pub async fn get_subscriber_id_from_token(
     Ok(result.map(|r| r.subscriber_id))
 }
 
-#[tracing::instrument(
-    name = "Mark subscriber as confirmed",
-    skip(connection_pool, subscriber_id)
-)]
-pub async fn confirm_subscriber(
-    connection_pool: &PgPool,
+#[tracing::instrument(name = "Mark subscriber as confirmed", skip(executor, subscriber_id))]
+pub async fn confirm_subscriber<'e>(
+    executor: impl PgExecutor<'e>,
     subscriber_id: &Uuid,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
         subscriber_id,
     )
-    .execute(connection_pool)
+    .execute(executor)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
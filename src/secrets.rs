@@ -0,0 +1,150 @@
+use crate::routes::error_chain_fmt;
+use secrecy::Secret;
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+/// The settings that hold a secret and can be resolved through a `SecretProvider`.
+pub const SECRET_KEYS: [&str; 7] = [
+    "database.password",
+    "email_client.authorization_token",
+    "application.admin_token",
+    "application.anti_bot.form_token_secret",
+    "application.anti_bot.captcha.secret",
+    "application.rate_limit.bucket_key_secret",
+    "telemetry.redaction.hash_key",
+];
+
+/// Where secrets that are not written in the configuration itself come from.
+///
+/// `get_configuration` asks the provider about every key in `SECRET_KEYS`
+/// (e.g. `database.password`): the value it returns, if any, takes precedence
+/// over the one found in the configuration files and `APP_*` variables.
+pub trait SecretProvider {
+    fn resolve(
+        &self,
+        key: &str,
+        configuration: &config::Config,
+    ) -> Result<Option<Secret<String>>, SecretError>;
+}
+
+#[derive(thiserror::Error)]
+pub enum SecretError {
+    #[error("Failed to read `{key}` from {path:?}")]
+    Read {
+        key: String,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("`{key}` is empty in {path:?}")]
+    Empty { key: String, path: PathBuf },
+}
+
+impl Debug for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Reads secrets from the files mounted by Docker or Kubernetes.
+///
+/// The path comes from the `{key}_file` setting, e.g. `database.password_file`
+/// in YAML or `APP_DATABASE__PASSWORD_FILE` in the environment.
+pub struct FileSecretProvider;
+
+impl SecretProvider for FileSecretProvider {
+    fn resolve(
+        &self,
+        key: &str,
+        configuration: &config::Config,
+    ) -> Result<Option<Secret<String>>, SecretError> {
+        let Ok(path) = configuration.get_string(&format!("{}_file", key)) else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+
+        let content = std::fs::read_to_string(&path).map_err(|source| SecretError::Read {
+            key: key.into(),
+            path: path.clone(),
+            source,
+        })?;
+
+        // Files written with `echo` or an editor end with a newline
+        // that is not part of the secret
+        let secret = content.trim_end_matches(['\r', '\n']);
+        if secret.is_empty() {
+            return Err(SecretError::Empty {
+                key: key.into(),
+                path,
+            });
+        }
+
+        Ok(Some(Secret::new(secret.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileSecretProvider, SecretError, SecretProvider};
+    use claims::{assert_err, assert_none};
+    use secrecy::ExposeSecret;
+    use std::path::{Path, PathBuf};
+    use uuid::Uuid;
+
+    fn secret_file(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("zero2prod-secret-{}", Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn configuration(password_file: &Path) -> config::Config {
+        config::Config::builder()
+            .set_override("database.password_file", password_file.to_str())
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn the_secret_is_read_from_the_file_without_the_trailing_newline() {
+        let path = secret_file("s3cr3t\n");
+
+        let secret = FileSecretProvider
+            .resolve("database.password", &configuration(&path))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(secret.expose_secret(), "s3cr3t");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keys_without_a_file_are_not_resolved() {
+        let configuration = config::Config::builder().build().unwrap();
+
+        let secret = FileSecretProvider
+            .resolve("database.password", &configuration)
+            .unwrap();
+
+        assert_none!(secret);
+    }
+
+    #[test]
+    fn a_missing_file_is_an_error() {
+        let path = std::env::temp_dir().join(format!("zero2prod-missing-{}", Uuid::new_v4()));
+
+        let result = FileSecretProvider.resolve("database.password", &configuration(&path));
+
+        assert!(matches!(result, Err(SecretError::Read { .. })));
+    }
+
+    #[test]
+    fn an_empty_file_is_an_error() {
+        let path = secret_file("\n");
+
+        let result = FileSecretProvider.resolve("database.password", &configuration(&path));
+
+        assert_err!(result);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Tells the HTTP server and the background workers that the process is stopping.
+///
+/// Cloning is cheap, every clone observes the same shutdown. Workers check
+/// it between two units of work: what they already started is completed.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Ask everyone to stop, it can be called more than once
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once `trigger` has been called
+    pub async fn triggered(&self) {
+        let mut receiver = self.receiver.clone();
+        // The sender lives as long as `self`, `wait_for` cannot fail
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Trigger the shutdown on `SIGTERM` (sent by orchestrators during a deploy)
+    /// or `SIGINT` (Ctrl+C).
+    pub async fn trigger_on_signal(self) -> Result<(), std::io::Error> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut terminate = signal(SignalKind::terminate())?;
+            tokio::select! {
+                _ = terminate.recv() => tracing::info!("Received SIGTERM, shutting down"),
+                outcome = tokio::signal::ctrl_c() => {
+                    outcome?;
+                    tracing::info!("Received SIGINT, shutting down")
+                }
+                _ = self.triggered() => return Ok(()),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::select! {
+                outcome = tokio::signal::ctrl_c() => {
+                    outcome?;
+                    tracing::info!("Received Ctrl+C, shutting down")
+                }
+                _ = self.triggered() => return Ok(()),
+            }
+        }
+
+        self.trigger();
+        Ok(())
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts the requests being processed, so that a shutdown can wait for them
+#[derive(Clone)]
+pub struct InFlightRequests {
+    count: Arc<watch::Sender<usize>>,
+}
+
+impl InFlightRequests {
+    pub fn new() -> Self {
+        Self {
+            count: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    /// The request is counted until the guard is dropped
+    pub fn start(&self) -> InFlightGuard {
+        self.count.send_modify(|count| *count += 1);
+        InFlightGuard {
+            count: self.count.clone(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    /// Resolves once no request is being processed
+    pub async fn drained(&self) {
+        let mut receiver = self.count.subscribe();
+        // The sender lives as long as `self`, `wait_for` cannot fail
+        let _ = receiver.wait_for(|count| *count == 0).await;
+    }
+}
+
+impl Default for InFlightRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct InFlightGuard {
+    count: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.count.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InFlightRequests, Shutdown};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn every_clone_observes_the_shutdown() {
+        let shutdown = Shutdown::new();
+        let clone = shutdown.clone();
+        assert!(!clone.is_triggered());
+
+        let waiter = tokio::spawn(async move { clone.triggered().await });
+        shutdown.trigger();
+
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("The shutdown was not observed")
+            .unwrap();
+        assert!(shutdown.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn waiting_after_the_shutdown_returns_immediately() {
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        shutdown.trigger();
+
+        tokio::time::timeout(Duration::from_millis(100), shutdown.triggered())
+            .await
+            .expect("The shutdown was not observed");
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_are_drained_when_their_guards_are_dropped() {
+        let in_flight = InFlightRequests::new();
+        let first = in_flight.start();
+        let second = in_flight.start();
+        assert_eq!(in_flight.count(), 2);
+
+        drop(first);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), in_flight.drained())
+                .await
+                .is_err()
+        );
+
+        drop(second);
+        tokio::time::timeout(Duration::from_millis(100), in_flight.drained())
+            .await
+            .expect("The requests were not drained");
+    }
+}
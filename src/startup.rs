@@ -1,107 +1,497 @@
 use crate::{
-    configuration::{DatabaseSettings, Settings},
+    configuration::{
+        ApplicationSettings, DatabaseSettings, InvalidSettings, Settings, TlsSettings,
+    },
     email_client::{subscriptions_confirm_route, EmailClient},
+    health::{Heartbeats, Readiness},
+    metrics::metrics,
+    reload::Components,
     routes::{
-        confirm, health_check, health_check_route, publish_newsletter, publish_newsletter_route,
-        subscribe, subscriptions_route,
+        add_email_rule, admin_email_rules_route, admin_log_filter_route, confirm, error_chain_fmt,
+        form_token, get_email_rules, get_log_filter, health_check, health_check_route, health_live,
+        health_live_route, health_ready, health_ready_route, metrics_endpoint, metrics_route,
+        publish_newsletter, publish_newsletter_route, remove_email_rule, set_log_filter, subscribe,
+        subscribe_json_config, subscriptions_form_token_route, subscriptions_route, AdminToken,
+    },
+    shutdown::{InFlightRequests, Shutdown},
+    telemetry::RedactedRootSpanBuilder,
+    tls::{
+        redirect_to_https, run_certificate_reloader_until_stopped, CertificateResolver, TlsError,
     },
 };
 
-use actix_web::{dev::Server, web, App, HttpServer};
+use actix_web::dev::{Server, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::{web, App, HttpServer};
+use sqlx::migrate::{MigrateError, Migrator};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
+use std::fmt::Debug;
+use std::future::Future;
 use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing_actix_web::TracingLogger;
 
+/// How long to wait for in-use database connections once the HTTP server is stopped
+const POOL_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long idle keep-alive connections are kept once in-flight requests are done
+const IDLE_CONNECTIONS_TIMEOUT: u64 = 1;
+
+/// The migrations in `./migrations`, embedded in the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(thiserror::Error)]
+pub enum BuildError {
+    #[error(transparent)]
+    InvalidConfiguration(#[from] InvalidSettings),
+    #[error("Failed to bind to {address}")]
+    Bind {
+        address: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Failed to start the HTTP server")]
+    Server(#[source] std::io::Error),
+    #[error("Failed to migrate the database")]
+    Migration(#[from] MigrateError),
+    #[error("Failed to load the TLS certificate")]
+    Tls(#[from] TlsError),
+}
+
+impl Debug for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
 pub struct Application {
     server: Server,
     port: u16,
+    /// Only set when the metrics are served on their own port
+    metrics_server: Option<(Server, u16)>,
+    /// Only set when TLS is enabled with a redirect port
+    redirect_server: Option<(Server, u16)>,
+    certificate_resolver: Option<Arc<CertificateResolver>>,
+    components: Components,
+    heartbeats: Arc<Heartbeats>,
+    connection_pool: PgPool,
+    in_flight_requests: InFlightRequests,
+    shutdown: Shutdown,
+    grace_period: Duration,
 }
 
 impl Application {
-    pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
+    pub async fn build(configuration: Settings) -> Result<Self, BuildError> {
+        configuration.validate()?;
+
         // Database
         let connection_pool = get_connection_pool(&configuration.database);
+        if configuration.database.migrate_on_startup {
+            migrate(&connection_pool).await?;
+        }
 
-        // Email Client
-        let sender_email = configuration
-            .email_client
-            .sender_email()
-            .expect("Invalid sender email address.");
-
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
-        );
+        // Shutdown
+        let shutdown = Shutdown::new();
+        let grace_period = configuration.application.shutdown_grace_period();
+        let in_flight_requests = InFlightRequests::new();
 
-        // Application
-        let address = format!(
-            "{}:{}",
-            configuration.application.host, configuration.application.port
-        );
+        // Email client, rate limiter, anti-bot checks and email policy
+        let components = Components::new(&configuration, connection_pool.clone())?;
 
-        let listener = TcpListener::bind(address).expect(&format!(
-            "Failed to bind to port {}",
-            configuration.application.port
+        // Health
+        let heartbeats = Arc::new(Heartbeats::new(
+            configuration.health.worker_heartbeat_timeout(),
         ));
+        let readiness = Readiness::new(
+            heartbeats.clone(),
+            configuration.health.email_provider_cache(),
+        );
 
-        let port = listener.local_addr().unwrap().port();
+        // Metrics
+        let metrics_server = match configuration.application.metrics_port {
+            Some(metrics_port) => {
+                let (listener, port) = bind(&configuration.application.host, metrics_port)?;
+                let server = run_metrics(listener, connection_pool.clone(), grace_period)
+                    .map_err(BuildError::Server)?;
+                Some((server, port))
+            }
+            None => None,
+        };
+
+        // TLS
+        let certificate_resolver = configuration
+            .application
+            .tls
+            .as_ref()
+            .map(CertificateResolver::new)
+            .transpose()?
+            .map(Arc::new);
+
+        // Application
+        let (listener, port) = bind(
+            &configuration.application.host,
+            configuration.application.port,
+        )?;
+        let redirect_server = match &configuration.application.tls {
+            Some(TlsSettings {
+                redirect_port: Some(redirect_port),
+                ..
+            }) => {
+                let (listener, redirect_port) =
+                    bind(&configuration.application.host, *redirect_port)?;
+                let server =
+                    run_https_redirect(listener, port, grace_period).map_err(BuildError::Server)?;
+                Some((server, redirect_port))
+            }
+            _ => None,
+        };
         let server = run(
             listener,
-            connection_pool,
-            email_client,
-            configuration.application.base_url,
-        )?;
+            connection_pool.clone(),
+            components.clone(),
+            readiness,
+            configuration.application,
+            certificate_resolver.as_ref().map(|r| r.server_config()),
+            in_flight_requests.clone(),
+        )
+        .map_err(BuildError::Server)?;
 
-        Ok(Self { server, port })
+        Ok(Self {
+            server,
+            port,
+            metrics_server,
+            redirect_server,
+            certificate_resolver,
+            components,
+            heartbeats,
+            connection_pool,
+            in_flight_requests,
+            shutdown,
+            grace_period,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// The port serving `/metrics`, if it is not the application port
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_server.as_ref().map(|(_, port)| *port)
+    }
+
+    /// The port redirecting plain HTTP requests to HTTPS, if any
+    pub fn redirect_port(&self) -> Option<u16> {
+        self.redirect_server.as_ref().map(|(_, port)| *port)
+    }
+
+    /// The email client used by the API, background workers should share it
+    /// so that they all see the same circuit breaker state.
+    pub fn email_client(&self) -> Arc<EmailClient> {
+        self.components.email_client.clone()
+    }
+
+    /// What applies the settings reloaded by `run_reloader_until_stopped`
+    pub fn components(&self) -> Components {
+        self.components.clone()
+    }
+
+    /// Where background workers report that they are making progress,
+    /// see `/health/ready`
+    pub fn heartbeats(&self) -> Arc<Heartbeats> {
+        self.heartbeats.clone()
+    }
+
+    /// Triggering it stops the application, background workers should
+    /// watch it as well.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Serve requests until `shutdown` is triggered.
+    ///
+    /// New connections are not accepted anymore straight away, in-flight
+    /// requests get the grace period to complete. The servers are stopped
+    /// once they are done and the connection pool is closed last.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let mut servers = vec![self.server];
+        servers.extend(self.metrics_server.map(|(server, _)| server));
+        servers.extend(self.redirect_server.map(|(server, _)| server));
+
+        let handles: Vec<_> = servers.iter().map(Server::handle).collect();
+        let shutdown = self.shutdown.clone();
+        let in_flight_requests = self.in_flight_requests.clone();
+        let grace_period = self.grace_period;
+        tokio::spawn(async move {
+            shutdown.triggered().await;
+            tracing::info!("Stopping the HTTP server");
+            for handle in &handles {
+                handle.pause().await;
+            }
+            // Stopping a server while requests are in flight can drop their
+            // connections before the workers notice the stop, we wait for
+            // the requests ourselves instead.
+            if tokio::time::timeout(grace_period, in_flight_requests.drained())
+                .await
+                .is_err()
+            {
+                tracing::warn!(
+                    in_flight_requests = in_flight_requests.count(),
+                    "Some requests did not complete within the grace period, they were cut off"
+                );
+            }
+            for handle in handles {
+                handle.stop(true).await;
+            }
+        });
+        if let Some(certificate_resolver) = self.certificate_resolver {
+            let certificate_reloader =
+                run_certificate_reloader_until_stopped(certificate_resolver, self.shutdown.clone());
+            tokio::spawn(async move {
+                if let Err(e) = certificate_reloader.await {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        "The TLS certificate will not be reloaded"
+                    );
+                }
+            });
+        }
+
+        let servers: Vec<_> = servers.into_iter().map(tokio::spawn).collect();
+        let mut outcome = Ok(());
+        for server in servers {
+            let stopped = server
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            outcome = outcome.and(stopped);
+        }
+
+        // Requests cut off at the end of the grace period may never give
+        // their connection back
+        if tokio::time::timeout(POOL_CLOSE_TIMEOUT, self.connection_pool.close())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Some database connections were still in use, they were dropped");
+        }
+        tracing::info!("The HTTP server has stopped");
+
+        outcome
     }
 }
 
+fn bind(host: &str, port: u16) -> Result<(TcpListener, u16), BuildError> {
+    let address = format!("{}:{}", host, port);
+
+    let listener = TcpListener::bind(&address).map_err(|source| BuildError::Bind {
+        address: address.clone(),
+        source,
+    })?;
+    let port = listener
+        .local_addr()
+        .map_err(|source| BuildError::Bind { address, source })?
+        .port();
+
+    Ok((listener, port))
+}
+
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
     PgPoolOptions::new().connect_lazy_with(configuration.with_db())
 }
 
+/// Apply the migrations that are missing from the database.
+///
+/// The migrator holds a Postgres advisory lock while it runs: instances
+/// starting at the same time apply them one after the other, the later
+/// ones find nothing left to do.
+#[tracing::instrument(name = "Migrate the database", skip_all)]
+pub async fn migrate(connection_pool: &PgPool) -> Result<(), MigrateError> {
+    MIGRATOR.run(connection_pool).await?;
+    tracing::info!("The database schema is up to date");
+
+    Ok(())
+}
+
 pub fn run(
     listener: TcpListener,
     connection_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    components: Components,
+    readiness: Readiness,
+    settings: ApplicationSettings,
+    tls: Option<rustls::ServerConfig>,
+    in_flight_requests: InFlightRequests,
 ) -> Result<Server, std::io::Error> {
+    // `/metrics` is served on the application port unless it has its own
+    let serve_metrics = settings.metrics_port.is_none();
     let connection_pool = web::Data::new(connection_pool);
-    let email_client = web::Data::new(email_client);
-    let base_url = web::Data::new(base_url);
+    let email_client = web::Data::from(components.email_client);
+    let base_url = web::Data::new(settings.base_url);
+    let admin_token = web::Data::new(AdminToken(settings.admin_token));
+    let readiness = web::Data::new(readiness);
+    let rate_limiter = web::Data::from(components.rate_limiter);
+    let anti_bot = web::Data::from(components.anti_bot);
+    let email_policy = web::Data::from(components.email_policy);
 
     let server = HttpServer::new(move || {
-        App::new()
-            .wrap(TracingLogger::default())
+        let in_flight_requests = in_flight_requests.clone();
+        let app = App::new()
+            .wrap_fn(move |request, service| {
+                let guard = in_flight_requests.start();
+                let response = service.call(request);
+                async move {
+                    let response = response.await;
+                    drop(guard);
+                    response
+                }
+            })
+            .wrap_fn(record_http_metrics)
+            .wrap(TracingLogger::<RedactedRootSpanBuilder>::new())
             .route(&health_check_route(), web::get().to(health_check))
-            .route(&subscriptions_route(), web::post().to(subscribe))
+            .route(&health_live_route(), web::get().to(health_live))
+            .route(&health_ready_route(), web::get().to(health_ready))
+            .service(
+                web::resource(subscriptions_route())
+                    .app_data(subscribe_json_config())
+                    .route(web::post().to(subscribe)),
+            )
+            .route(&subscriptions_form_token_route(), web::get().to(form_token))
             .route(&subscriptions_confirm_route(), web::get().to(confirm))
             .route(
                 &publish_newsletter_route(),
                 web::post().to(publish_newsletter),
             )
+            .service(
+                web::resource(admin_log_filter_route())
+                    .route(web::get().to(get_log_filter))
+                    .route(web::put().to(set_log_filter)),
+            )
+            .service(
+                web::resource(admin_email_rules_route())
+                    .route(web::get().to(get_email_rules))
+                    .route(web::post().to(add_email_rule)),
+            )
+            .route(
+                &format!("{}/{{id}}", admin_email_rules_route()),
+                web::delete().to(remove_email_rule),
+            )
             .app_data(connection_pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(admin_token.clone())
+            .app_data(readiness.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(anti_bot.clone())
+            .app_data(email_policy.clone());
+
+        if serve_metrics {
+            app.route(&metrics_route(), web::get().to(metrics_endpoint))
+        } else {
+            app
+        }
+    })
+    // Shutdowns are coordinated by `Application::run_until_stopped`, the
+    // grace period is over by the time the server is stopped
+    .disable_signals()
+    .shutdown_timeout(IDLE_CONNECTIONS_TIMEOUT);
+
+    let server = match tls {
+        Some(tls) => server.listen_rustls_0_21(listener, tls)?,
+        None => server.listen(listener)?,
+    };
+
+    Ok(server.run())
+}
+
+/// Serve `/metrics` on its own, e.g. on a port that is only reachable internally
+pub fn run_metrics(
+    listener: TcpListener,
+    connection_pool: PgPool,
+    grace_period: Duration,
+) -> Result<Server, std::io::Error> {
+    let connection_pool = web::Data::new(connection_pool);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .route(&metrics_route(), web::get().to(metrics_endpoint))
+            .app_data(connection_pool.clone())
+    })
+    .disable_signals()
+    .shutdown_timeout(grace_period.as_secs())
+    .listen(listener)?
+    .run();
+
+    Ok(server)
+}
+
+/// Redirect every plain HTTP request to the HTTPS listener on `https_port`
+pub fn run_https_redirect(
+    listener: TcpListener,
+    https_port: u16,
+    grace_period: Duration,
+) -> Result<Server, std::io::Error> {
+    let https_port = web::Data::new(https_port);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .default_service(web::to(redirect_to_https))
+            .app_data(https_port.clone())
     })
+    .disable_signals()
+    .shutdown_timeout(grace_period.as_secs())
     .listen(listener)?
     .run();
 
     Ok(server)
 }
 
+/// Count every request and how long it took, labelled by the route that matched it
+fn record_http_metrics<S, B>(
+    request: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let started_at = Instant::now();
+    let method = method_label(request.method());
+    let response = service.call(request);
+
+    async move {
+        let response = response.await?;
+        // Unmatched paths are lumped together to keep the number of series bounded
+        let route = response
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        metrics().record_http_request(
+            method,
+            &route,
+            response.status().as_u16(),
+            started_at.elapsed(),
+        );
+
+        Ok(response)
+    }
+}
+
+/// Clients pick the method, any non-standard one would add series
+fn method_label(method: &Method) -> &'static str {
+    match method.as_str() {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "DELETE" => "DELETE",
+        "CONNECT" => "CONNECT",
+        "OPTIONS" => "OPTIONS",
+        "TRACE" => "TRACE",
+        "PATCH" => "PATCH",
+        _ => "other",
+    }
+}
+
 pub struct PostRequestHeader {
     pub name: String,
     pub value: String,
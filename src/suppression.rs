@@ -0,0 +1,26 @@
+use crate::domain::SubscriberEmail;
+use sqlx::PgExecutor;
+
+/// Stop emailing an address the email provider refuses to deliver to
+/// (hard bounce, spam complaint, ...).
+///
+/// Suppressed subscribers are not confirmed anymore, so they are left out
+/// of newsletter issues.
+#[tracing::instrument(name = "Suppress a subscriber", skip(executor, email))]
+pub async fn suppress_subscriber<'e>(
+    executor: impl PgExecutor<'e>,
+    email: &SubscriberEmail,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'suppressed' WHERE email_normalized = $1"#,
+        email.normalized()
+    )
+    .execute(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
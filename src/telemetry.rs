@@ -1,7 +1,33 @@
-use tracing::{subscriber::set_global_default, Subscriber};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Version;
+use actix_web::HttpMessage;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{TraceContextExt, TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use secrecy::{ExposeSecret, Secret};
+use serde_json::Value;
+use sha2::Sha256;
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{subscriber::set_global_default, Span, Subscriber};
+use tracing_actix_web::{DefaultRootSpanBuilder, RequestId, RootSpanBuilder};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, reload, EnvFilter, Registry};
+
+/// The filter of the global subscriber, set by `init_subscriber`
+static LOG_FILTER: OnceLock<LogFilterHandle> = OnceLock::new();
 
 /// Compose multiple layers into a `tracing`'s subscriber.
 ///
@@ -13,11 +39,20 @@ use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Regis
 /// We need to explicitly call out that the returned subscriber is
 /// `Send` and `Sync` to make it possible to pass it to `init_subscriber`
 /// later on.
+///
+/// The returned `LogFilterHandle` changes the filter of the subscriber
+/// while it is running.
+///
+/// Spans are also exported to OpenTelemetry when a `tracer` is provided,
+/// see `otlp_tracer`.
+///
+/// Personal data is redacted from the logs written to `sink`, see `RedactingWriter`.
 pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
     sink: Sink,
-) -> impl Subscriber + Sync + Send
+    tracer: Option<Tracer>,
+) -> (impl Subscriber + Sync + Send, LogFilterHandle)
 where
     // This syntax is a higher-ranked trait bound (HRTB)
     // which means Sink implements the `MakeWriter`
@@ -28,21 +63,945 @@ where
     // if the RUST_LOG environment variable has not been set.
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    let directives = env_filter.to_string();
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    let log_filter = LogFilterHandle::new(handle, directives);
+    let formatting_layer = BunyanFormattingLayer::new(name, RedactingWriter::new(sink));
 
     // The `with` method is provided by `SubscriberExt`, an extension
     // trait for `Subscriber` exposed by `tracing_subscriber`
-    Registry::default()
+    let subscriber = Registry::default()
         .with(env_filter)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
         .with(JsonStorageLayer)
-        .with(formatting_layer)
+        .with(formatting_layer);
+
+    (subscriber, log_filter)
 }
 
-pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
+pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync, log_filter: LogFilterHandle) {
     // Redirect all `log`'s events to our subscriber
     LogTracer::init().expect("Failed to set logger");
 
     // `set_global_default` is used to specify what subscriber
     // should be used to process spans
     set_global_default(subscriber).expect("Failed to set subscriber");
+
+    // Read and write W3C `traceparent` headers, for incoming requests
+    // (see `TracingLogger`) and outgoing ones (see `trace_context_headers`)
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    LOG_FILTER
+        .set(log_filter)
+        .unwrap_or_else(|_| panic!("The log filter has already been set"));
+}
+
+/// Export spans over OTLP/HTTP to the collector configured by the standard
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` (or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`)
+/// environment variable. Returns `None` when neither is set.
+///
+/// The `TracerProvider` must be kept around and shut down before exiting
+/// to flush the spans that have not been exported yet.
+pub fn otlp_tracer_from_env(
+    service_name: &str,
+) -> Result<Option<(TracerProvider, Tracer)>, TraceError> {
+    let endpoint = match (
+        std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"),
+        std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT"),
+    ) {
+        (Ok(endpoint), _) => endpoint,
+        (_, Ok(endpoint)) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        _ => return Ok(None),
+    };
+
+    otlp_tracer(service_name, &endpoint).map(Some)
+}
+
+/// Export spans over OTLP/HTTP to `endpoint`, e.g. `http://localhost:4318/v1/traces`.
+/// Must be called from a Tokio runtime.
+pub fn otlp_tracer(
+    service_name: &str,
+    endpoint: &str,
+) -> Result<(TracerProvider, Tracer), TraceError> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint)
+        .build_span_exporter()?;
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_config(
+            opentelemetry_sdk::trace::Config::default().with_resource(Resource::new([
+                KeyValue::new("service.name", service_name.to_string()),
+            ])),
+        )
+        .build();
+    let tracer = provider.tracer(service_name.to_string());
+
+    Ok((provider, tracer))
+}
+
+/// The W3C `traceparent` header for the current span, to continue
+/// the trace in the services we call.
+///
+/// Empty unless the subscriber exports spans to OpenTelemetry.
+pub fn trace_context_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let context = tracing::Span::current().context();
+
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+
+    headers
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// The root span of every request, as `TracingLogger::default()` builds it
+/// except that `http.target` is only the path.
+///
+/// Query strings carry personal data and secrets, e.g. the subscription
+/// token of a confirmation link, they never reach the logs.
+pub struct RedactedRootSpanBuilder;
+
+impl RootSpanBuilder for RedactedRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let connection_info = request.connection_info();
+        let route = request
+            .match_pattern()
+            .unwrap_or_else(|| "default".to_string());
+        let method = request.method().as_str();
+        // Set by `TracingLogger` before the span is built
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+
+        let span = tracing::info_span!(
+            "HTTP request",
+            http.method = %method,
+            http.route = %route,
+            http.flavor = http_flavor(request.version()),
+            http.scheme = %connection_info.scheme(),
+            http.host = %connection_info.host(),
+            http.client_ip = %connection_info.realip_remote_addr().unwrap_or(""),
+            http.user_agent = %request
+                .headers()
+                .get("User-Agent")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or(""),
+            http.target = %request.path(),
+            http.status_code = tracing::field::Empty,
+            otel.name = %format!("HTTP {} {}", method, route),
+            otel.kind = "server",
+            otel.status_code = tracing::field::Empty,
+            trace_id = tracing::field::Empty,
+            request_id = %request_id,
+            exception.message = tracing::field::Empty,
+            exception.details = tracing::field::Empty,
+        );
+
+        // Continue the trace of the caller, if it sent a `traceparent` header
+        let parent_context = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        span.set_parent(parent_context);
+        let trace_id = span.context().span().span_context().trace_id();
+        span.record(
+            "trace_id",
+            tracing::field::display(format!("{:032x}", trace_id)),
+        );
+
+        span
+    }
+
+    fn on_request_end<B: MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, actix_web::Error>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+fn http_flavor(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_11 => "1.1",
+        Version::HTTP_2 => "2.0",
+        Version::HTTP_3 => "3.0",
+        _ => "unknown",
+    }
+}
+
+struct HeaderExtractor<'a>(&'a actix_web::http::header::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Written in front of hashed values, e.g. `hmac:3f2a9c0d41e7b856`
+const HASH_PREFIX: &str = "hmac:";
+
+/// How personal data is written to logs and traces, see `Redacted`.
+///
+/// Starts out redacting everything: nothing leaks before the configuration
+/// has been loaded.
+static REDACTION_POLICY: RwLock<RedactionPolicy> = RwLock::new(RedactionPolicy::DEFAULT);
+
+/// Replace the redaction policy, e.g. after loading the configuration
+pub fn set_redaction_policy(policy: RedactionPolicy) {
+    *REDACTION_POLICY.write().unwrap() = policy;
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RedactionPolicy {
+    /// Write personal data as is.
+    /// Only allowed in the local environment, see `Settings::validate`.
+    pub disabled: bool,
+    pub email: RedactionStrategy,
+    pub name: RedactionStrategy,
+    pub token: RedactionStrategy,
+    /// The key of `RedactionStrategy::Hash`, required to use it
+    pub hash_key: Option<Secret<String>>,
+}
+
+impl RedactionPolicy {
+    const DEFAULT: Self = Self {
+        disabled: false,
+        email: RedactionStrategy::Hash,
+        name: RedactionStrategy::Mask,
+        token: RedactionStrategy::Mask,
+        hash_key: None,
+    };
+
+    /// Whether any kind of personal data is hashed, which needs a `hash_key`
+    pub fn hashes(&self) -> bool {
+        !self.disabled && [self.email, self.name, self.token].contains(&RedactionStrategy::Hash)
+    }
+
+    fn strategy(&self, kind: PersonalData) -> RedactionStrategy {
+        match kind {
+            PersonalData::Email => self.email,
+            PersonalData::Name => self.name,
+            PersonalData::Token => self.token,
+        }
+    }
+}
+
+impl Default for RedactionPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionStrategy {
+    /// The first 16 hex digits of the HMAC-SHA256 of the value with `hash_key`:
+    /// log lines about the same subscriber can be correlated without revealing
+    /// who it is, and without the key candidate values cannot be checked
+    Hash,
+    /// Keep the first character (and the domain of an email), e.g. `u***@gmail.com`
+    Mask,
+}
+
+#[derive(Clone, Copy)]
+enum PersonalData {
+    Email,
+    Name,
+    Token,
+}
+
+/// Personal data, formatted according to the redaction policy.
+///
+/// Use it for every span field or log event that could identify someone:
+/// ```ignore
+/// #[tracing::instrument(fields(subscriber_email = %Redacted::email(&form.email)))]
+/// ```
+/// Values are redacted before they reach any layer, they never
+/// end up in the logs nor in the exported traces.
+///
+/// The logs are redacted anyway (see `RedactingWriter`), `Redacted` is
+/// what keeps personal data out of messages and exported traces.
+pub struct Redacted<'a> {
+    kind: PersonalData,
+    value: &'a str,
+}
+
+impl<'a> Redacted<'a> {
+    pub fn email(value: &'a str) -> Self {
+        Self {
+            kind: PersonalData::Email,
+            value,
+        }
+    }
+
+    pub fn name(value: &'a str) -> Self {
+        Self {
+            kind: PersonalData::Name,
+            value,
+        }
+    }
+
+    pub fn token(value: &'a str) -> Self {
+        Self {
+            kind: PersonalData::Token,
+            value,
+        }
+    }
+}
+
+impl std::fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(&REDACTION_POLICY.read().unwrap(), f)
+    }
+}
+
+impl Redacted<'_> {
+    fn write(&self, policy: &RedactionPolicy, f: &mut impl std::fmt::Write) -> std::fmt::Result {
+        if policy.disabled {
+            return f.write_str(self.value);
+        }
+
+        match policy.strategy(self.kind) {
+            RedactionStrategy::Hash => match &policy.hash_key {
+                Some(key) => {
+                    let mut mac = HmacSha256::new_from_slice(key.expose_secret().as_bytes())
+                        .expect("HMAC accepts keys of any length");
+                    mac.update(self.value.as_bytes());
+                    f.write_str(HASH_PREFIX)?;
+                    mac.finalize().into_bytes()[..8]
+                        .iter()
+                        .try_for_each(|byte| write!(f, "{:02x}", byte))
+                }
+                // Only until the configuration is loaded, `Settings::validate`
+                // requires a key to hash
+                None => f.write_str("***"),
+            },
+            RedactionStrategy::Mask => {
+                let (local_part, domain) = match self.kind {
+                    PersonalData::Email => match self.value.rsplit_once('@') {
+                        Some((local_part, domain)) => (local_part, Some(domain)),
+                        None => (self.value, None),
+                    },
+                    PersonalData::Name | PersonalData::Token => (self.value, None),
+                };
+                if let Some(first) = local_part.chars().next() {
+                    f.write_char(first)?;
+                }
+                f.write_str("***")?;
+                if let Some(domain) = domain {
+                    write!(f, "@{}", domain)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Redacted<'_> {
+    /// Whether the value is already the output of `Redacted` for the policy
+    fn is_redacted(&self, policy: &RedactionPolicy) -> bool {
+        match policy.strategy(self.kind) {
+            RedactionStrategy::Hash => {
+                self.value == "***"
+                    || self.value.strip_prefix(HASH_PREFIX).is_some_and(|digest| {
+                        digest.len() == 16 && digest.bytes().all(|b| b.is_ascii_hexdigit())
+                    })
+            }
+            // Masking a masked value does not change it
+            RedactionStrategy::Mask => false,
+        }
+    }
+
+    fn redact(&self, policy: &RedactionPolicy) -> String {
+        let mut output = String::new();
+        if self.is_redacted(policy) {
+            output.push_str(self.value);
+        } else {
+            // Writing to a `String` does not fail
+            let _ = self.write(policy, &mut output);
+        }
+        output
+    }
+}
+
+/// The kind of personal data a field holds, going by its name:
+/// `email`, `subscriber_email` or `error.email` all hold an email.
+fn personal_data(field: &str) -> Option<PersonalData> {
+    match field.rsplit(['.', '_']).next() {
+        Some("email") => Some(PersonalData::Email),
+        Some("name") => Some(PersonalData::Name),
+        Some("token") => Some(PersonalData::Token),
+        _ => None,
+    }
+}
+
+/// The sink of the JSON logs, with personal data redacted according to the
+/// redaction policy: the fields named like personal data (see `personal_data`)
+/// and the values of such fields in the `Debug` representation of a struct,
+/// e.g. the `form` argument of a `#[tracing::instrument]` that does not skip it.
+///
+/// It catches what was recorded without `Redacted`, values that already went
+/// through it are left as they are.
+pub struct RedactingWriter<Sink> {
+    sink: Sink,
+}
+
+impl<Sink> RedactingWriter<Sink> {
+    pub fn new(sink: Sink) -> Self {
+        Self { sink }
+    }
+}
+
+impl<'a, Sink: MakeWriter<'a>> MakeWriter<'a> for RedactingWriter<Sink> {
+    type Writer = RedactingWriter<Sink::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter::new(self.sink.make_writer())
+    }
+
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        RedactingWriter::new(self.sink.make_writer_for(meta))
+    }
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    /// `BunyanFormattingLayer` writes every record at once, as a line of JSON
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match redact_record(&REDACTION_POLICY.read().unwrap(), buf) {
+            Some(record) => self.sink.write_all(&record)?,
+            None => self.sink.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// The record with its personal data redacted, `None` if there was nothing to redact
+fn redact_record(policy: &RedactionPolicy, record: &[u8]) -> Option<Vec<u8>> {
+    if policy.disabled {
+        return None;
+    }
+
+    let mut fields: serde_json::Map<String, Value> = serde_json::from_slice(record).ok()?;
+    let mut redacted_any = false;
+    for (field, value) in fields.iter_mut() {
+        let Value::String(text) = value else {
+            continue;
+        };
+        // The name of the application and the name of the span
+        if field == "name" || field.starts_with("otel.") {
+            continue;
+        }
+        let redacted = match personal_data(field) {
+            Some(kind) => Redacted { kind, value: text }.redact(policy),
+            None => match redact_debug(policy, text) {
+                Some(redacted) => redacted,
+                None => continue,
+            },
+        };
+        if redacted != *text {
+            *text = redacted;
+            redacted_any = true;
+        }
+    }
+
+    if !redacted_any {
+        return None;
+    }
+    let mut record = serde_json::to_vec(&fields).ok()?;
+    record.push(b'\n');
+    Some(record)
+}
+
+/// `text` with the string literals in the values of the fields named like
+/// personal data redacted, e.g. `FormData { name: "l***", email: "hmac:…" }`,
+/// `None` if there were none.
+fn redact_debug(policy: &RedactionPolicy, text: &str) -> Option<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut redacted_any = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                output.push('"');
+                output.push_str(&string_literal(&mut chars));
+                output.push('"');
+            }
+            ':' if chars.peek() == Some(&' ') => {
+                let field_start = output
+                    .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .map_or(0, |i| i + 1);
+                output.push(':');
+                let Some(kind) = personal_data(&output[field_start..output.len() - 1]) else {
+                    continue;
+                };
+
+                // The value ends with the next field or with its struct
+                let mut depth = 0;
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        ',' if depth == 0 => break,
+                        ')' | '}' | ']' if depth == 0 => break,
+                        ')' | '}' | ']' => depth -= 1,
+                        '(' | '{' | '[' => depth += 1,
+                        _ => {}
+                    }
+                    chars.next();
+                    if c == '"' {
+                        let value = string_literal(&mut chars);
+                        output.push('"');
+                        output.push_str(
+                            &Redacted {
+                                kind,
+                                value: &value,
+                            }
+                            .redact(policy),
+                        );
+                        output.push('"');
+                        redacted_any = true;
+                    } else {
+                        output.push(c);
+                    }
+                }
+            }
+            c => output.push(c),
+        }
+    }
+
+    redacted_any.then_some(output)
+}
+
+/// The rest of a string literal, as written by `Debug`, up to its closing quote
+fn string_literal(chars: &mut impl Iterator<Item = char>) -> String {
+    let mut literal = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => {
+                literal.push(c);
+                literal.extend(chars.next());
+            }
+            c => literal.push(c),
+        }
+    }
+    literal
+}
+
+/// The handle on the filter of the global subscriber,
+/// `None` until `init_subscriber` has been called.
+pub fn log_filter() -> Option<&'static LogFilterHandle> {
+    LOG_FILTER.get()
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LogFilterError {
+    #[error("Invalid log filter directives: {0}")]
+    InvalidDirectives(String),
+    #[error("Failed to replace the log filter")]
+    Reload(#[from] reload::Error),
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct LogFilterStatus {
+    /// The directives currently in effect, e.g. `info,zero2prod=debug`
+    pub directives: String,
+    /// When a temporary filter goes back to the previous directives
+    pub revert_at: Option<DateTime<Utc>>,
+}
+
+/// Changes the filter of a running subscriber.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+    state: Arc<Mutex<LogFilterState>>,
+}
+
+struct LogFilterState {
+    /// What we go back to once a temporary filter expires
+    baseline: String,
+    revert: Option<ScheduledRevert>,
+}
+
+struct ScheduledRevert {
+    at: DateTime<Utc>,
+    task: JoinHandle<()>,
+}
+
+impl LogFilterHandle {
+    fn new(handle: reload::Handle<EnvFilter, Registry>, directives: String) -> Self {
+        Self {
+            handle,
+            state: Arc::new(Mutex::new(LogFilterState {
+                baseline: directives,
+                revert: None,
+            })),
+        }
+    }
+
+    pub fn status(&self) -> LogFilterStatus {
+        let state = self.state.lock().unwrap();
+        let directives = self
+            .handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_else(|_| state.baseline.clone());
+
+        LogFilterStatus {
+            directives,
+            revert_at: state.revert.as_ref().map(|revert| revert.at),
+        }
+    }
+
+    /// Replace the filter with `directives`.
+    ///
+    /// With a `ttl`, the previous directives are restored once it has elapsed.
+    /// Any pending revert is cancelled. Must be called from a Tokio runtime.
+    pub fn set(
+        &self,
+        directives: &str,
+        ttl: Option<Duration>,
+    ) -> Result<LogFilterStatus, LogFilterError> {
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|e| LogFilterError::InvalidDirectives(e.to_string()))?;
+
+        {
+            let mut state = self.state.lock().unwrap();
+            self.handle.reload(filter)?;
+
+            if let Some(revert) = state.revert.take() {
+                revert.task.abort();
+            }
+
+            match ttl {
+                None => state.baseline = directives.to_string(),
+                Some(ttl) => {
+                    let log_filter = self.clone();
+                    let task = tokio::spawn(async move {
+                        tokio::time::sleep(ttl).await;
+                        log_filter.revert();
+                    });
+                    state.revert = Some(ScheduledRevert {
+                        at: Utc::now() + ttl,
+                        task,
+                    });
+                }
+            }
+        }
+
+        let status = self.status();
+        tracing::info!(
+            directives = %status.directives,
+            revert_at = ?status.revert_at,
+            "Changed the log filter"
+        );
+
+        Ok(status)
+    }
+
+    fn revert(&self) {
+        let baseline = {
+            let mut state = self.state.lock().unwrap();
+            state.revert = None;
+            state.baseline.clone()
+        };
+
+        match self.handle.reload(EnvFilter::new(&baseline)) {
+            Ok(()) => tracing::info!(directives = %baseline, "Reverted the log filter"),
+            Err(e) => tracing::error!(error.message = %e, "Failed to revert the log filter"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_subscriber, otlp_tracer, redact_record, trace_context_headers, Redacted,
+        RedactionPolicy, RedactionStrategy,
+    };
+    use opentelemetry::global;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use secrecy::Secret;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_to_the_otlp_collector() {
+        // Arrange
+        let collector = MockServer::start().await;
+        Mock::given(path("/v1/traces"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1..)
+            .mount(&collector)
+            .await;
+        let (provider, tracer) =
+            otlp_tracer("zero2prod", &format!("{}/v1/traces", collector.uri())).unwrap();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        // Act
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("Adding a new subscriber").in_scope(|| {});
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap();
+
+        // Assert
+        let requests = collector.received_requests().await.unwrap();
+        let body = &requests[0].body;
+        let span_name = b"Adding a new subscriber";
+        assert!(body.windows(span_name.len()).any(|w| w == span_name));
+    }
+
+    #[test]
+    fn the_trace_context_of_the_current_span_is_injected() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let headers = tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("Send an email").in_scope(trace_context_headers)
+        });
+
+        let traceparent = headers.get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.starts_with("00-"));
+        assert!(traceparent.ends_with("-01"));
+    }
+
+    fn redact(value: Redacted, policy: &RedactionPolicy) -> String {
+        let mut output = String::new();
+        value.write(policy, &mut output).unwrap();
+        output
+    }
+
+    fn policy() -> RedactionPolicy {
+        RedactionPolicy {
+            hash_key: Some(Secret::new("a".repeat(32))),
+            ..RedactionPolicy::default()
+        }
+    }
+
+    #[test]
+    fn emails_are_hashed_by_default() {
+        let policy = policy();
+
+        let redacted = redact(Redacted::email("ursula_le_guin@gmail.com"), &policy);
+
+        assert!(redacted.starts_with("hmac:"));
+        assert_eq!(redacted.len(), "hmac:".len() + 16);
+        assert!(!redacted.contains("ursula"));
+        // The same subscriber can be followed across log lines
+        assert_eq!(
+            redacted,
+            redact(Redacted::email("ursula_le_guin@gmail.com"), &policy)
+        );
+    }
+
+    #[test]
+    fn hashes_depend_on_the_key() {
+        let other_policy = RedactionPolicy {
+            hash_key: Some(Secret::new("b".repeat(32))),
+            ..RedactionPolicy::default()
+        };
+
+        assert_ne!(
+            redact(Redacted::email("ursula_le_guin@gmail.com"), &policy()),
+            redact(Redacted::email("ursula_le_guin@gmail.com"), &other_policy)
+        );
+    }
+
+    #[test]
+    fn nothing_is_hashed_without_a_key() {
+        let policy = RedactionPolicy::default();
+
+        assert_eq!(
+            redact(Redacted::email("ursula_le_guin@gmail.com"), &policy),
+            "***"
+        );
+    }
+
+    #[test]
+    fn masked_emails_keep_their_domain() {
+        let policy = RedactionPolicy {
+            email: RedactionStrategy::Mask,
+            ..RedactionPolicy::default()
+        };
+
+        assert_eq!(
+            redact(Redacted::email("ursula_le_guin@gmail.com"), &policy),
+            "u***@gmail.com"
+        );
+        assert_eq!(redact(Redacted::email("not-an-email"), &policy), "n***");
+    }
+
+    #[test]
+    fn names_and_tokens_are_masked_by_default() {
+        let policy = policy();
+
+        assert_eq!(redact(Redacted::name("le guin"), &policy), "l***");
+        assert_eq!(redact(Redacted::token("aBcDeF123"), &policy), "a***");
+        assert_eq!(redact(Redacted::name(""), &policy), "***");
+    }
+
+    #[test]
+    fn nothing_is_redacted_when_redaction_is_disabled() {
+        let policy = RedactionPolicy {
+            disabled: true,
+            ..RedactionPolicy::default()
+        };
+
+        assert_eq!(
+            redact(Redacted::email("ursula_le_guin@gmail.com"), &policy),
+            "ursula_le_guin@gmail.com"
+        );
+    }
+
+    fn redact_line(line: serde_json::Value, policy: &RedactionPolicy) -> serde_json::Value {
+        let record = redact_record(policy, line.to_string().as_bytes())
+            .unwrap_or_else(|| line.to_string().into_bytes());
+        serde_json::from_slice(&record).unwrap()
+    }
+
+    #[test]
+    fn fields_named_like_personal_data_are_redacted() {
+        let policy = policy();
+        let line = serde_json::json!({
+            "name": "zero2prod",
+            "msg": "[ADDING A NEW SUBSCRIBER - START]",
+            "otel.name": "HTTP POST /subscriptions",
+            "email": "ursula_le_guin@gmail.com",
+            "subscriber_name": "le guin",
+            "subscription_token": "aBcDeF123",
+            "newsletter_issue_id": "b2b3c5f1",
+        });
+
+        let redacted = redact_line(line, &policy);
+
+        assert_eq!(redacted["name"], "zero2prod");
+        assert_eq!(redacted["otel.name"], "HTTP POST /subscriptions");
+        assert_eq!(redacted["newsletter_issue_id"], "b2b3c5f1");
+        assert_eq!(
+            redacted["email"].as_str().unwrap(),
+            redact(Redacted::email("ursula_le_guin@gmail.com"), &policy)
+        );
+        assert_eq!(redacted["subscriber_name"], "l***");
+        assert_eq!(redacted["subscription_token"], "a***");
+    }
+
+    #[test]
+    fn values_redacted_with_redacted_are_left_as_they_are() {
+        let policy = policy();
+        let hashed = redact(Redacted::email("ursula_le_guin@gmail.com"), &policy);
+        let line = serde_json::json!({
+            "subscriber_email": hashed,
+            "subscriber_name": "l***",
+        });
+
+        assert!(redact_record(&policy, line.to_string().as_bytes()).is_none());
+    }
+
+    #[test]
+    fn personal_data_in_debug_representations_is_redacted() {
+        let policy = policy();
+        let line = serde_json::json!({
+            "form": r#"FormData { name: "le guin", email: "ursula_le_guin@gmail.com", website: None, form_token: Some("aBcDeF123") }"#,
+            "new_subscriber": r#"NewSubscriber { name: SubscriberName("le guin"), email: SubscriberEmail { address: "ursula_le_guin@gmail.com" } }"#,
+            "error.cause_chain": "Failed to parse the email: the address is not valid",
+        });
+
+        let redacted = redact_line(line, &policy);
+
+        let hashed = redact(Redacted::email("ursula_le_guin@gmail.com"), &policy);
+        assert_eq!(
+            redacted["form"],
+            format!(
+                r#"FormData {{ name: "l***", email: "{}", website: None, form_token: Some("a***") }}"#,
+                hashed
+            )
+        );
+        assert_eq!(
+            redacted["new_subscriber"],
+            format!(
+                r#"NewSubscriber {{ name: SubscriberName("l***"), email: SubscriberEmail {{ address: "{}" }} }}"#,
+                hashed
+            )
+        );
+        assert_eq!(
+            redacted["error.cause_chain"],
+            "Failed to parse the email: the address is not valid"
+        );
+    }
+
+    #[derive(Clone)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_logs_of_the_subscriber_are_redacted() {
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct FormData {
+            email: String,
+        }
+
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let sink = Sink(logs.clone());
+        let (subscriber, _) =
+            get_subscriber("test".into(), "info".into(), move || sink.clone(), None);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let form = FormData {
+                email: "ursula_le_guin@gmail.com".into(),
+            };
+            tracing::info!(subscriber_email = %form.email, form = ?form, "New subscriber");
+        });
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("New subscriber"));
+        assert!(!logs.contains("ursula_le_guin@gmail.com"), "{}", logs);
+    }
 }
@@ -0,0 +1,424 @@
+use crate::configuration::TlsSettings;
+use crate::routes::error_chain_fmt;
+use crate::shutdown::Shutdown;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use notify::{RecursiveMode, Watcher};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use std::fmt::Debug;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Certificates are usually renewed by writing both files, one after the other:
+/// we wait for things to settle before reading them.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+#[derive(thiserror::Error)]
+pub enum TlsError {
+    #[error("Failed to read {path:?}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("There is no certificate in {0:?}")]
+    NoCertificate(PathBuf),
+    #[error("There is no private key in {0:?}")]
+    NoPrivateKey(PathBuf),
+    #[error("The private key in {0:?} is not supported")]
+    UnsupportedPrivateKey(PathBuf),
+    #[error("The private key in {key:?} does not match the certificate in {certificate:?}")]
+    KeyMismatch { certificate: PathBuf, key: PathBuf },
+}
+
+impl Debug for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Hands out the certificate currently on disk to every TLS handshake.
+///
+/// `reload` swaps the certificate atomically: new connections use the new
+/// one, established connections are not affected.
+pub struct CertificateResolver {
+    certificate_path: PathBuf,
+    key_path: PathBuf,
+    current: ArcSwap<LoadedCertificate>,
+}
+
+/// A certificate and its private key, as read from disk
+struct LoadedCertificate {
+    certified_key: Arc<CertifiedKey>,
+    /// To tell whether the key changed, the signing key cannot be compared
+    key: PrivateKey,
+}
+
+impl CertificateResolver {
+    pub fn new(settings: &TlsSettings) -> Result<Self, TlsError> {
+        let current = load_certificate(&settings.certificate_path, &settings.key_path)?;
+
+        Ok(Self {
+            certificate_path: settings.certificate_path.clone(),
+            key_path: settings.key_path.clone(),
+            current: ArcSwap::from_pointee(current),
+        })
+    }
+
+    /// Read the certificate and key again. The current ones are kept if they
+    /// cannot be read or do not match, e.g. while they are being written one
+    /// after the other, or if neither of them changed.
+    #[tracing::instrument(name = "Reload the TLS certificate", skip(self), err)]
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let loaded = load_certificate(&self.certificate_path, &self.key_path)?;
+        let current = self.current.load();
+        if loaded.certified_key.cert == current.certified_key.cert && loaded.key == current.key {
+            tracing::debug!("The TLS certificate did not change");
+            return Ok(());
+        }
+        self.current.store(Arc::new(loaded));
+        tracing::info!("Applied the new TLS certificate");
+
+        Ok(())
+    }
+
+    /// A rustls configuration that always picks the current certificate
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load().certified_key.clone())
+    }
+}
+
+fn load_certificate(
+    certificate_path: &Path,
+    key_path: &Path,
+) -> Result<LoadedCertificate, TlsError> {
+    let certificates: Vec<Certificate> = read_pem(certificate_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate(certificate_path.into()));
+    }
+
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoPrivateKey(key_path.into()))?;
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| TlsError::UnsupportedPrivateKey(key_path.into()))?;
+    if !key_matches_certificate(signing_key.as_ref(), &certificates[0]) {
+        return Err(TlsError::KeyMismatch {
+            certificate: certificate_path.into(),
+            key: key_path.into(),
+        });
+    }
+
+    Ok(LoadedCertificate {
+        certified_key: Arc::new(CertifiedKey::new(certificates, signing_key)),
+        key,
+    })
+}
+
+/// Whether `key` is the private key of `certificate`: the public key of the
+/// certificate verifies what the key signs.
+fn key_matches_certificate(key: &dyn SigningKey, certificate: &Certificate) -> bool {
+    const SCHEMES: [SignatureScheme; 5] = [
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::ED25519,
+        SignatureScheme::RSA_PSS_SHA256,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ];
+    const MESSAGE: &[u8] = b"Does the key match the certificate?";
+
+    let Ok(certificate) = webpki::EndEntityCert::try_from(certificate.0.as_slice()) else {
+        return false;
+    };
+    let Some(signer) = key.choose_scheme(&SCHEMES) else {
+        return false;
+    };
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        _ => return false,
+    };
+
+    signer.sign(MESSAGE).is_ok_and(|signature| {
+        certificate
+            .verify_signature(algorithm, MESSAGE, &signature)
+            .is_ok()
+    })
+}
+
+fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>, TlsError> {
+    let read_error = |source| TlsError::Read {
+        path: path.into(),
+        source,
+    };
+    let file = std::fs::File::open(path).map_err(read_error)?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(read_error)
+}
+
+/// Send a plain HTTP request to the same host and path over HTTPS
+pub async fn redirect_to_https(request: HttpRequest, https_port: web::Data<u16>) -> HttpResponse {
+    let host = request.connection_info().host().to_owned();
+    let mut url = match reqwest::Url::parse(&format!("http://{}", host)) {
+        Ok(url) => url,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    // The scheme and port cannot fail to change for an http URL with a host
+    let _ = url.set_scheme("https");
+    let _ = url.set_port(Some(**https_port));
+    url.set_path(request.path());
+    url.set_query(Some(request.query_string()).filter(|query| !query.is_empty()));
+
+    // 308 rather than 301, clients must not turn a POST into a GET
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, url.as_str()))
+        .finish()
+}
+
+/// Reload the certificate when the directories holding its files change,
+/// until `shutdown` is triggered.
+///
+/// The directories are watched rather than the files themselves: renewal
+/// tools tend to replace them (rename, symlink swap). Any change in them
+/// triggers a reload, e.g. Kubernetes swaps a `..data` symlink that the
+/// files point through, no event names the files themselves.
+pub async fn run_certificate_reloader_until_stopped(
+    resolver: Arc<CertificateResolver>,
+    shutdown: Shutdown,
+) -> Result<(), anyhow::Error> {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if !event.kind.is_access() {
+                let _ = sender.send(());
+            }
+        }
+    })
+    .context("Failed to create a watcher for the TLS certificate")?;
+    for path in [&resolver.certificate_path, &resolver.key_path] {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {:?}", directory))?;
+    }
+
+    loop {
+        tokio::select! {
+            change = receiver.recv() => if change.is_none() {
+                break;
+            },
+            _ = shutdown.triggered() => break,
+        };
+        tokio::time::sleep(DEBOUNCE).await;
+        while receiver.try_recv().is_ok() {}
+
+        // Errors are logged by `reload`, we keep serving the current certificate
+        let _ = resolver.reload();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_certificate_reloader_until_stopped, CertificateResolver, TlsError};
+    use crate::configuration::TlsSettings;
+    use crate::shutdown::Shutdown;
+    use claims::{assert_err, assert_ok};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// A self-signed certificate for `localhost`, written to temporary files
+    fn write_certificate() -> TlsSettings {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let directory = std::env::temp_dir().join(format!("zero2prod-tls-{}", Uuid::new_v4()));
+        std::fs::create_dir(&directory).unwrap();
+
+        let settings = TlsSettings {
+            certificate_path: directory.join("certificate.pem"),
+            key_path: directory.join("key.pem"),
+            redirect_port: None,
+        };
+        std::fs::write(
+            &settings.certificate_path,
+            certificate.serialize_pem().unwrap(),
+        )
+        .unwrap();
+        std::fs::write(&settings.key_path, certificate.serialize_private_key_pem()).unwrap();
+
+        settings
+    }
+
+    fn served_certificate(resolver: &CertificateResolver) -> Vec<u8> {
+        resolver.current.load().certified_key.cert[0].0.clone()
+    }
+
+    #[test]
+    fn a_certificate_and_its_key_are_loaded() {
+        let settings = write_certificate();
+
+        assert_ok!(CertificateResolver::new(&settings));
+    }
+
+    #[test]
+    fn a_missing_file_is_rejected() {
+        let mut settings = write_certificate();
+        settings.key_path = PathBuf::from("/does/not/exist.pem");
+
+        let error = CertificateResolver::new(&settings).err().unwrap();
+        assert!(matches!(error, TlsError::Read { .. }));
+    }
+
+    #[test]
+    fn a_key_file_without_a_key_is_rejected() {
+        let mut settings = write_certificate();
+        settings.key_path = settings.certificate_path.clone();
+
+        let error = CertificateResolver::new(&settings).err().unwrap();
+        assert!(matches!(error, TlsError::NoPrivateKey(_)));
+    }
+
+    #[test]
+    fn a_key_that_does_not_match_the_certificate_is_rejected() {
+        let mut settings = write_certificate();
+        settings.key_path = write_certificate().key_path;
+
+        let error = CertificateResolver::new(&settings).err().unwrap();
+        assert!(matches!(error, TlsError::KeyMismatch { .. }));
+    }
+
+    #[test]
+    fn a_certificate_is_only_applied_once_its_key_is_written() {
+        let settings = write_certificate();
+        let resolver = Arc::new(CertificateResolver::new(&settings).unwrap());
+        let before = served_certificate(&resolver);
+
+        // The renewal tool writes the certificate first
+        let renewed = write_certificate();
+        std::fs::rename(&renewed.certificate_path, &settings.certificate_path).unwrap();
+
+        assert_err!(resolver.reload());
+        assert_eq!(served_certificate(&resolver), before);
+
+        // Then its key
+        std::fs::rename(&renewed.key_path, &settings.key_path).unwrap();
+
+        assert_ok!(resolver.reload());
+        assert_ne!(served_certificate(&resolver), before);
+    }
+
+    #[test]
+    fn reloading_picks_up_the_new_certificate() {
+        let settings = write_certificate();
+        let resolver = Arc::new(CertificateResolver::new(&settings).unwrap());
+        let before = served_certificate(&resolver);
+
+        let renewed = write_certificate();
+        std::fs::rename(&renewed.certificate_path, &settings.certificate_path).unwrap();
+        std::fs::rename(&renewed.key_path, &settings.key_path).unwrap();
+
+        assert_ok!(resolver.reload());
+        assert_ne!(served_certificate(&resolver), before);
+    }
+
+    #[test]
+    fn a_failed_reload_keeps_the_current_certificate() {
+        let settings = write_certificate();
+        let resolver = Arc::new(CertificateResolver::new(&settings).unwrap());
+        let before = served_certificate(&resolver);
+
+        std::fs::write(&settings.certificate_path, "not a certificate").unwrap();
+
+        assert_err!(resolver.reload());
+        assert_eq!(served_certificate(&resolver), before);
+    }
+
+    /// The layout of a Kubernetes secret volume: the files are symlinks
+    /// through `..data`, which is swapped to point at the new version
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn swapping_a_symlinked_directory_reloads_the_certificate() {
+        use std::os::unix::fs::symlink;
+
+        let current = write_certificate();
+        let volume = std::env::temp_dir().join(format!("zero2prod-tls-{}", Uuid::new_v4()));
+        std::fs::create_dir(&volume).unwrap();
+        symlink(
+            current.certificate_path.parent().unwrap(),
+            volume.join("..data"),
+        )
+        .unwrap();
+        symlink("..data/certificate.pem", volume.join("certificate.pem")).unwrap();
+        symlink("..data/key.pem", volume.join("key.pem")).unwrap();
+        let settings = TlsSettings {
+            certificate_path: volume.join("certificate.pem"),
+            key_path: volume.join("key.pem"),
+            redirect_port: None,
+        };
+        let resolver = Arc::new(CertificateResolver::new(&settings).unwrap());
+        let before = served_certificate(&resolver);
+        let shutdown = Shutdown::new();
+        let reloader = tokio::spawn(run_certificate_reloader_until_stopped(
+            resolver.clone(),
+            shutdown.clone(),
+        ));
+        // Let the watcher start
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let renewed = write_certificate();
+        symlink(
+            renewed.certificate_path.parent().unwrap(),
+            volume.join("..data_tmp"),
+        )
+        .unwrap();
+        std::fs::rename(volume.join("..data_tmp"), volume.join("..data")).unwrap();
+
+        let mut reloaded = false;
+        for _ in 0..50 {
+            if served_certificate(&resolver) != before {
+                reloaded = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        shutdown.trigger();
+        assert_ok!(reloader.await.unwrap());
+        assert!(reloaded, "The certificate was not reloaded");
+    }
+}
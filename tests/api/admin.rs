@@ -0,0 +1,94 @@
+use crate::helpers::spawn_app;
+use std::time::Duration;
+use zero2prod::routes::admin_log_filter_route;
+
+#[tokio::test]
+async fn admin_endpoints_reject_requests_without_a_valid_token() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let url = format!("{}{}", app.address, admin_log_filter_route());
+
+    // Act
+    let missing_token = client.get(&url).send().await.unwrap();
+    let wrong_token = app.get_log_filter("not-the-admin-token").await;
+    let wrong_token_update = app
+        .put_log_filter(
+            "not-the-admin-token",
+            &serde_json::json!({ "directives": "trace" }),
+        )
+        .await;
+
+    // Assert
+    for response in [missing_token, wrong_token, wrong_token_update] {
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(
+            response.headers()["WWW-Authenticate"],
+            r#"Bearer realm="admin""#
+        );
+    }
+}
+
+#[tokio::test]
+async fn invalid_log_filters_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .put_log_filter(
+            &app.admin_token,
+            &serde_json::json!({ "directives": "zero2prod=not-a-level" }),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_temporary_log_filter_is_reverted_after_its_ttl() {
+    // Arrange
+    let app = spawn_app().await;
+    let initial: serde_json::Value = app
+        .get_log_filter(&app.admin_token)
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    // Act - Part 1 - Turn on debug logs for a second
+    let response = app
+        .put_log_filter(
+            &app.admin_token,
+            &serde_json::json!({ "directives": "info,zero2prod=debug", "ttl_seconds": 1 }),
+        )
+        .await;
+
+    // Assert - Part 1
+    assert_eq!(response.status().as_u16(), 200);
+    let current: serde_json::Value = app
+        .get_log_filter(&app.admin_token)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(current["directives"]
+        .as_str()
+        .unwrap()
+        .contains("zero2prod=debug"));
+    assert!(current["revert_at"].is_string());
+
+    // Act - Part 2 - Wait for the TTL to elapse
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    // Assert - Part 2
+    let reverted: serde_json::Value = app
+        .get_log_filter(&app.admin_token)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reverted["directives"], initial["directives"]);
+    assert!(reverted["revert_at"].is_null());
+}
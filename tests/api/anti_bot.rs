@@ -0,0 +1,137 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use secrecy::Secret;
+use zero2prod::configuration::CaptchaSettings;
+use zero2prod::routes::{subscriptions_form_token_route, subscriptions_route};
+
+async fn subscribe_with(app: &TestApp, extra_fields: &[(&str, &str)]) -> reqwest::Response {
+    let mut form = vec![("name", "le guin"), ("email", "ursula_le_guin@gmail.com")];
+    form.extend_from_slice(extra_fields);
+
+    reqwest::Client::new()
+        .post(format!("{}{}", &app.address, subscriptions_route()))
+        .form(&form)
+        .send()
+        .await
+        .expect("Failed to execute POST request")
+}
+
+async fn get_form_token(app: &TestApp) -> reqwest::Response {
+    reqwest::get(format!(
+        "{}{}",
+        &app.address,
+        subscriptions_form_token_route()
+    ))
+    .await
+    .expect("Failed to execute GET request")
+}
+
+async fn subscriber_count(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn a_filled_in_honeypot_gets_a_200_but_no_subscription() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = subscribe_with(&app, &[("website", "https://spam.example.com")]).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 0);
+    assert!(app
+        .captured_logs()
+        .contains("Rejected a subscription from a bot"));
+    assert!(app
+        .get_metrics()
+        .await
+        .contains(r#"bot_submissions_total{reason="honeypot"}"#));
+}
+
+#[tokio::test]
+async fn an_empty_honeypot_is_a_person() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = subscribe_with(&app, &[("website", "")]).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn forms_submitted_too_quickly_are_turned_away() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.application.anti_bot.min_time_to_submit_seconds = 2;
+        c.application.anti_bot.form_token_secret = Some(Secret::new(
+            "a-form-token-secret-that-is-long-enough".into(),
+        ));
+    })
+    .await;
+    let response = get_form_token(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let form_token = body["form_token"].as_str().unwrap().to_owned();
+
+    // Act - Part 1 - No token, then straight away
+    let without_token = subscribe_with(&app, &[]).await;
+    let too_fast = subscribe_with(&app, &[("form_token", &form_token)]).await;
+
+    // Assert - Part 1
+    assert_eq!(without_token.status().as_u16(), 200);
+    assert_eq!(too_fast.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 0);
+
+    // Act - Part 2 - After a person would have filled the form in
+    tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+    let response = subscribe_with(&app, &[("form_token", &form_token)]).await;
+
+    // Assert - Part 2
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn form_tokens_are_not_served_without_a_secret() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = get_form_token(&app).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_captcha_response_must_be_accepted_by_the_provider() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.application.anti_bot.captcha = Some(CaptchaSettings::Mock {
+            accepted_response: "human".into(),
+        })
+    })
+    .await;
+
+    // Act - Part 1 - A response the provider does not accept
+    let response = subscribe_with(&app, &[("captcha_response", "robot")]).await;
+
+    // Assert - Part 1
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 0);
+
+    // Act - Part 2 - The field name used by the hCaptcha widget
+    let response = subscribe_with(&app, &[("h-captcha-response", "human")]).await;
+
+    // Assert - Part 2
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
@@ -0,0 +1,250 @@
+use crate::helpers::{email_accepted_response, spawn_app, TestApp};
+use crate::newsletter::create_confirmed_subscriber;
+use std::path::PathBuf;
+use std::process::Output;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+use zero2prod::email_client::email_route;
+
+/// Run the `zero2prod` binary against the database and email server of `app`
+fn zero2prod(app: &TestApp, args: &[&str]) -> Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_zero2prod"))
+        .args(args)
+        .env(
+            "APP_DATABASE__DATABASE_NAME",
+            &app.configuration.database.database_name,
+        )
+        .env(
+            "APP_EMAIL_CLIENT__BASE_URL",
+            &app.configuration.email_client.base_url,
+        )
+        .output()
+        .expect("Failed to run zero2prod")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn write_temporary_file(content: &str) -> PathBuf {
+    let file = std::env::temp_dir().join(format!("{}.csv", Uuid::new_v4()));
+    std::fs::write(&file, content).unwrap();
+
+    file
+}
+
+#[tokio::test]
+async fn imported_subscribers_are_exported() {
+    // Arrange
+    let app = spawn_app().await;
+    let file = write_temporary_file(
+        "email,name\n\
+        ursula_le_guin@gmail.com,Ursula Le Guin\n\
+        octavia_butler@gmail.com,\"Butler, Octavia\"\n",
+    );
+
+    // Act
+    let import = zero2prod(
+        &app,
+        &[
+            "subscribers",
+            "import",
+            "--confirmed",
+            file.to_str().unwrap(),
+        ],
+    );
+    let export = zero2prod(&app, &["subscribers", "export"]);
+
+    // Assert
+    assert!(import.status.success());
+    assert!(stdout(&import).contains("Imported 2 subscriber(s)"));
+
+    assert!(export.status.success());
+    let mut reader = csv::Reader::from_reader(export.stdout.as_slice());
+    let exported: Vec<(String, String, String)> = reader
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            (record[0].into(), record[1].into(), record[2].into())
+        })
+        .collect();
+    assert_eq!(
+        exported,
+        vec![
+            (
+                "ursula_le_guin@gmail.com".into(),
+                "Ursula Le Guin".into(),
+                "confirmed".into()
+            ),
+            (
+                "octavia_butler@gmail.com".into(),
+                "Butler, Octavia".into(),
+                "confirmed".into()
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn imported_subscribers_are_sent_a_confirmation_email_unless_already_confirmed() {
+    // Arrange
+    let app = spawn_app().await;
+    let file = write_temporary_file("email,name\nursula_le_guin@gmail.com,le guin\n");
+
+    // Act
+    let output = zero2prod(&app, &["subscribers", "import", file.to_str().unwrap()]);
+
+    // Assert
+    assert!(output.status.success());
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+
+    let queued = sqlx::query!("SELECT kind, recipient FROM outbox")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.kind, "subscription_confirmation");
+    assert_eq!(queued.recipient, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn nothing_is_imported_when_a_line_is_not_valid() {
+    // Arrange
+    let app = spawn_app().await;
+    let file = write_temporary_file(
+        "email,name\n\
+        ursula_le_guin@gmail.com,le guin\n\
+        definitely-not-an-email,someone\n",
+    );
+
+    // Act
+    let output = zero2prod(&app, &["subscribers", "import", file.to_str().unwrap()]);
+
+    // Assert
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 3"));
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_empty());
+}
+
+#[tokio::test]
+async fn deleting_a_subscriber_removes_their_tokens_and_pending_emails() {
+    // Arrange
+    let app = spawn_app().await;
+    app.send_subscription_request("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Act
+    let output = zero2prod(&app, &["subscribers", "delete", "ursula_le_guin@gmail.com"]);
+
+    // Assert
+    assert!(output.status.success());
+
+    let subscribers: i64 = sqlx::query_scalar("SELECT count(*) FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    let tokens: i64 = sqlx::query_scalar("SELECT count(*) FROM subscription_tokens")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    let pending_emails: i64 =
+        sqlx::query_scalar("SELECT count(*) FROM outbox WHERE status = 'pending'")
+            .fetch_one(&app.connection_pool)
+            .await
+            .unwrap();
+    assert_eq!((subscribers, tokens, pending_emails), (0, 0, 0));
+}
+
+#[tokio::test]
+async fn deleting_an_unknown_subscriber_fails() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let output = zero2prod(&app, &["subscribers", "delete", "ursula_le_guin@gmail.com"]);
+
+    // Assert
+    assert!(!output.status.success());
+}
+
+#[tokio::test]
+async fn purging_tokens_keeps_the_ones_still_awaiting_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.send_subscription_request("name=butler&email=octavia_butler%40gmail.com".into())
+        .await;
+
+    // Act
+    let output = zero2prod(&app, &["tokens", "purge"]);
+
+    // Assert
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Purged 1 subscription token(s)"));
+
+    let remaining = sqlx::query!(
+        "SELECT email FROM subscription_tokens \
+        INNER JOIN subscriptions ON subscription_tokens.subscriber_id = subscriptions.id"
+    )
+    .fetch_all(&app.connection_pool)
+    .await
+    .unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].email, "octavia_butler@gmail.com");
+}
+
+#[tokio::test]
+async fn issues_sent_from_the_command_line_report_their_status() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let html = write_temporary_file("<p>Newsletter body as HTML</p>");
+    let text = write_temporary_file("Newsletter body as plain text");
+
+    // Act
+    let send = zero2prod(
+        &app,
+        &[
+            "issue",
+            "send",
+            "--title",
+            "Newsletter title",
+            "--html",
+            html.to_str().unwrap(),
+            "--text",
+            text.to_str().unwrap(),
+        ],
+    );
+
+    // Assert
+    assert!(send.status.success());
+    let newsletter_issue_id = stdout(&send)
+        .trim()
+        .strip_prefix("Published newsletter issue ")
+        .expect("The issue id was not printed")
+        .to_string();
+
+    let status = zero2prod(&app, &["issue", "status", &newsletter_issue_id]);
+    assert!(status.status.success());
+    let status = stdout(&status);
+    assert!(status.contains("Status:       sent"));
+    assert!(status.contains("Delivered:    1"));
+}
@@ -0,0 +1,199 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use zero2prod::configuration::{DeliverabilitySettings, DomainResolverSettings};
+
+#[tokio::test]
+async fn subscribe_returns_a_400_for_disposable_and_role_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        ("reader%40mailinator.com", "Disposable email addresses"),
+        ("reader%40eu.mailinator.com", "Disposable email addresses"),
+        ("postmaster%40example.com", "Role addresses"),
+        ("Abuse%2Bnews%40example.com", "Role addresses"),
+    ];
+
+    for (email, message) in test_cases {
+        // Act
+        let response = app
+            .send_subscription_request(format!("name=le%20guin&email={}", email))
+            .await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "{}", email);
+        assert!(response.text().await.unwrap().starts_with(message));
+    }
+}
+
+#[tokio::test]
+async fn the_policy_checks_can_be_turned_off() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.application.email_policy.block_disposable_domains = false;
+        c.application.email_policy.reject_role_addresses = false;
+    })
+    .await;
+
+    for email in ["reader%40mailinator.com", "postmaster%40example.com"] {
+        // Act
+        let response = app
+            .send_subscription_request(format!("name=le%20guin&email={}", email))
+            .await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 200, "{}", email);
+    }
+}
+
+/// Only `example.com` and `gmail.com` receive emails
+fn with_deliverability_check(c: &mut zero2prod::configuration::Settings) {
+    c.application.email_policy.deliverability = Some(DeliverabilitySettings {
+        resolver: DomainResolverSettings::Static {
+            domains: vec!["example.com".into(), "gmail.com".into()],
+        },
+        cache_ttl_seconds: 60,
+    });
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_with_a_suggestion_for_domains_that_do_not_receive_emails() {
+    // Arrange
+    let app = spawn_app_with(with_deliverability_check).await;
+    let test_cases = [
+        ("ursula%40gmial.con", Some("Did you mean gmail.com?")),
+        ("ursula%40no-mail.example.org", None),
+    ];
+
+    for (email, suggestion) in test_cases {
+        // Act
+        let response = app
+            .send_subscription_request(format!("name=le%20guin&email={}", email))
+            .await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "{}", email);
+        let body = response.text().await.unwrap();
+        assert!(body.starts_with("The domain of this email address does not receive emails."));
+        match suggestion {
+            Some(suggestion) => assert!(body.ends_with(suggestion), "{}", body),
+            None => assert!(!body.contains("Did you mean"), "{}", body),
+        }
+    }
+}
+
+#[tokio::test]
+async fn domains_that_receive_emails_and_allowed_addresses_pass_the_deliverability_check() {
+    // Arrange
+    let app = spawn_app_with(with_deliverability_check).await;
+    let response = app
+        .post_email_rule(
+            &app.admin_token,
+            &serde_json::json!({ "pattern": "intranet.example.org", "action": "allow" }),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    for email in ["ursula%40Example.com", "ursula%40intranet.example.org"] {
+        // Act
+        let response = app
+            .send_subscription_request(format!("name=le%20guin&email={}", email))
+            .await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 200, "{}", email);
+    }
+}
+
+#[tokio::test]
+async fn admin_rules_deny_and_allow_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    for (pattern, action) in [
+        ("*.spam.example.com", "deny"),
+        ("competitor.example.com", "deny"),
+        ("friend@mailinator.com", "allow"),
+    ] {
+        let response = app
+            .post_email_rule(
+                &app.admin_token,
+                &serde_json::json!({ "pattern": pattern, "action": action }),
+            )
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let test_cases = [
+        ("reader%40eu.spam.example.com", 400),
+        ("reader%40Competitor.example.com", 400),
+        ("reader%40spam.example.com", 200),
+        ("friend%40mailinator.com", 200),
+    ];
+
+    for (email, expected) in test_cases {
+        // Act
+        let response = app
+            .send_subscription_request(format!("name=le%20guin&email={}", email))
+            .await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), expected, "{}", email);
+    }
+}
+
+#[tokio::test]
+async fn admins_can_list_and_delete_rules() {
+    // Arrange
+    let app = spawn_app().await;
+    let rule: serde_json::Value = app
+        .post_email_rule(
+            &app.admin_token,
+            &serde_json::json!({ "pattern": "Example.com", "action": "deny" }),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = rule["id"].as_str().unwrap();
+
+    // Act - Part 1 - List
+    let rules: serde_json::Value = app
+        .get_email_rules(&app.admin_token)
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    // Assert - Part 1
+    assert_eq!(rules.as_array().unwrap().len(), 1);
+    assert_eq!(rules[0]["pattern"], "example.com");
+    assert_eq!(rules[0]["action"], "deny");
+
+    // Act - Part 2 - Delete, twice
+    let deleted = app.delete_email_rule(&app.admin_token, id).await;
+    let deleted_again = app.delete_email_rule(&app.admin_token, id).await;
+
+    // Assert - Part 2
+    assert_eq!(deleted.status().as_u16(), 204);
+    assert_eq!(deleted_again.status().as_u16(), 404);
+    let response = app
+        .send_subscription_request("name=le%20guin&email=reader%40example.com".into())
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn malformed_rules_and_unauthorized_admins_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let malformed = app
+        .post_email_rule(
+            &app.admin_token,
+            &serde_json::json!({ "pattern": "not a domain", "action": "deny" }),
+        )
+        .await;
+    let unauthorized = app.get_email_rules("not-the-admin-token").await;
+
+    // Assert
+    assert_eq!(malformed.status().as_u16(), 400);
+    assert_eq!(unauthorized.status().as_u16(), 401);
+}
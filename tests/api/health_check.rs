@@ -1,5 +1,10 @@
-use crate::helpers::spawn_app;
-use zero2prod::routes::health_check_route;
+use crate::helpers::{spawn_app, spawn_app_with};
+use sqlx::{Connection, Executor, PgConnection};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::get_configuration;
+use zero2prod::email_client::server_route;
+use zero2prod::routes::{health_check_route, health_live_route};
 
 // `tokio::test` is the testing equivalent of `tokio::main`.
 // It also spares us from having to specify the `#[test]` attribute.
@@ -20,5 +25,124 @@ async fn health_check_works() {
 
     // Assert
     assert!(response.status().is_success());
-    assert_eq!(Some(0), response.content_length());
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email_circuit_breaker"], "closed");
+}
+
+#[tokio::test]
+async fn liveness_works() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!("{}{}", app.address, health_live_route()))
+        .await
+        .expect("Failed to execute GET request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn readiness_reports_every_check() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.get_health_ready().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["checks"]["database"]["status"], "up");
+    assert_eq!(body["checks"]["migrations"]["status"], "up");
+    // The email provider is only checked when enabled
+    assert!(body["checks"].get("email_provider").is_none());
+}
+
+#[tokio::test]
+async fn readiness_returns_503_when_migrations_are_pending() {
+    // Arrange
+    let app = spawn_app().await;
+    sqlx::query(
+        "DELETE FROM _sqlx_migrations \
+        WHERE version = (SELECT max(version) FROM _sqlx_migrations)",
+    )
+    .execute(&app.connection_pool)
+    .await
+    .unwrap();
+
+    // Act
+    let response = app.get_health_ready().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "not_ready");
+    assert_eq!(body["checks"]["database"]["status"], "up");
+    assert_eq!(body["checks"]["migrations"]["status"], "down");
+    assert_eq!(
+        body["checks"]["migrations"]["message"],
+        "1 pending migration(s)"
+    );
+}
+
+#[tokio::test]
+async fn readiness_returns_503_when_the_database_is_unreachable() {
+    // Arrange
+    let app = spawn_app().await;
+    let database_name: String = sqlx::query_scalar("SELECT current_database()")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    let configuration = get_configuration().unwrap();
+    let mut connection = PgConnection::connect_with(&configuration.database.without_db())
+        .await
+        .unwrap();
+    connection
+        .execute(format!(r#"DROP DATABASE "{}" WITH (FORCE)"#, database_name).as_str())
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.get_health_ready().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["checks"]["database"]["status"], "down");
+    assert_eq!(body["checks"]["database"]["critical"], true);
+}
+
+#[tokio::test]
+async fn an_unreachable_email_provider_does_not_fail_readiness() {
+    // Arrange
+    let app =
+        spawn_app_with(|configuration| configuration.health.check_email_provider = true).await;
+
+    Mock::given(path(server_route()))
+        .and(method("GET"))
+        .respond_with(ResponseTemplate::new(500))
+        // The outcome of the check is cached
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    for _ in 0..2 {
+        // Act
+        let response = app.get_health_ready().await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 200);
+
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["email_provider"]["status"], "down");
+        assert_eq!(body["checks"]["email_provider"]["critical"], false);
+    }
 }
@@ -1,11 +1,24 @@
 use once_cell::sync::Lazy;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::trace::TracerProvider;
+use secrecy::Secret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use tracing_subscriber::fmt::MakeWriter;
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
-use zero2prod::routes::{publish_newsletter_route, subscriptions_route};
+use wiremock::{MockServer, ResponseTemplate};
+use zero2prod::configuration::{get_configuration, DatabaseSettings, OutboxSettings, Settings};
+use zero2prod::email_client::EmailClient;
+use zero2prod::outbox::{try_execute_task, ExecutionOutcome};
+use zero2prod::routes::{
+    admin_email_rules_route, admin_log_filter_route, health_ready_route, metrics_route,
+    publish_newsletter_route, subscriptions_route,
+};
+use zero2prod::shutdown::Shutdown;
 use zero2prod::startup::{get_connection_pool, header, Application};
-use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::telemetry::{get_subscriber, init_subscriber, set_redaction_policy};
 
 /// Confirmation links embedded in the request to the email API
 pub struct ConfirmationLinks {
@@ -18,9 +31,46 @@ pub struct TestApp {
     pub connection_pool: PgPool,
     pub email_server: MockServer,
     pub port: u16,
+    pub email_client: EmailClient,
+    pub outbox_settings: OutboxSettings,
+    pub admin_token: String,
+    /// Set when `/metrics` is served on its own port
+    pub metrics_port: Option<u16>,
+    /// Set when TLS is enabled with an HTTP to HTTPS redirect
+    pub redirect_port: Option<u16>,
+    /// Stops the application and the workers started by the test
+    pub shutdown: Shutdown,
+    pub application_task: JoinHandle<Result<(), std::io::Error>>,
+    pub configuration: Settings,
+    logs: Arc<Mutex<Vec<u8>>>,
 }
 
 impl TestApp {
+    /// Deliver every email in the outbox that is due, as the outbox relay would
+    pub async fn dispatch_all_pending_emails(&self) {
+        // Tie the logs to this application, see `captured_logs`
+        let span = tracing::info_span!("Dispatch all pending emails", test_app = %self.address);
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(
+                &self.connection_pool,
+                &self.email_client,
+                &self.outbox_settings,
+            )
+            .instrument(span.clone())
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    /// The log lines written so far about the requests to this application
+    /// and the emails dispatched with `dispatch_all_pending_emails`
+    pub fn captured_logs(&self) -> String {
+        String::from_utf8_lossy(&self.logs.lock().unwrap()).into_owned()
+    }
+
     pub async fn send_subscription_request(&self, body: String) -> reqwest::Response {
         let post_request_header = header();
         let request = format!("{}{}", &self.address, subscriptions_route());
@@ -34,6 +84,15 @@ impl TestApp {
             .expect("Failed to execute POST request")
     }
 
+    pub async fn send_subscription_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}{}", &self.address, subscriptions_route()))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute POST request")
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
@@ -55,15 +114,99 @@ impl TestApp {
             confirmation_link
         };
 
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
 
         ConfirmationLinks { html, plain_text }
     }
 
+    pub async fn get_log_filter(&self, admin_token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}{}", &self.address, admin_log_filter_route()))
+            .bearer_auth(admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_log_filter(
+        &self,
+        admin_token: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!("{}{}", &self.address, admin_log_filter_route()))
+            .bearer_auth(admin_token)
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_email_rules(&self, admin_token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}{}", &self.address, admin_email_rules_route()))
+            .bearer_auth(admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_email_rule(
+        &self,
+        admin_token: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}{}", &self.address, admin_email_rules_route()))
+            .bearer_auth(admin_token)
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn delete_email_rule(&self, admin_token: &str, id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!(
+                "{}{}/{}",
+                &self.address,
+                admin_email_rules_route(),
+                id
+            ))
+            .bearer_auth(admin_token)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_metrics(&self) -> String {
+        let address = match self.metrics_port {
+            Some(port) => format!("http://127.0.0.1:{}", port),
+            None => self.address.clone(),
+        };
+
+        reqwest::get(format!("{}{}", address, metrics_route()))
+            .await
+            .expect("Failed to execute request.")
+            .error_for_status()
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_health_ready(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}{}", &self.address, health_ready_route()))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn post_newsletters(&self, body: &serde_json::Value) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}{}", &self.address, publish_newsletter_route()))
+            .post(format!("{}{}", &self.address, publish_newsletter_route()))
             .json(&body)
             .send()
             .await
@@ -71,21 +214,105 @@ impl TestApp {
     }
 }
 
+/// A response mimicking Postmark accepting an email
+pub fn email_accepted_response() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(serde_json::json!({
+        "To": "ursula_le_guin@gmail.com",
+        "SubmittedAt": "2024-07-02T09:00:00.4178645-05:00",
+        "MessageID": Uuid::new_v4().to_string(),
+        "ErrorCode": 0,
+        "Message": "OK"
+    }))
+}
+
+/// Where the log lines about each running `TestApp` are kept
+static CAPTURED_LOGS: Mutex<Vec<CapturedLogs>> = Mutex::new(Vec::new());
+
+struct CapturedLogs {
+    /// The end of the address of the application, e.g. `127.0.0.1:41234"`,
+    /// as found in the `http.host` of the requests it handles
+    host: String,
+    /// Dropped with the `TestApp`
+    lines: Weak<Mutex<Vec<u8>>>,
+}
+
+/// Start capturing the log lines about the application listening on `host`
+fn capture_logs(host: String) -> Arc<Mutex<Vec<u8>>> {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    CAPTURED_LOGS.lock().unwrap().push(CapturedLogs {
+        host: format!("{}\"", host),
+        lines: Arc::downgrade(&lines),
+    });
+    lines
+}
+
+/// Keeps the logs in `CAPTURED_LOGS`, echoing them to stdout when `TEST_LOG` is set
+#[derive(Clone, Copy)]
+struct CapturingSink {
+    echo: bool,
+}
+
+impl<'a> MakeWriter<'a> for CapturingSink {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        *self
+    }
+}
+
+impl std::io::Write for CapturingSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        let mut captured = CAPTURED_LOGS.lock().unwrap();
+        captured.retain(|logs| logs.lines.strong_count() > 0);
+        for logs in captured.iter().filter(|logs| line.contains(&logs.host)) {
+            if let Some(lines) = logs.lines.upgrade() {
+                lines.lock().unwrap().extend_from_slice(buf);
+            }
+        }
+        drop(captured);
+
+        if self.echo {
+            std::io::stdout().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
 // Ensure that the `tracing` stack is only initialised once using `once_cell`
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
     let subscriber_name = "test".to_string();
+    // Spans are not exported anywhere, but we get trace ids to assert on.
+    // The provider is registered globally to keep it alive.
+    let tracer_provider = TracerProvider::builder().build();
+    let tracer = Some(tracer_provider.tracer("test"));
+    opentelemetry::global::set_tracer_provider(tracer_provider);
 
-    if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
-        init_subscriber(subscriber);
-    } else {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink);
-        init_subscriber(subscriber);
-    }
+    let (subscriber, log_filter) = get_subscriber(
+        subscriber_name,
+        default_filter_level,
+        CapturingSink {
+            echo: std::env::var("TEST_LOG").is_ok(),
+        },
+        tracer,
+    );
+    init_subscriber(subscriber, log_filter);
+
+    let configuration = get_configuration().expect("Failed to read configuration");
+    set_redaction_policy(configuration.telemetry.redaction);
 });
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawn the application after `configure` has adjusted the test configuration
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     // The first time `initialize` is invoked the code in `TRACING` is executed.
     // All other invocations will instead skip execution.
     Lazy::force(&TRACING);
@@ -93,13 +320,19 @@ pub async fn spawn_app() -> TestApp {
     // Mock Email Sever
     let email_server = MockServer::start().await;
 
+    let admin_token = Uuid::new_v4().simple().to_string();
+
     // Database
     let configuration = {
         let mut config = get_configuration().expect("Failed to read configuration");
         config.database.database_name = Uuid::new_v4().to_string();
+        // Exercise the same path as a deploy
+        config.database.migrate_on_startup = true;
         config.application.port = 0;
 
         config.email_client.base_url = email_server.uri();
+        config.application.admin_token = Some(Secret::new(admin_token.clone()));
+        configure(&mut config);
 
         config
     };
@@ -110,6 +343,8 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to build application");
     let port = application.port();
+    let metrics_port = application.metrics_port();
+    let redirect_port = application.redirect_port();
 
     let address = format!(
         "http://{}:{}",
@@ -117,20 +352,36 @@ pub async fn spawn_app() -> TestApp {
         application.port()
     );
 
+    let logs = capture_logs(format!("{}:{}", configuration.database.host, port));
+
+    let shutdown = application.shutdown();
     // Launch our application in the background
-    tokio::spawn(application.run_until_stopped());
+    let application_task = tokio::spawn(application.run_until_stopped());
 
     TestApp {
         address,
         port,
         connection_pool: get_connection_pool(&configuration.database),
         email_server,
+        email_client: configuration
+            .email_client
+            .clone()
+            .client()
+            .expect("Failed to build the email client."),
+        outbox_settings: configuration.outbox.clone(),
+        admin_token,
+        metrics_port,
+        redirect_port,
+        shutdown,
+        application_task,
+        configuration,
+        logs,
     }
 }
 
 /*
 Create a new database with a new/random name for each test for test isolation.
-We then also need to run migrations on this new database.
+The migrations are applied by `Application::build`, as they are on deploy.
 */
 pub async fn configure_database(config: &DatabaseSettings) {
     let mut connection = PgConnection::connect_with(&config.without_db())
@@ -143,14 +394,4 @@ pub async fn configure_database(config: &DatabaseSettings) {
         .execute(query.as_str())
         .await
         .expect("Failed to create database");
-
-    let connection_pool = PgPool::connect_with(config.with_db())
-        .await
-        .expect("Failed to connect to Postgres.");
-
-    // Defaults to "./migrations"
-    sqlx::migrate!()
-        .run(&connection_pool)
-        .await
-        .expect("Failed to migrate database");
 }
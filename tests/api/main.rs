@@ -1,5 +1,22 @@
+// CI does not lint test targets, so older tests are not written with clippy in mind
+#![allow(
+    clippy::needless_borrow,
+    clippy::useless_conversion,
+    clippy::useless_format
+)]
+
+mod admin;
+mod anti_bot;
+mod cli;
+mod email_policy;
 mod health_check;
 mod helpers;
+mod metrics;
+mod migrations;
 mod newsletter;
+mod outbox;
+mod rate_limit;
+mod shutdown;
 mod subscriptions;
 mod subscriptions_confirm;
+mod tls;
@@ -0,0 +1,90 @@
+use crate::helpers::{email_accepted_response, spawn_app, spawn_app_with};
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+use zero2prod::email_client::email_route;
+use zero2prod::routes::{health_check_route, metrics_route};
+
+#[tokio::test]
+async fn metrics_are_exposed_in_the_prometheus_format() {
+    // Arrange
+    let app = spawn_app().await;
+    reqwest::get(format!("{}{}", app.address, health_check_route()))
+        .await
+        .unwrap();
+
+    // Act
+    let metrics = app.get_metrics().await;
+
+    // Assert
+    assert!(
+        metrics.contains(r#"http_requests_total{method="GET",route="/health_check",status="200"}"#)
+    );
+    assert!(metrics
+        .contains(r#"http_request_duration_seconds_bucket{method="GET",route="/health_check""#));
+    assert!(metrics.contains(r#"db_pool_connections{state="max"}"#));
+    assert!(metrics.contains("db_pool_acquire_duration_seconds_bucket"));
+    assert!(metrics.contains("outbox_pending_emails"));
+}
+
+#[tokio::test]
+async fn subscriptions_and_emails_are_counted() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let metrics = app.get_metrics().await;
+
+    // Assert
+    assert!(metrics.contains(r#"subscriptions_total{event="requested"}"#));
+    assert!(metrics.contains(r#"emails_total{kind="subscription_confirmation",outcome="sent"}"#));
+    assert!(metrics
+        .contains(r#"http_requests_total{method="POST",route="/subscriptions",status="200"}"#));
+}
+
+#[tokio::test]
+async fn non_standard_methods_share_a_single_label() {
+    // Arrange
+    let app = spawn_app().await;
+    let method = reqwest::Method::from_bytes(b"PURGE-1234").unwrap();
+
+    // Act
+    reqwest::Client::new()
+        .request(method, format!("{}{}", app.address, health_check_route()))
+        .send()
+        .await
+        .unwrap();
+    let metrics = app.get_metrics().await;
+
+    // Assert
+    assert!(!metrics.contains("PURGE-1234"));
+    assert!(metrics.contains(r#"http_requests_total{method="other",route="#));
+}
+
+#[tokio::test]
+async fn metrics_can_be_served_on_a_separate_port() {
+    // Arrange
+    let app = spawn_app_with(|configuration| {
+        configuration.application.metrics_port = Some(0);
+    })
+    .await;
+
+    // Act
+    let on_application_port = reqwest::get(format!("{}{}", app.address, metrics_route()))
+        .await
+        .unwrap();
+    let metrics = app.get_metrics().await;
+
+    // Assert
+    assert!(app.metrics_port.is_some());
+    assert_eq!(on_application_port.status().as_u16(), 404);
+    assert!(metrics.contains("outbox_pending_emails"));
+}
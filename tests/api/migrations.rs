@@ -0,0 +1,170 @@
+use crate::helpers::{configure_database, spawn_app_with};
+use sqlx::migrate::Migrator;
+use sqlx::PgPool;
+use uuid::Uuid;
+use zero2prod::configuration::{get_configuration, Settings};
+use zero2prod::domain::SubscriberEmail;
+use zero2prod::startup::{get_connection_pool, migrate, MIGRATOR};
+
+/// The test configuration, pointing to a database that has just been created
+async fn configuration_with_an_empty_database() -> Settings {
+    let mut configuration = get_configuration().expect("Failed to read configuration");
+    configuration.database.database_name = Uuid::new_v4().to_string();
+    configure_database(&configuration.database).await;
+
+    configuration
+}
+
+async fn applied_migrations(connection_pool: &PgPool) -> i64 {
+    sqlx::query_scalar("SELECT count(*) FROM _sqlx_migrations WHERE success")
+        .fetch_one(connection_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_database_is_left_alone_when_migrate_on_startup_is_disabled() {
+    // Arrange
+    let app = spawn_app_with(|configuration| {
+        configuration.database.migrate_on_startup = false;
+    })
+    .await;
+
+    // Act
+    let response = app.get_health_ready().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["checks"]["migrations"]["status"], "down");
+}
+
+#[tokio::test]
+async fn concurrent_instances_apply_each_migration_once() {
+    // Arrange
+    let configuration = configuration_with_an_empty_database().await;
+    // One pool per instance
+    let pools: Vec<PgPool> = (0..3)
+        .map(|_| get_connection_pool(&configuration.database))
+        .collect();
+
+    // Act
+    let outcomes = tokio::join!(migrate(&pools[0]), migrate(&pools[1]), migrate(&pools[2]));
+
+    // Assert
+    outcomes.0.expect("Failed to migrate the database");
+    outcomes.1.expect("Failed to migrate the database");
+    outcomes.2.expect("Failed to migrate the database");
+    assert_eq!(
+        applied_migrations(&pools[0]).await,
+        MIGRATOR.iter().count() as i64
+    );
+}
+
+#[tokio::test]
+async fn the_migrate_command_applies_the_migrations_and_exits() {
+    // Arrange
+    let configuration = configuration_with_an_empty_database().await;
+
+    // Act
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_zero2prod"))
+        .arg("migrate")
+        .env(
+            "APP_DATABASE__DATABASE_NAME",
+            &configuration.database.database_name,
+        )
+        .stdout(std::process::Stdio::null())
+        .status()
+        .expect("Failed to run zero2prod migrate");
+
+    // Assert
+    assert!(status.success());
+
+    let connection_pool = get_connection_pool(&configuration.database);
+    assert_eq!(
+        applied_migrations(&connection_pool).await,
+        MIGRATOR.iter().count() as i64
+    );
+}
+
+#[tokio::test]
+async fn existing_emails_are_normalized_and_their_duplicates_merged() {
+    // Arrange
+    let configuration = configuration_with_an_empty_database().await;
+    let connection_pool = get_connection_pool(&configuration.database);
+    let before_normalization = Migrator {
+        migrations: MIGRATOR
+            .iter()
+            .filter(|migration| migration.version < 20240706090000)
+            .cloned()
+            .collect(),
+        ..Migrator::DEFAULT
+    };
+    before_normalization
+        .run(&connection_pool)
+        .await
+        .expect("Failed to migrate the database");
+    for (email, status, days_ago) in [
+        ("Ursula@Bücher.de", "confirmed", 2),
+        ("ursula@xn--bcher-kva.de", "pending_confirmation", 1),
+        ("Octavia.Butler+news@Gmail.com", "confirmed", 2),
+        ("octavia.butler@GMAIL.com", "pending_confirmation", 1),
+    ] {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO subscriptions (id, email, name, subscribed_at, status) \
+            VALUES ($1, $2, 'le guin', now() - make_interval(days => $3), $4)",
+        )
+        .bind(id)
+        .bind(email)
+        .bind(days_ago)
+        .bind(status)
+        .execute(&connection_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO subscription_tokens (subscriber_id, subscription_token) VALUES ($1, $2)",
+        )
+        .bind(id)
+        .bind(id.to_string())
+        .execute(&connection_pool)
+        .await
+        .unwrap();
+    }
+
+    // Act
+    migrate(&connection_pool)
+        .await
+        .expect("Failed to migrate the database");
+
+    // Assert
+    let subscribers: Vec<(String, String)> =
+        sqlx::query_as("SELECT email, email_normalized FROM subscriptions ORDER BY email")
+            .fetch_all(&connection_pool)
+            .await
+            .unwrap();
+    // The confirmed subscriber is kept, provider rules are off by default
+    assert_eq!(
+        subscribers,
+        vec![
+            (
+                "Octavia.Butler+news@Gmail.com".to_string(),
+                "octavia.butler+news@gmail.com".to_string()
+            ),
+            (
+                "Ursula@Bücher.de".to_string(),
+                "ursula@xn--bcher-kva.de".to_string()
+            ),
+            (
+                "octavia.butler@GMAIL.com".to_string(),
+                "octavia.butler@gmail.com".to_string()
+            ),
+        ]
+    );
+    // As `SubscriberEmail::normalized` computes them
+    for (email, email_normalized) in subscribers {
+        let email = SubscriberEmail::parse(&email).unwrap();
+        assert_eq!(email.normalized(), email_normalized);
+    }
+}
@@ -0,0 +1,206 @@
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::email_client::email_route;
+
+use crate::helpers::{email_accepted_response, spawn_app, TestApp};
+
+async fn outbox_status(app: &TestApp) -> (String, i32) {
+    let saved = sqlx::query!("SELECT status, attempts FROM outbox")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch the outbox email");
+
+    (saved.status, saved.attempts)
+}
+
+/// Make every pending email due right away instead of waiting for the backoff
+async fn skip_retry_delay(app: &TestApp) {
+    sqlx::query!("UPDATE outbox SET next_attempt_at = now()")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn subscribing_enqueues_the_confirmation_email_without_sending_it() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request(body.into()).await;
+
+    // Assert
+    assert_eq!(outbox_status(&app).await, ("pending".to_string(), 0));
+}
+
+#[tokio::test]
+async fn delivered_emails_are_marked_as_delivered() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    // Nothing left to deliver
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(outbox_status(&app).await, ("delivered".to_string(), 1));
+}
+
+#[tokio::test]
+async fn delivered_emails_store_the_provider_message_id() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT provider_message_id, delivered_at FROM outbox")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch the outbox email");
+    assert!(saved.provider_message_id.is_some());
+    assert!(saved.delivered_at.is_some());
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - The email provider fails
+    let response = app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Part 1
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(outbox_status(&app).await, ("pending".to_string(), 1));
+
+    // Act - Part 2 - The retry goes through
+    skip_retry_delay(&app).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Part 2
+    assert_eq!(outbox_status(&app).await, ("delivered".to_string(), 2));
+}
+
+#[tokio::test]
+async fn emails_are_marked_as_failed_after_too_many_attempts() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.outbox_settings.max_attempts = 2;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    skip_retry_delay(&app).await;
+    app.dispatch_all_pending_emails().await;
+    // A failed email is never picked up again
+    skip_retry_delay(&app).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(outbox_status(&app).await, ("failed".to_string(), 2));
+}
+
+#[tokio::test]
+async fn rate_limited_deliveries_are_rescheduled_without_counting_an_attempt() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(outbox_status(&app).await, ("pending".to_string(), 0));
+}
+
+#[tokio::test]
+async fn inactive_recipients_are_suppressed() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(outbox_status(&app).await, ("failed".to_string(), 1));
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.status, "suppressed");
+}
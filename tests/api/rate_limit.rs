@@ -0,0 +1,139 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use secrecy::Secret;
+use zero2prod::configuration::RateLimitStore;
+use zero2prod::routes::subscriptions_route;
+
+async fn subscribe_from(app: &TestApp, email: &str, forwarded_for: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}{}", &app.address, subscriptions_route()))
+        .header("X-Forwarded-For", forwarded_for)
+        .form(&[("name", "le guin"), ("email", email)])
+        .send()
+        .await
+        .expect("Failed to execute POST request")
+}
+
+#[tokio::test]
+async fn too_many_requests_from_the_same_ip_address_get_a_429() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.application.rate_limit.per_ip.capacity = 2;
+        c.application.rate_limit.per_ip.refill_period_seconds = 60;
+    })
+    .await;
+
+    // Act
+    let mut responses = Vec::new();
+    for i in 0..3 {
+        responses.push(subscribe_from(&app, &format!("reader{}@gmail.com", i), "10.0.0.1").await);
+    }
+
+    // Assert
+    let statuses: Vec<u16> = responses.iter().map(|r| r.status().as_u16()).collect();
+    assert_eq!(statuses, vec![200, 200, 429]);
+    // The client is told when a token is back in the bucket
+    assert_eq!(responses[2].headers()["Retry-After"], "60");
+}
+
+#[tokio::test]
+async fn forwarded_addresses_are_only_trusted_when_configured() {
+    // Arrange
+    let trusting = spawn_app_with(|c| {
+        c.application.rate_limit.per_ip.capacity = 1;
+        c.application.rate_limit.trust_forwarded_headers = true;
+    })
+    .await;
+    let not_trusting = spawn_app_with(|c| {
+        c.application.rate_limit.per_ip.capacity = 1;
+        c.application.rate_limit.trust_forwarded_headers = false;
+    })
+    .await;
+
+    for (app, expected) in [(&trusting, 200), (&not_trusting, 429)] {
+        // Act
+        subscribe_from(app, "reader1@gmail.com", "10.0.0.1").await;
+        // Only the last address is added by the proxy, the others come from the client
+        let response = subscribe_from(app, "reader2@gmail.com", "10.0.0.1, 10.0.0.2").await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), expected);
+    }
+}
+
+#[tokio::test]
+async fn too_many_requests_for_the_same_email_address_get_a_429() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.application.rate_limit.per_email.capacity = 1;
+        c.application.rate_limit.trust_forwarded_headers = true;
+    })
+    .await;
+
+    // Act
+    let first = subscribe_from(&app, "ursula_le_guin@gmail.com", "10.0.0.1").await;
+    let second = subscribe_from(&app, "ursula_le_guin@gmail.com", "10.0.0.2").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn the_confirmation_email_is_not_resent_during_the_cooldown() {
+    // Arrange
+    let app =
+        spawn_app_with(|c| c.application.rate_limit.confirmation_cooldown_seconds = 300).await;
+
+    // Act
+    let first = subscribe_from(&app, "ursula_le_guin@gmail.com", "10.0.0.1").await;
+    let second = subscribe_from(&app, "ursula_le_guin@gmail.com", "10.0.0.1").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+    let retry_after: u64 = second.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((295..=300).contains(&retry_after), "{}", retry_after);
+
+    let emails = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM outbox")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(emails.count, 1);
+}
+
+#[tokio::test]
+async fn the_postgres_store_keeps_hashed_keys_only() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.application.rate_limit.store = RateLimitStore::Postgres;
+        c.application.rate_limit.bucket_key_secret = Some(Secret::new("a".repeat(32)));
+        c.application.rate_limit.per_email.capacity = 1;
+    })
+    .await;
+
+    // Act
+    let first = subscribe_from(&app, "ursula_le_guin@gmail.com", "10.0.0.1").await;
+    let second = subscribe_from(&app, "ursula_le_guin@gmail.com", "10.0.0.1").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+
+    let keys: Vec<String> = sqlx::query!("SELECT key FROM rate_limit_buckets ORDER BY key")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.key)
+        .collect();
+    assert_eq!(keys.len(), 2, "{:?}", keys);
+    assert!(keys[0].starts_with("email:"));
+    assert!(keys[1].starts_with("ip:"));
+    assert!(keys
+        .iter()
+        .all(|key| !key.contains("ursula") && !key.contains("127.0.0.1")));
+}
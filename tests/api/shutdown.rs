@@ -0,0 +1,165 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+use zero2prod::email_client::email_route;
+use zero2prod::health::Heartbeats;
+use zero2prod::outbox::run_relay_until_stopped;
+use zero2prod::routes::{health_live_route, publish_newsletter_route};
+
+use crate::helpers::{email_accepted_response, spawn_app, spawn_app_with, TestApp};
+use crate::newsletter::create_confirmed_subscriber;
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+/// The work is in progress once the email API has been called
+async fn wait_for_the_email_request(app: &TestApp) {
+    while app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty()
+    {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn in_flight_requests_complete_during_a_graceful_shutdown() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    // Forget the confirmation email
+    app.email_server.reset().await;
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response().set_delay(Duration::from_secs(1)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let address = app.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::Client::new()
+            .post(format!("{}{}", address, publish_newsletter_route()))
+            .json(&newsletter_request_body())
+            .send()
+            .await
+            // The response holds the connection open until it is dropped
+            .map(|response| response.status())
+    });
+    wait_for_the_email_request(&app).await;
+
+    // Act
+    app.shutdown.trigger();
+
+    // Assert
+    let status = in_flight.await.unwrap().expect("The request was cut off");
+    assert_eq!(status.as_u16(), 200);
+
+    tokio::time::timeout(Duration::from_secs(5), app.application_task)
+        .await
+        .expect("The application did not stop")
+        .unwrap()
+        .unwrap();
+
+    // The listener is closed
+    assert!(
+        reqwest::get(format!("{}{}", app.address, health_live_route()))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn in_flight_requests_are_cut_off_after_the_grace_period() {
+    // Arrange
+    let app = spawn_app_with(|configuration| {
+        configuration.application.shutdown_grace_period_seconds = 1;
+    })
+    .await;
+    create_confirmed_subscriber(&app).await;
+    // Forget the confirmation email
+    app.email_server.reset().await;
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response().set_delay(Duration::from_secs(5)))
+        .mount(&app.email_server)
+        .await;
+
+    let address = app.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::Client::new()
+            .post(format!("{}{}", address, publish_newsletter_route()))
+            .json(&newsletter_request_body())
+            .send()
+            .await
+            // The response holds the connection open until it is dropped
+            .map(|response| response.status())
+    });
+    wait_for_the_email_request(&app).await;
+
+    // Act
+    app.shutdown.trigger();
+
+    // Assert
+    // Actix checks the in-flight requests once per second
+    tokio::time::timeout(Duration::from_secs(4), app.application_task)
+        .await
+        .expect("The application did not stop within the grace period")
+        .unwrap()
+        .unwrap();
+    assert!(in_flight.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn the_outbox_relay_delivers_its_current_email_before_stopping() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response().set_delay(Duration::from_secs(1)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.send_subscription_request(body.into()).await;
+
+    let relay = tokio::spawn(run_relay_until_stopped(
+        Arc::new(ArcSwap::from_pointee(app.configuration.clone())),
+        Arc::new(app.configuration.email_client.clone().client().unwrap()),
+        Arc::new(Heartbeats::new(Duration::from_secs(60))),
+        app.shutdown.clone(),
+    ));
+    wait_for_the_email_request(&app).await;
+
+    // Act
+    app.shutdown.trigger();
+
+    // Assert
+    tokio::time::timeout(Duration::from_secs(5), relay)
+        .await
+        .expect("The relay did not stop")
+        .unwrap()
+        .unwrap();
+
+    let saved = sqlx::query!("SELECT status FROM outbox")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "delivered");
+}
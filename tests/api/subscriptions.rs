@@ -1,21 +1,16 @@
+use uuid::Uuid;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 use zero2prod::email_client::email_route;
+use zero2prod::routes::subscriptions_route;
 
-use crate::helpers::spawn_app;
+use crate::helpers::{email_accepted_response, spawn_app, spawn_app_with};
 
 #[tokio::test]
 async fn subscribe_returns_a_200_for_valid_form_data() {
     // Arrange
     let app = spawn_app().await;
 
-    Mock::given(path(email_route()))
-        .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
-        .expect(1)
-        .mount(&app.email_server)
-        .await;
-
     let body = format!("name=Taha%20Afzal&email=tahaafzal5%40hotmail.com");
 
     // Act
@@ -30,13 +25,6 @@ async fn subscribe_persists_the_new_subscriber() {
     // Arrange
     let app = spawn_app().await;
 
-    Mock::given(path(email_route()))
-        .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
-        .expect(1)
-        .mount(&app.email_server)
-        .await;
-
     let body = format!("name=Taha%20Afzal&email=tahaafzal5%40hotmail.com");
 
     // Act
@@ -104,6 +92,179 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
     }
 }
 
+#[tokio::test]
+async fn subscribe_form_errors_stay_plain_text() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .send_subscription_request("name=Ursula&email=definitely-not-an-email".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "The subscriber email is not valid."
+    );
+}
+
+#[tokio::test]
+async fn subscribe_accepts_a_json_body() {
+    // Arrange
+    let app = spawn_app().await;
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .send_subscription_json(&serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribe_returns_structured_errors_to_json_clients() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        (
+            serde_json::json!({ "name": "le guin" }),
+            serde_json::json!("email"),
+            "missing",
+        ),
+        (
+            serde_json::json!({ "name": null, "email": "ursula_le_guin@gmail.com" }),
+            serde_json::json!("name"),
+            "missing",
+        ),
+        (
+            serde_json::json!({ "name": "", "email": "ursula_le_guin@gmail.com" }),
+            serde_json::json!("name"),
+            "invalid",
+        ),
+        (
+            serde_json::json!({ "name": "le guin", "email": "definitely-not-an-email" }),
+            serde_json::json!("email"),
+            "invalid",
+        ),
+        (
+            serde_json::json!({ "name": "le guin", "email": "reader@mailinator.com" }),
+            serde_json::json!("email"),
+            "disposable_domain",
+        ),
+        (
+            serde_json::json!(["le guin", 42]),
+            serde_json::Value::Null,
+            "malformed_body",
+        ),
+        (
+            serde_json::json!({ "name": "le guin", "email": 42 }),
+            serde_json::json!("email"),
+            "malformed_body",
+        ),
+    ];
+
+    for (body, field, code) in test_cases {
+        // Act
+        let response = app.send_subscription_json(&body).await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "{}", body);
+        let error: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(error["field"], field, "{}", body);
+        assert_eq!(error["code"], code, "{}", body);
+        assert!(error["message"].as_str().is_some_and(|m| !m.is_empty()));
+    }
+}
+
+#[tokio::test]
+async fn json_content_types_are_recognized_whatever_their_case() {
+    // Arrange
+    let app = spawn_app().await;
+
+    for content_type in [
+        "Application/JSON",
+        "application/json; charset=UTF-8",
+        "application/vnd.zero2prod+JSON",
+    ] {
+        // Act
+        let response = reqwest::Client::new()
+            .post(format!("{}{}", &app.address, subscriptions_route()))
+            .header("Content-Type", content_type)
+            .body(r#"{ "name": "le guin" }"#)
+            .send()
+            .await
+            .expect("Failed to execute POST request");
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "{}", content_type);
+        let error: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(error["field"], "email", "{}", content_type);
+        assert_eq!(error["code"], "missing", "{}", content_type);
+    }
+}
+
+#[tokio::test]
+async fn unreadable_json_bodies_get_structured_errors_without_their_content() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        (
+            r#"{ "name": "le guin", "email": "ursula_le_guin@gmail.com""#.to_string(),
+            400,
+            "malformed_body",
+        ),
+        (
+            format!(r#"{{ "name": "{}" }}"#, "a".repeat(64 * 1024)),
+            413,
+            "payload_too_large",
+        ),
+    ];
+
+    for (body, status, code) in test_cases {
+        // Act
+        let response = reqwest::Client::new()
+            .post(format!("{}{}", &app.address, subscriptions_route()))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute POST request");
+
+        // Assert
+        assert_eq!(response.status().as_u16(), status, "{}", code);
+        let error: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(error["field"], serde_json::Value::Null, "{}", code);
+        assert_eq!(error["code"], code);
+        let message = error["message"].as_str().unwrap();
+        assert!(
+            !message.contains("ursula") && !message.contains("line"),
+            "{}",
+            message
+        );
+    }
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_for_valid_data() {
     // Arrange
@@ -112,13 +273,14 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
 
     Mock::given(path(email_route()))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted_response())
         .expect(1)
         .mount(&app.email_server)
         .await;
 
     // Act
     app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     // Mock asserts on drop
@@ -132,12 +294,13 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     Mock::given(path(email_route()))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted_response())
         .mount(&app.email_server)
         .await;
 
     // Act
     app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
     let confirmation_links = app.get_confirmation_links(&email_request);
@@ -151,17 +314,18 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 async fn subscribe_twice_when_status_is_pending_confirmation_sends_another_email_reusing_the_subscription_token(
 ) {
     // First Arrange
-    let app = spawn_app().await;
+    let app = spawn_app_with(|c| c.application.rate_limit.confirmation_cooldown_seconds = 0).await;
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
 
     Mock::given(path(email_route()))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted_response())
         .mount(&app.email_server)
         .await;
 
     // First Act
     app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let first_email_request = &app.email_server.received_requests().await.unwrap()[0];
     let first_confirmation_links = app.get_confirmation_links(&first_email_request);
@@ -177,12 +341,13 @@ async fn subscribe_twice_when_status_is_pending_confirmation_sends_another_email
     // Second Arrange
     Mock::given(path(email_route()))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted_response())
         .mount(&app.email_server)
         .await;
 
     // Second Act
     app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let second_email_request = &app.email_server.received_requests().await.unwrap()[0];
     let second_confirmation_links = app.get_confirmation_links(&second_email_request);
 
@@ -198,19 +363,76 @@ async fn subscribe_twice_when_status_is_pending_confirmation_sends_another_email
 }
 
 #[tokio::test]
-async fn subscribing_twice_when_status_is_already_confirmed_returns_a_500() {
+async fn subscribe_keeps_the_address_as_typed_next_to_its_normalized_form() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=%20Ursula_Le_Guin%2Bnews%40GMail.COM%20";
+
+    // Act
+    let response = app.send_subscription_request(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email, email_normalized FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.email, "Ursula_Le_Guin+news@gmail.com");
+    assert_eq!(saved.email_normalized, "ursula_le_guin+news@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribing_with_another_spelling_of_a_pending_address_resends_the_same_confirmation() {
+    // Arrange
+    let app = spawn_app_with(|c| c.application.rate_limit.confirmation_cooldown_seconds = 0).await;
+
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.send_subscription_request("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let response = app
+        .send_subscription_request("name=le%20guin&email=Ursula_Le_Guin%40Gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscribers: i64 = sqlx::query_scalar("SELECT count(*) FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers, 1);
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]);
+    let second_links = app.get_confirmation_links(&email_requests[1]);
+    assert_eq!(first_links.html, second_links.html);
+    // To the address we have on file
+    let second_body: serde_json::Value = serde_json::from_slice(&email_requests[1].body).unwrap();
+    assert_eq!(second_body["To"], "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribing_twice_when_status_is_already_confirmed_returns_a_200() {
     // First Arrange
     let app = spawn_app().await;
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
 
     Mock::given(path(email_route()))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted_response())
         .mount(&app.email_server)
         .await;
 
     // First Act
     app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
 
     let confirmation_links = app.get_confirmation_links(&email_request);
@@ -241,7 +463,13 @@ async fn subscribing_twice_when_status_is_already_confirmed_returns_a_500() {
     let result = app.send_subscription_request(body.into()).await;
 
     // Second Assert
-    assert_eq!(result.status().as_u16(), 500);
+    assert_eq!(result.status().as_u16(), 200);
+    let pending_emails: i64 =
+        sqlx::query_scalar("SELECT count(*) FROM outbox WHERE status = 'pending'")
+            .fetch_one(&app.connection_pool)
+            .await
+            .unwrap();
+    assert_eq!(pending_emails, 0);
 }
 
 #[tokio::test]
@@ -262,3 +490,66 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     // Assert
     assert_eq!(response.status().as_u16(), 500);
 }
+
+#[tokio::test]
+async fn subscriber_emails_never_reach_the_logs() {
+    // Arrange
+    let app = spawn_app().await;
+    let valid_email = format!("{}@example.com", Uuid::new_v4().simple());
+    let invalid_email = format!("{}-at-example.com", Uuid::new_v4().simple());
+
+    // The confirmation email goes through, the newsletter does not
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(email_accepted_response())
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path(email_route()))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .send_subscription_request(format!("name=le%20guin&email={}", valid_email))
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request).html;
+    let subscription_token = confirmation_link
+        .query_pairs()
+        .find(|(name, _)| name == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    let response = reqwest::get(confirmation_link).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+
+    let response = app
+        .post_newsletters(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(500, response.status().as_u16());
+
+    let response = app
+        .send_subscription_request(format!("name=le%20guin&email={}", invalid_email))
+        .await;
+    assert_eq!(400, response.status().as_u16());
+
+    // Assert
+    let logs = app.captured_logs();
+    assert!(logs.contains("\"subscriber_email\":\"hmac:"));
+    assert!(logs.contains("Failed to send newsletter issue to hmac:"));
+    assert!(!logs.contains(&valid_email));
+    assert!(!logs.contains(&invalid_email));
+    assert!(!logs.contains(&subscription_token));
+}
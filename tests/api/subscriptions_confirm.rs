@@ -1,7 +1,7 @@
-use wiremock::{matchers::method, matchers::path, Mock, ResponseTemplate};
+use wiremock::{matchers::method, matchers::path, Mock};
 use zero2prod::email_client::{email_route, subscriptions_confirm_route};
 
-use crate::helpers::spawn_app;
+use crate::helpers::{email_accepted_response, spawn_app};
 
 #[tokio::test]
 async fn confirmations_without_token_are_rejected_with_a_400() {
@@ -26,12 +26,13 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
 
     Mock::given(path(email_route()))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted_response())
         .mount(&app.email_server)
         .await;
 
     // Act
     app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
 
     let confirmation_links = app.get_confirmation_links(&email_request);
@@ -50,12 +51,13 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
 
     Mock::given(path(email_route()))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(email_accepted_response())
         .mount(&app.email_server)
         .await;
 
     // Act
     app.send_subscription_request(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
 
     let confirmation_links = app.get_confirmation_links(&email_request);
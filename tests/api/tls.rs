@@ -0,0 +1,151 @@
+use crate::helpers::spawn_app_with;
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+use zero2prod::configuration::TlsSettings;
+use zero2prod::routes::{health_check_route, subscriptions_route};
+
+/// Write a new self-signed certificate for `localhost` and its key, return
+/// the certificate in PEM
+fn write_certificate(settings: &TlsSettings) -> String {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let pem = certificate.serialize_pem().unwrap();
+
+    // Renewal tools replace the files rather than writing them in place
+    let directory = settings.certificate_path.parent().unwrap();
+    let replace = |path: &Path, content: &str| {
+        let temporary = directory.join(Uuid::new_v4().to_string());
+        std::fs::write(&temporary, content).unwrap();
+        std::fs::rename(&temporary, path).unwrap();
+    };
+    replace(&settings.key_path, &certificate.serialize_private_key_pem());
+    replace(&settings.certificate_path, &pem);
+
+    pem
+}
+
+fn tls_settings(redirect_port: Option<u16>) -> (TlsSettings, String) {
+    let directory = std::env::temp_dir().join(format!("zero2prod-tls-{}", Uuid::new_v4()));
+    std::fs::create_dir(&directory).unwrap();
+    let settings = TlsSettings {
+        certificate_path: directory.join("certificate.pem"),
+        key_path: directory.join("key.pem"),
+        redirect_port,
+    };
+    let certificate = write_certificate(&settings);
+
+    (settings, certificate)
+}
+
+/// The certificate presented by the application, in DER
+async fn served_certificate(port: u16) -> Vec<u8> {
+    let response = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .tls_info(true)
+        .build()
+        .unwrap()
+        .get(format!(
+            "https://localhost:{}{}",
+            port,
+            health_check_route()
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|tls_info| tls_info.peer_certificate())
+        .expect("No certificate was presented")
+        .to_vec()
+}
+
+fn der(pem: &str) -> Vec<u8> {
+    let base64: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    base64::Engine::decode(&base64::engine::general_purpose::STANDARD, base64).unwrap()
+}
+
+#[tokio::test]
+async fn requests_are_served_over_https() {
+    // Arrange
+    let (tls, certificate) = tls_settings(None);
+    let app = spawn_app_with(|configuration| configuration.application.tls = Some(tls)).await;
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(certificate.as_bytes()).unwrap())
+        .build()
+        .unwrap();
+
+    // Act
+    let response = client
+        .get(format!(
+            "https://localhost:{}{}",
+            app.port,
+            health_check_route()
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(response.status().is_success());
+}
+
+#[tokio::test]
+async fn plain_http_requests_are_redirected_to_https() {
+    // Arrange
+    let (tls, _) = tls_settings(Some(0));
+    let app = spawn_app_with(|configuration| configuration.application.tls = Some(tls)).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    // Act
+    let response = client
+        .post(format!(
+            "http://127.0.0.1:{}{}?source=homepage",
+            app.redirect_port.unwrap(),
+            subscriptions_route()
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 308);
+    assert_eq!(
+        response.headers()["Location"],
+        format!(
+            "https://127.0.0.1:{}{}?source=homepage",
+            app.port,
+            subscriptions_route()
+        )
+        .as_str()
+    );
+}
+
+#[tokio::test]
+async fn a_renewed_certificate_is_served_without_a_restart() {
+    // Arrange
+    let (tls, certificate) = tls_settings(None);
+    let app =
+        spawn_app_with(|configuration| configuration.application.tls = Some(tls.clone())).await;
+    assert_eq!(served_certificate(app.port).await, der(&certificate));
+
+    // Act
+    let renewed = write_certificate(&tls);
+
+    // Assert
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while served_certificate(app.port).await != der(&renewed) {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "The renewed certificate was not picked up"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}